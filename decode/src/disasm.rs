@@ -0,0 +1,602 @@
+// RV64GC反汇编器，包括I、M、A、F、D、C、Zicsr和Zifencei扩展
+//
+// 只用于调试器打印指令，所以输出的是字符串，不关心指令的语义
+use alloc::format;
+use alloc::string::String;
+
+const XREG: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2",
+    "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+    "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7",
+    "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+
+const FREG: [&str; 32] = [
+    "ft0", "ft1", "ft2", "ft3", "ft4", "ft5", "ft6", "ft7",
+    "fs0", "fs1", "fa0", "fa1", "fa2", "fa3", "fa4", "fa5",
+    "fa6", "fa7", "fs2", "fs3", "fs4", "fs5", "fs6", "fs7",
+    "fs8", "fs9", "fs10", "fs11", "ft8", "ft9", "ft10", "ft11",
+];
+
+/// 通用寄存器的ABI名称
+pub fn xreg_name(index: usize) -> &'static str {
+    XREG[index & 31]
+}
+
+/// 根据指令的最低16位，得到整条指令的字节数
+pub fn instruction_length(low_half: u16) -> usize {
+    if low_half & 0b11 != 0b11 {
+        2
+    } else if low_half & 0b11100 != 0b11100 {
+        4
+    } else if low_half & 0b111111 == 0b011111 {
+        6
+    } else if low_half & 0b1111111 == 0b0111111 {
+        8
+    } else {
+        // 更长的指令编码还没有定义，按2字节处理，方便继续往后解码
+        2
+    }
+}
+
+/// 反汇编一条指令
+///
+/// `ins`的低位是指令的前两个字节；压缩指令只使用低16位。`pc`是指令所在的地址，用于计算跳转目标。
+pub fn disassemble(ins: u32, pc: usize) -> String {
+    match instruction_length(ins as u16) {
+        2 => compressed(ins as u16, pc).unwrap_or_else(|| format!(".2byte  {:#06x}", ins as u16)),
+        4 => standard(ins, pc).unwrap_or_else(|| format!(".4byte  {:#010x}", ins)),
+        n => format!("unknown {}-byte instruction", n),
+    }
+}
+
+fn op(mnemonic: &str, operands: String) -> Option<String> {
+    Some(format!("{:<7} {}", mnemonic, operands))
+}
+
+fn sext(value: u32, bits: u32) -> i32 {
+    let shift = 32 - bits;
+    ((value << shift) as i32) >> shift
+}
+
+/// I型指令的立即数
+pub fn imm_i(ins: u32) -> i32 {
+    sext(ins >> 20, 12)
+}
+
+/// S型指令的立即数
+pub fn imm_s(ins: u32) -> i32 {
+    sext(((ins >> 25) << 5) | ((ins >> 7) & 0x1f), 12)
+}
+
+/// B型指令（条件分支）的偏移量
+pub fn imm_b(ins: u32) -> i32 {
+    sext(
+        ((ins >> 31) << 12) | (((ins >> 7) & 1) << 11) | (((ins >> 25) & 0x3f) << 5) | (((ins >> 8) & 0xf) << 1),
+        13,
+    )
+}
+
+/// J型指令（jal）的偏移量
+pub fn imm_j(ins: u32) -> i32 {
+    sext(
+        ((ins >> 31) << 20) | (((ins >> 12) & 0xff) << 12) | (((ins >> 20) & 1) << 11) | (((ins >> 21) & 0x3ff) << 1),
+        21,
+    )
+}
+
+/// c.j的偏移量
+pub fn c_jump_offset(ins: u32) -> i32 {
+    let bit = |n: u32| (ins >> n) & 1;
+    sext(
+        (bit(12) << 11) | (bit(11) << 4) | (((ins >> 9) & 0b11) << 8) | (bit(8) << 10)
+            | (bit(7) << 6) | (bit(6) << 7) | (((ins >> 3) & 0b111) << 1) | (bit(2) << 5),
+        12,
+    )
+}
+
+/// c.beqz和c.bnez的偏移量
+pub fn c_branch_offset(ins: u32) -> i32 {
+    let bit = |n: u32| (ins >> n) & 1;
+    sext(
+        (bit(12) << 8) | (((ins >> 10) & 0b11) << 3) | (((ins >> 5) & 0b11) << 6) | (((ins >> 3) & 0b11) << 1) | (bit(2) << 5),
+        9,
+    )
+}
+
+/// 分支或跳转的目标地址
+pub fn target(pc: usize, offset: i32) -> usize {
+    pc.wrapping_add(offset as isize as usize)
+}
+
+fn rounding_mode(rm: u32) -> &'static str {
+    match rm {
+        0 => ",rne",
+        1 => ",rtz",
+        2 => ",rdn",
+        3 => ",rup",
+        4 => ",rmm",
+        7 => "", // 动态舍入模式，不打印
+        _ => ",invalid",
+    }
+}
+
+fn csr_name(csr: u32) -> String {
+    let name = match csr {
+        0x001 => "fflags",
+        0x002 => "frm",
+        0x003 => "fcsr",
+        0xc00 => "cycle",
+        0xc01 => "time",
+        0xc02 => "instret",
+        0xc80 => "cycleh",
+        0xc81 => "timeh",
+        0xc82 => "instreth",
+        0x100 => "sstatus",
+        0x104 => "sie",
+        0x105 => "stvec",
+        0x106 => "scounteren",
+        0x140 => "sscratch",
+        0x141 => "sepc",
+        0x142 => "scause",
+        0x143 => "stval",
+        0x144 => "sip",
+        0x180 => "satp",
+        0xf11 => "mvendorid",
+        0xf12 => "marchid",
+        0xf13 => "mimpid",
+        0xf14 => "mhartid",
+        0x300 => "mstatus",
+        0x301 => "misa",
+        0x302 => "medeleg",
+        0x303 => "mideleg",
+        0x304 => "mie",
+        0x305 => "mtvec",
+        0x306 => "mcounteren",
+        0x320 => "mcountinhibit",
+        0x340 => "mscratch",
+        0x341 => "mepc",
+        0x342 => "mcause",
+        0x343 => "mtval",
+        0x344 => "mip",
+        0xb00 => "mcycle",
+        0xb02 => "minstret",
+        0x7a0 => "tselect",
+        0x7a1 => "tdata1",
+        0x7a2 => "tdata2",
+        0x7a3 => "tdata3",
+        0x7a4 => "tinfo",
+        0x7b0 => "dcsr",
+        0x7b1 => "dpc",
+        0x7b2 => "dscratch0",
+        0x7b3 => "dscratch1",
+        0xc03 ..= 0xc1f => return format!("hpmcounter{}", csr - 0xc00),
+        0xc83 ..= 0xc9f => return format!("hpmcounter{}h", csr - 0xc80),
+        0xb03 ..= 0xb1f => return format!("mhpmcounter{}", csr - 0xb00),
+        0x323 ..= 0x33f => return format!("mhpmevent{}", csr - 0x320),
+        0x3a0 ..= 0x3af => return format!("pmpcfg{}", csr - 0x3a0),
+        0x3b0 ..= 0x3ef => return format!("pmpaddr{}", csr - 0x3b0),
+        _ => return format!("{:#x}", csr),
+    };
+    String::from(name)
+}
+
+fn fence_set(bits: u32) -> String {
+    let mut ans = String::new();
+    for (mask, ch) in [(8, 'i'), (4, 'o'), (2, 'r'), (1, 'w')].iter() {
+        if bits & mask != 0 {
+            ans.push(*ch);
+        }
+    }
+    if ans.is_empty() {
+        ans.push('0');
+    }
+    ans
+}
+
+// 32位标准指令
+fn standard(ins: u32, pc: usize) -> Option<String> {
+    let opcode = ins & 0x7f;
+    let rd = ((ins >> 7) & 0x1f) as usize;
+    let funct3 = (ins >> 12) & 0x7;
+    let rs1 = ((ins >> 15) & 0x1f) as usize;
+    let rs2 = ((ins >> 20) & 0x1f) as usize;
+    let funct7 = ins >> 25;
+    let (imm_i, imm_s, imm_b, imm_j) = (imm_i(ins), imm_s(ins), imm_b(ins), imm_j(ins));
+    let (xd, xs1, xs2) = (XREG[rd], XREG[rs1], XREG[rs2]);
+    let (fd, fs1, fs2) = (FREG[rd], FREG[rs1], FREG[rs2]);
+    match opcode {
+        0x37 => op("lui", format!("{},{:#x}", xd, ins >> 12)),
+        0x17 => op("auipc", format!("{},{:#x}", xd, ins >> 12)),
+        0x6f => op("jal", format!("{},{:#x}", xd, target(pc, imm_j))),
+        0x67 if funct3 == 0 => op("jalr", format!("{},{}({})", xd, imm_i, xs1)),
+        0x63 => {
+            let mnemonic = match funct3 {
+                0 => "beq", 1 => "bne", 4 => "blt", 5 => "bge", 6 => "bltu", 7 => "bgeu",
+                _ => return None,
+            };
+            op(mnemonic, format!("{},{},{:#x}", xs1, xs2, target(pc, imm_b)))
+        }
+        0x03 => {
+            let mnemonic = match funct3 {
+                0 => "lb", 1 => "lh", 2 => "lw", 3 => "ld", 4 => "lbu", 5 => "lhu", 6 => "lwu",
+                _ => return None,
+            };
+            op(mnemonic, format!("{},{}({})", xd, imm_i, xs1))
+        }
+        0x23 => {
+            let mnemonic = match funct3 {
+                0 => "sb", 1 => "sh", 2 => "sw", 3 => "sd",
+                _ => return None,
+            };
+            op(mnemonic, format!("{},{}({})", xs2, imm_s, xs1))
+        }
+        0x13 => {
+            let shamt = (ins >> 20) & 0x3f;
+            match funct3 {
+                1 if ins >> 26 == 0 => op("slli", format!("{},{},{}", xd, xs1, shamt)),
+                5 if ins >> 26 == 0 => op("srli", format!("{},{},{}", xd, xs1, shamt)),
+                5 if ins >> 26 == 0x10 => op("srai", format!("{},{},{}", xd, xs1, shamt)),
+                1 | 5 => None,
+                _ => {
+                    let mnemonic = match funct3 {
+                        0 => "addi", 2 => "slti", 3 => "sltiu", 4 => "xori", 6 => "ori", _ => "andi",
+                    };
+                    op(mnemonic, format!("{},{},{}", xd, xs1, imm_i))
+                }
+            }
+        }
+        0x1b => {
+            let shamt = (ins >> 20) & 0x1f;
+            match (funct3, funct7) {
+                (0, _) => op("addiw", format!("{},{},{}", xd, xs1, imm_i)),
+                (1, 0) => op("slliw", format!("{},{},{}", xd, xs1, shamt)),
+                (5, 0) => op("srliw", format!("{},{},{}", xd, xs1, shamt)),
+                (5, 0x20) => op("sraiw", format!("{},{},{}", xd, xs1, shamt)),
+                _ => None,
+            }
+        }
+        0x33 => {
+            let mnemonic = match (funct7, funct3) {
+                (0, 0) => "add", (0x20, 0) => "sub", (0, 1) => "sll", (0, 2) => "slt",
+                (0, 3) => "sltu", (0, 4) => "xor", (0, 5) => "srl", (0x20, 5) => "sra",
+                (0, 6) => "or", (0, 7) => "and",
+                (1, 0) => "mul", (1, 1) => "mulh", (1, 2) => "mulhsu", (1, 3) => "mulhu",
+                (1, 4) => "div", (1, 5) => "divu", (1, 6) => "rem", (1, 7) => "remu",
+                _ => return None,
+            };
+            op(mnemonic, format!("{},{},{}", xd, xs1, xs2))
+        }
+        0x3b => {
+            let mnemonic = match (funct7, funct3) {
+                (0, 0) => "addw", (0x20, 0) => "subw", (0, 1) => "sllw",
+                (0, 5) => "srlw", (0x20, 5) => "sraw",
+                (1, 0) => "mulw", (1, 4) => "divw", (1, 5) => "divuw",
+                (1, 6) => "remw", (1, 7) => "remuw",
+                _ => return None,
+            };
+            op(mnemonic, format!("{},{},{}", xd, xs1, xs2))
+        }
+        0x0f => match funct3 {
+            0 if ins >> 28 == 0b1000 && (ins >> 20) & 0xff == 0x33 => Some(String::from("fence.tso")),
+            0 => op("fence", format!("{},{}", fence_set((ins >> 24) & 0xf), fence_set((ins >> 20) & 0xf))),
+            1 => Some(String::from("fence.i")),
+            _ => None,
+        },
+        0x73 => system(ins, funct3, xd, xs1, xs2, rs1),
+        0x2f => atomic(ins, funct3, xd, xs1, xs2),
+        0x07 | 0x27 => {
+            let (mnemonic, offset, data) = match (opcode, funct3) {
+                (0x07, 2) => ("flw", imm_i, fd),
+                (0x07, 3) => ("fld", imm_i, fd),
+                (0x27, 2) => ("fsw", imm_s, fs2),
+                (0x27, 3) => ("fsd", imm_s, fs2),
+                _ => return None,
+            };
+            op(mnemonic, format!("{},{}({})", data, offset, xs1))
+        }
+        0x43 | 0x47 | 0x4b | 0x4f => {
+            let fmt = match (ins >> 25) & 0b11 {
+                0 => "s", 1 => "d",
+                _ => return None,
+            };
+            let name = match opcode {
+                0x43 => "fmadd", 0x47 => "fmsub", 0x4b => "fnmsub", _ => "fnmadd",
+            };
+            let fs3 = FREG[(ins >> 27) as usize];
+            op(&format!("{}.{}", name, fmt), format!("{},{},{},{}{}", fd, fs1, fs2, fs3, rounding_mode(funct3)))
+        }
+        0x53 => float(funct7, funct3, rs2, xd, xs1, fd, fs1, fs2),
+        _ => None,
+    }
+}
+
+fn system(ins: u32, funct3: u32, xd: &str, xs1: &str, xs2: &str, rs1: usize) -> Option<String> {
+    let csr = csr_name(ins >> 20);
+    match funct3 {
+        0 => match ins {
+            0x0000_0073 => Some(String::from("ecall")),
+            0x0010_0073 => Some(String::from("ebreak")),
+            0x1020_0073 => Some(String::from("sret")),
+            0x3020_0073 => Some(String::from("mret")),
+            0x1050_0073 => Some(String::from("wfi")),
+            _ if ins >> 25 == 0x09 && (ins >> 7) & 0x1f == 0 => op("sfence.vma", format!("{},{}", xs1, xs2)),
+            _ => None,
+        },
+        1 => op("csrrw", format!("{},{},{}", xd, csr, xs1)),
+        2 => op("csrrs", format!("{},{},{}", xd, csr, xs1)),
+        3 => op("csrrc", format!("{},{},{}", xd, csr, xs1)),
+        5 => op("csrrwi", format!("{},{},{}", xd, csr, rs1)),
+        6 => op("csrrsi", format!("{},{},{}", xd, csr, rs1)),
+        7 => op("csrrci", format!("{},{},{}", xd, csr, rs1)),
+        _ => None,
+    }
+}
+
+fn atomic(ins: u32, funct3: u32, xd: &str, xs1: &str, xs2: &str) -> Option<String> {
+    let width = match funct3 {
+        2 => "w", 3 => "d",
+        _ => return None,
+    };
+    let ordering = match (ins >> 25) & 0b11 {
+        0b00 => "", 0b10 => ".aq", 0b01 => ".rl", _ => ".aqrl",
+    };
+    let name = match ins >> 27 {
+        0x02 => {
+            if (ins >> 20) & 0x1f != 0 {
+                return None
+            }
+            return op(&format!("lr.{}{}", width, ordering), format!("{},({})", xd, xs1))
+        }
+        0x03 => "sc", 0x01 => "amoswap", 0x00 => "amoadd", 0x04 => "amoxor",
+        0x0c => "amoand", 0x08 => "amoor", 0x10 => "amomin", 0x14 => "amomax",
+        0x18 => "amominu", 0x1c => "amomaxu",
+        _ => return None,
+    };
+    op(&format!("{}.{}{}", name, width, ordering), format!("{},{},({})", xd, xs2, xs1))
+}
+
+#[allow(clippy::too_many_arguments)]
+fn float(funct7: u32, funct3: u32, rs2: usize, xd: &str, xs1: &str, fd: &str, fs1: &str, fs2: &str) -> Option<String> {
+    let fmt = if funct7 & 1 == 0 { "s" } else { "d" };
+    let rm = rounding_mode(funct3);
+    let int_type = |rs2| match rs2 {
+        0 => Some("w"), 1 => Some("wu"), 2 => Some("l"), 3 => Some("lu"),
+        _ => None,
+    };
+    match funct7 >> 1 {
+        0x00 | 0x02 | 0x04 | 0x06 => {
+            let name = match funct7 >> 1 {
+                0x00 => "fadd", 0x02 => "fsub", 0x04 => "fmul", _ => "fdiv",
+            };
+            op(&format!("{}.{}", name, fmt), format!("{},{},{}{}", fd, fs1, fs2, rm))
+        }
+        0x16 if rs2 == 0 => op(&format!("fsqrt.{}", fmt), format!("{},{}{}", fd, fs1, rm)),
+        0x08 => {
+            let name = match funct3 {
+                0 => "fsgnj", 1 => "fsgnjn", 2 => "fsgnjx",
+                _ => return None,
+            };
+            op(&format!("{}.{}", name, fmt), format!("{},{},{}", fd, fs1, fs2))
+        }
+        0x0a => {
+            let name = match funct3 {
+                0 => "fmin", 1 => "fmax",
+                _ => return None,
+            };
+            op(&format!("{}.{}", name, fmt), format!("{},{},{}", fd, fs1, fs2))
+        }
+        0x10 => match (funct7, rs2) {
+            (0x20, 1) => op("fcvt.s.d", format!("{},{}{}", fd, fs1, rm)),
+            (0x21, 0) => op("fcvt.d.s", format!("{},{}", fd, fs1)),
+            _ => None,
+        },
+        0x28 => {
+            let name = match funct3 {
+                0 => "fle", 1 => "flt", 2 => "feq",
+                _ => return None,
+            };
+            op(&format!("{}.{}", name, fmt), format!("{},{},{}", xd, fs1, fs2))
+        }
+        0x30 => op(&format!("fcvt.{}.{}", int_type(rs2)?, fmt), format!("{},{}{}", xd, fs1, rm)),
+        0x34 => {
+            let int = int_type(rs2)?;
+            // 32位整数转双精度是精确的，不需要打印舍入模式
+            let rm = if fmt == "d" && (int == "w" || int == "wu") { "" } else { rm };
+            op(&format!("fcvt.{}.{}", fmt, int), format!("{},{}{}", fd, xs1, rm))
+        }
+        0x38 if rs2 == 0 => match funct3 {
+            0 => op(if fmt == "s" { "fmv.x.w" } else { "fmv.x.d" }, format!("{},{}", xd, fs1)),
+            1 => op(&format!("fclass.{}", fmt), format!("{},{}", xd, fs1)),
+            _ => None,
+        },
+        0x3c if rs2 == 0 && funct3 == 0 => {
+            op(if fmt == "s" { "fmv.w.x" } else { "fmv.d.x" }, format!("{},{}", fd, xs1))
+        }
+        _ => None,
+    }
+}
+
+// 16位压缩指令
+fn compressed(ins: u16, pc: usize) -> Option<String> {
+    let ins = ins as u32;
+    let bit = |n: u32| (ins >> n) & 1;
+    let bits = |hi: u32, lo: u32| (ins >> lo) & ((1 << (hi - lo + 1)) - 1);
+    // rd'、rs1'和rs2'只能表示x8到x15
+    let creg = |n: u32| 8 + n as usize;
+    let funct3 = bits(15, 13);
+    let rd = bits(11, 7) as usize;
+    let rs2 = bits(6, 2) as usize;
+    let rd_short = creg(bits(4, 2));
+    let rs1_short = creg(bits(9, 7));
+    // c.lw、c.sw、c.flw等字长为4的访存指令
+    let offset_word = (bits(12, 10) << 3) | (bit(6) << 2) | (bit(5) << 6);
+    // c.ld、c.sd、c.fld等字长为8的访存指令
+    let offset_double = (bits(12, 10) << 3) | (bits(6, 5) << 6);
+    let imm6 = sext((bit(12) << 5) | bits(6, 2), 6);
+    match (ins & 0b11, funct3) {
+        (0b00, 0b000) => {
+            let imm = (bits(12, 11) << 4) | (bits(10, 7) << 6) | (bit(6) << 2) | (bit(5) << 3);
+            if imm == 0 {
+                return None
+            }
+            op("c.addi4spn", format!("{},sp,{}", XREG[rd_short], imm))
+        }
+        (0b00, 0b001) => op("c.fld", format!("{},{}({})", FREG[rd_short], offset_double, XREG[rs1_short])),
+        (0b00, 0b010) => op("c.lw", format!("{},{}({})", XREG[rd_short], offset_word, XREG[rs1_short])),
+        (0b00, 0b011) => op("c.ld", format!("{},{}({})", XREG[rd_short], offset_double, XREG[rs1_short])),
+        (0b00, 0b101) => op("c.fsd", format!("{},{}({})", FREG[rd_short], offset_double, XREG[rs1_short])),
+        (0b00, 0b110) => op("c.sw", format!("{},{}({})", XREG[rd_short], offset_word, XREG[rs1_short])),
+        (0b00, 0b111) => op("c.sd", format!("{},{}({})", XREG[rd_short], offset_double, XREG[rs1_short])),
+        (0b01, 0b000) if rd == 0 => Some(String::from("c.nop")),
+        (0b01, 0b000) => op("c.addi", format!("{},{}", XREG[rd], imm6)),
+        (0b01, 0b001) if rd != 0 => op("c.addiw", format!("{},{}", XREG[rd], imm6)),
+        (0b01, 0b010) => op("c.li", format!("{},{}", XREG[rd], imm6)),
+        (0b01, 0b011) if rd == 2 => {
+            let imm = sext(
+                (bit(12) << 9) | (bit(6) << 4) | (bit(5) << 6) | (bits(4, 3) << 7) | (bit(2) << 5),
+                10,
+            );
+            if imm == 0 {
+                return None
+            }
+            op("c.addi16sp", format!("sp,{}", imm))
+        }
+        (0b01, 0b011) if imm6 != 0 => op("c.lui", format!("{},{:#x}", XREG[rd], (imm6 as u32) & 0xfffff)),
+        (0b01, 0b100) => {
+            let rd = XREG[rs1_short];
+            let rs2 = XREG[creg(bits(4, 2))];
+            let shamt = (bit(12) << 5) | bits(6, 2);
+            match (bits(11, 10), bit(12), bits(6, 5)) {
+                (0b00, _, _) => op("c.srli", format!("{},{}", rd, shamt)),
+                (0b01, _, _) => op("c.srai", format!("{},{}", rd, shamt)),
+                (0b10, _, _) => op("c.andi", format!("{},{}", rd, imm6)),
+                (0b11, 0, 0b00) => op("c.sub", format!("{},{}", rd, rs2)),
+                (0b11, 0, 0b01) => op("c.xor", format!("{},{}", rd, rs2)),
+                (0b11, 0, 0b10) => op("c.or", format!("{},{}", rd, rs2)),
+                (0b11, 0, 0b11) => op("c.and", format!("{},{}", rd, rs2)),
+                (0b11, 1, 0b00) => op("c.subw", format!("{},{}", rd, rs2)),
+                (0b11, 1, 0b01) => op("c.addw", format!("{},{}", rd, rs2)),
+                _ => None,
+            }
+        }
+        (0b01, 0b101) => op("c.j", format!("{:#x}", target(pc, c_jump_offset(ins)))),
+        (0b01, 0b110) | (0b01, 0b111) => {
+            let offset = c_branch_offset(ins);
+            let mnemonic = if funct3 == 0b110 { "c.beqz" } else { "c.bnez" };
+            op(mnemonic, format!("{},{:#x}", XREG[rs1_short], target(pc, offset)))
+        }
+        (0b10, 0b000) if rd != 0 => op("c.slli", format!("{},{}", XREG[rd], (bit(12) << 5) | bits(6, 2))),
+        (0b10, 0b001) => {
+            let offset = (bit(12) << 5) | (bits(6, 5) << 3) | (bits(4, 2) << 6);
+            op("c.fldsp", format!("{},{}(sp)", FREG[rd], offset))
+        }
+        (0b10, 0b010) if rd != 0 => {
+            let offset = (bit(12) << 5) | (bits(6, 4) << 2) | (bits(3, 2) << 6);
+            op("c.lwsp", format!("{},{}(sp)", XREG[rd], offset))
+        }
+        (0b10, 0b011) if rd != 0 => {
+            let offset = (bit(12) << 5) | (bits(6, 5) << 3) | (bits(4, 2) << 6);
+            op("c.ldsp", format!("{},{}(sp)", XREG[rd], offset))
+        }
+        (0b10, 0b100) => match (bit(12), rd, rs2) {
+            (0, 0, _) => None,
+            (0, rs1, 0) => op("c.jr", String::from(XREG[rs1])),
+            (0, rd, rs2) => op("c.mv", format!("{},{}", XREG[rd], XREG[rs2])),
+            (1, 0, 0) => Some(String::from("c.ebreak")),
+            (1, rs1, 0) => op("c.jalr", String::from(XREG[rs1])),
+            (_, rd, rs2) => op("c.add", format!("{},{}", XREG[rd], XREG[rs2])),
+        },
+        (0b10, 0b101) => {
+            let offset = (bits(12, 10) << 3) | (bits(9, 7) << 6);
+            op("c.fsdsp", format!("{},{}(sp)", FREG[rs2], offset))
+        }
+        (0b10, 0b110) => {
+            let offset = (bits(12, 9) << 2) | (bits(8, 7) << 6);
+            op("c.swsp", format!("{},{}(sp)", XREG[rs2], offset))
+        }
+        (0b10, 0b111) => {
+            let offset = (bits(12, 10) << 3) | (bits(9, 7) << 6);
+            op("c.sdsp", format!("{},{}(sp)", XREG[rs2], offset))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PC: usize = 0x8020_0000;
+
+    fn check(ins: u32, expected: &str) {
+        assert_eq!(disassemble(ins, PC), expected, "instruction {:#x}", ins);
+    }
+
+    #[test]
+    fn length() {
+        assert_eq!(instruction_length(0x1141), 2);
+        assert_eq!(instruction_length(0x0113), 4);
+        assert_eq!(instruction_length(0x001f), 6);
+        assert_eq!(instruction_length(0x003f), 8);
+    }
+
+    #[test]
+    fn base_integer() {
+        check(0xff010113, "addi    sp,sp,-16");
+        check(0x00813083, "ld      ra,8(sp)");
+        check(0x00113423, "sd      ra,8(sp)");
+        check(0x80200537, "lui     a0,0x80200");
+        check(0x02051513, "slli    a0,a0,32");
+        check(0x43f55513, "srai    a0,a0,63");
+        check(0x100000ef, "jal     ra,0x80200100");
+        check(0xfeb50ce3, "beq     a0,a1,0x801ffff8");
+        check(0x00008067, "jalr    zero,0(ra)");
+    }
+
+    #[test]
+    fn system_and_fence() {
+        check(0x00000073, "ecall");
+        check(0x00100073, "ebreak");
+        check(0x30200073, "mret");
+        check(0x10500073, "wfi");
+        check(0xf1402573, "csrrs   a0,mhartid,zero");
+        check(0x0330000f, "fence   rw,rw");
+        check(0x0000100f, "fence.i");
+        check(0x12000073, "sfence.vma zero,zero");
+    }
+
+    #[test]
+    fn multiply_and_atomic() {
+        check(0x02c58533, "mul     a0,a1,a2");
+        check(0x0cb6252f, "amoswap.w.aq a0,a1,(a2)");
+        check(0x1005b52f, "lr.d    a0,(a1)");
+    }
+
+    #[test]
+    fn float() {
+        check(0x02c5f553, "fadd.d  fa0,fa1,fa2");
+        check(0x00813507, "fld     fa0,8(sp)");
+        check(0xc2051553, "fcvt.w.d a0,fa0,rtz");
+        check(0xe2050553, "fmv.x.d a0,fa0");
+    }
+
+    #[test]
+    fn compressed() {
+        check(0x1141, "c.addi  sp,-16");
+        check(0xe406, "c.sdsp  ra,8(sp)");
+        check(0x60a2, "c.ldsp  ra,8(sp)");
+        check(0x8082, "c.jr    ra");
+        check(0x9002, "c.ebreak");
+        check(0x4505, "c.li    a0,1");
+        check(0x852e, "c.mv    a0,a1");
+        check(0xa001, "c.j     0x80200000");
+        check(0xc501, "c.beqz  a0,0x80200008");
+        check(0x0800, "c.addi4spn s0,sp,16");
+        check(0x41c8, "c.lw    a0,4(a1)");
+        check(0x6505, "c.lui   a0,0x1");
+        check(0x8d0d, "c.sub   a0,a1");
+        check(0x9d2d, "c.addw  a0,a1");
+        check(0x0000, ".2byte  0x0000");
+    }
+}
@@ -0,0 +1,646 @@
+// 扁平设备树（FDT）的解析
+//
+// 遍历结构块中的节点和属性，或者在原来的设备树的基础上写出新的设备树，都不分配内存。
+// 格式见Devicetree Specification的第5章：头部之后是保留内存表、结构块和字符串块，
+// 结构块由大端的32位记号组成，属性名保存在字符串块中
+use core::str;
+
+const MAGIC: u32 = 0xd00d_feed;
+const HEADER_SIZE: usize = 40;
+const TOKEN_BEGIN_NODE: u32 = 1;
+const TOKEN_END_NODE: u32 = 2;
+const TOKEN_PROP: u32 = 3;
+const TOKEN_NOP: u32 = 4;
+const TOKEN_END: u32 = 9;
+// 写出的设备树的版本和兼容的最低版本
+const VERSION: u32 = 17;
+const LAST_COMPATIBLE_VERSION: u32 = 16;
+// 节点嵌套的最大深度，遇到更深的节点时停止遍历
+const MAX_DEPTH: usize = 16;
+// 父节点没有说明时，reg中地址和大小占用的单元数
+const DEFAULT_ADDRESS_CELLS: usize = 2;
+const DEFAULT_SIZE_CELLS: usize = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    // 设备树的地址为0或者没有对齐到8个字节
+    BadAddress,
+    BadMagic,
+    // 结构块、字符串块或保留内存表超出了设备树的大小
+    Truncated,
+    // 写出的设备树超出了缓冲区的大小
+    NoSpace,
+}
+
+#[derive(Clone, Copy)]
+pub struct Fdt<'a> {
+    data: &'a [u8],
+    // 保留内存表，包括最后一项全为0的结束标记
+    reservations: &'a [u8],
+    structure: &'a [u8],
+    strings: &'a [u8],
+}
+
+impl Fdt<'static> {
+    /// 读取物理地址上的设备树
+    ///
+    /// # Safety
+    ///
+    /// `address`开始的头部和整个设备树都可以读取，并且在调试大师运行期间不变
+    pub unsafe fn from_address(address: usize) -> Result<Self, Error> {
+        if address == 0 || address & 7 != 0 {
+            return Err(Error::BadAddress)
+        }
+        let header = core::slice::from_raw_parts(address as *const u8, HEADER_SIZE);
+        if be32(header, 0) != MAGIC {
+            return Err(Error::BadMagic)
+        }
+        Fdt::new(core::slice::from_raw_parts(address as *const u8, be32(header, 4) as usize))
+    }
+}
+
+impl<'a> Fdt<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Self, Error> {
+        if data.len() < HEADER_SIZE {
+            return Err(Error::Truncated)
+        }
+        if be32(data, 0) != MAGIC {
+            return Err(Error::BadMagic)
+        }
+        let field = |offset| be32(data, offset) as usize;
+        // totalsize包括头部
+        if field(4) < HEADER_SIZE {
+            return Err(Error::Truncated)
+        }
+        let data = data.get(..field(4)).ok_or(Error::Truncated)?;
+        let structure = data.get(field(8)..field(8) + field(36)).ok_or(Error::Truncated)?;
+        let strings = data.get(field(12)..field(12) + field(32)).ok_or(Error::Truncated)?;
+        // 保留内存表的每一项是16个字节的地址和大小
+        let mut end = field(16);
+        loop {
+            let entry = data.get(end..end + 16).ok_or(Error::Truncated)?;
+            end += 16;
+            if entry.iter().all(|&byte| byte == 0) {
+                break
+            }
+        }
+        let reservations = &data[field(16)..end];
+        Ok(Fdt { data, reservations, structure, strings })
+    }
+
+    /// 设备树的字节，包括头部
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.data
+    }
+
+    /// 按结构块中的顺序遍历所有节点，第一个是根节点
+    pub fn nodes(&self) -> Nodes<'a> {
+        let cells = [(DEFAULT_ADDRESS_CELLS, DEFAULT_SIZE_CELLS); MAX_DEPTH + 1];
+        Nodes { fdt: *self, offset: 0, depth: 0, cells }
+    }
+
+    /// 第一个兼容列表中任意一项的节点，跳过被禁用的节点
+    pub fn find_compatible(&self, compatible: &[&str]) -> Option<Node<'a>> {
+        self.nodes().find(|node| node.is_enabled() && compatible.iter().any(|name| node.is_compatible(name)))
+    }
+
+    /// phandle为给定值的节点
+    pub fn find_phandle(&self, phandle: u32) -> Option<Node<'a>> {
+        self.nodes().find(|node| node.phandle() == Some(phandle))
+    }
+
+    // 结构块中offset处的记号
+    fn token(&self, offset: usize) -> Option<u32> {
+        if offset + 4 > self.structure.len() {
+            return None
+        }
+        Some(be32(self.structure, offset))
+    }
+
+    // 以0结尾的字符串
+    fn string_at(bytes: &'a [u8], offset: usize) -> Option<&'a str> {
+        let bytes = bytes.get(offset..)?;
+        let len = bytes.iter().position(|&byte| byte == 0)?;
+        str::from_utf8(&bytes[..len]).ok()
+    }
+}
+
+/// 设备树中的一个节点
+#[derive(Clone, Copy)]
+pub struct Node<'a> {
+    fdt: Fdt<'a>,
+    /// 节点名，包括@后面的单元地址；根节点的名称为空
+    pub name: &'a str,
+    /// 根节点的深度为0
+    pub depth: usize,
+    // 父节点的#address-cells和#size-cells，解析reg时使用
+    address_cells: usize,
+    size_cells: usize,
+    // 第一个属性在结构块中的位置
+    properties: usize,
+}
+
+impl<'a> Node<'a> {
+    pub fn properties(&self) -> Properties<'a> {
+        Properties { fdt: self.fdt, offset: self.properties }
+    }
+
+    pub fn property(&self, name: &str) -> Option<&'a [u8]> {
+        self.properties().find(|property| property.name == name).map(|property| property.value)
+    }
+
+    /// 属性的第一个单元
+    pub fn property_u32(&self, name: &str) -> Option<u32> {
+        cells(self.property(name)?).next()
+    }
+
+    /// 字符串属性，不包括结尾的0
+    pub fn property_str(&self, name: &str) -> Option<&'a str> {
+        Fdt::string_at(self.property(name)?, 0)
+    }
+
+    pub fn is_compatible(&self, compatible: &str) -> bool {
+        match self.property("compatible") {
+            Some(list) => list.split(|&byte| byte == 0).any(|name| name == compatible.as_bytes()),
+            None => false,
+        }
+    }
+
+    /// 没有status属性，或者status为"okay"
+    pub fn is_enabled(&self) -> bool {
+        match self.property_str("status") {
+            Some(status) => status == "okay" || status == "ok",
+            None => true,
+        }
+    }
+
+    pub fn phandle(&self) -> Option<u32> {
+        self.property_u32("phandle").or_else(|| self.property_u32("linux,phandle"))
+    }
+
+    /// reg中的第一段地址和大小
+    pub fn reg(&self) -> Option<(usize, usize)> {
+        let reg = self.property("reg")?;
+        let (address, rest) = read_cells(reg, self.address_cells)?;
+        let (size, _) = read_cells(rest, self.size_cells)?;
+        Some((address, size))
+    }
+
+    // 最后一个属性之后的位置
+    fn end_of_properties(&self) -> usize {
+        let mut properties = self.properties();
+        while properties.next().is_some() {}
+        properties.offset
+    }
+}
+
+// 同一个设备树中的节点按位置比较
+impl PartialEq for Node<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.properties == other.properties
+    }
+}
+
+/// 按顺序遍历设备树的节点
+pub struct Nodes<'a> {
+    fdt: Fdt<'a>,
+    offset: usize,
+    depth: usize,
+    // 每一层节点给子节点规定的#address-cells和#size-cells
+    cells: [(usize, usize); MAX_DEPTH + 1],
+}
+
+impl<'a> Iterator for Nodes<'a> {
+    type Item = Node<'a>;
+
+    fn next(&mut self) -> Option<Node<'a>> {
+        loop {
+            match self.fdt.token(self.offset)? {
+                TOKEN_BEGIN_NODE => {
+                    if self.depth == MAX_DEPTH {
+                        return None
+                    }
+                    let name = Fdt::string_at(self.fdt.structure, self.offset + 4)?;
+                    let (address_cells, size_cells) = self.cells[self.depth];
+                    let properties = align4(self.offset + 4 + name.len() + 1);
+                    let node = Node { fdt: self.fdt, name, depth: self.depth, address_cells, size_cells, properties };
+                    self.depth += 1;
+                    self.cells[self.depth] = (
+                        node.property_u32("#address-cells").map_or(DEFAULT_ADDRESS_CELLS, |cells| cells as usize),
+                        node.property_u32("#size-cells").map_or(DEFAULT_SIZE_CELLS, |cells| cells as usize),
+                    );
+                    self.offset = node.end_of_properties();
+                    return Some(node)
+                }
+                TOKEN_END_NODE => {
+                    self.depth = self.depth.checked_sub(1)?;
+                    self.offset += 4;
+                }
+                TOKEN_NOP => self.offset += 4,
+                // FDT_END或者格式错误
+                _ => return None,
+            }
+        }
+    }
+}
+
+/// 节点的一个属性
+#[derive(Clone, Copy)]
+pub struct Property<'a> {
+    pub name: &'a str,
+    pub value: &'a [u8],
+}
+
+/// 遍历节点的属性，遇到子节点或节点结束时停止
+pub struct Properties<'a> {
+    fdt: Fdt<'a>,
+    offset: usize,
+}
+
+impl<'a> Iterator for Properties<'a> {
+    type Item = Property<'a>;
+
+    fn next(&mut self) -> Option<Property<'a>> {
+        loop {
+            match self.fdt.token(self.offset)? {
+                TOKEN_PROP => {
+                    let structure = self.fdt.structure;
+                    let len = be32(structure.get(self.offset + 4..self.offset + 8)?, 0) as usize;
+                    let name_offset = be32(structure.get(self.offset + 8..self.offset + 12)?, 0) as usize;
+                    let value = structure.get(self.offset + 12..self.offset + 12 + len)?;
+                    let name = Fdt::string_at(self.fdt.strings, name_offset)?;
+                    self.offset = align4(self.offset + 12 + len);
+                    return Some(Property { name, value })
+                }
+                TOKEN_NOP => self.offset += 4,
+                _ => return None,
+            }
+        }
+    }
+}
+
+// 新增的属性名占用的最大字节数
+const EXTRA_STRINGS_SIZE: usize = 256;
+
+/// 在原来的设备树的基础上写出新的设备树
+///
+/// 保留内存表和字符串块原样复制，结构块由调用者按顺序写出节点和属性，或者用`copy_structure`原样复制。
+/// 写到缓冲区外面时只做记录，由`finish`报告错误
+pub struct Writer<'a, 'b> {
+    fdt: Fdt<'a>,
+    buffer: &'b mut [u8],
+    structure_offset: usize,
+    offset: usize,
+    // 原来的字符串块中没有的属性名，放在原来的字符串块后面
+    extra_strings: [u8; EXTRA_STRINGS_SIZE],
+    extra_len: usize,
+    overflow: bool,
+}
+
+impl<'a, 'b> Writer<'a, 'b> {
+    /// `buffer`需要对齐到8个字节
+    pub fn new(fdt: &Fdt<'a>, buffer: &'b mut [u8]) -> Self {
+        Writer::with_reservation(fdt, buffer, None)
+    }
+
+    /// 和`new`相同，并在保留内存表中加上`reservation`（地址和大小）
+    pub fn with_reservation(fdt: &Fdt<'a>, buffer: &'b mut [u8], reservation: Option<(usize, usize)>) -> Self {
+        let mut writer = Writer {
+            fdt: *fdt,
+            buffer,
+            structure_offset: 0,
+            offset: HEADER_SIZE,
+            extra_strings: [0; EXTRA_STRINGS_SIZE],
+            extra_len: 0,
+            overflow: false,
+        };
+        // 最后一项是结束标记，新的一项加在它前面
+        let (entries, end) = fdt.reservations.split_at(fdt.reservations.len() - 16);
+        writer.write(entries);
+        if let Some((base, size)) = reservation {
+            writer.write(&(base as u64).to_be_bytes());
+            writer.write(&(size as u64).to_be_bytes());
+        }
+        writer.write(end);
+        writer.structure_offset = writer.offset;
+        writer
+    }
+
+    /// 原样写出原来的设备树的所有节点
+    pub fn copy_structure(&mut self) {
+        let structure = self.fdt.structure;
+        let end = TOKEN_END.to_be_bytes();
+        let nodes = if structure.ends_with(&end) { &structure[..structure.len() - end.len()] } else { structure };
+        self.write(nodes);
+    }
+
+    pub fn begin_node(&mut self, name: &str) {
+        self.write(&TOKEN_BEGIN_NODE.to_be_bytes());
+        self.write(name.as_bytes());
+        self.write(&[0]);
+        self.pad();
+    }
+
+    pub fn end_node(&mut self) {
+        self.write(&TOKEN_END_NODE.to_be_bytes());
+    }
+
+    pub fn property(&mut self, name: &str, value: &[u8]) {
+        self.property_header(name, value.len());
+        self.write(value);
+        self.pad();
+    }
+
+    /// 字符串属性，自动加上结尾的0
+    pub fn property_str(&mut self, name: &str, value: &str) {
+        self.property_header(name, value.len() + 1);
+        self.write(value.as_bytes());
+        self.write(&[0]);
+        self.pad();
+    }
+
+    pub fn property_cells(&mut self, name: &str, cells: &[u32]) {
+        self.property_header(name, cells.len() * 4);
+        for cell in cells {
+            self.write(&cell.to_be_bytes());
+        }
+    }
+
+    /// 写出结束记号、字符串块和头部，返回设备树的大小
+    pub fn finish(mut self) -> Result<usize, Error> {
+        self.write(&TOKEN_END.to_be_bytes());
+        let structure_size = self.offset - self.structure_offset;
+        let strings_offset = self.offset;
+        let (strings, extra_strings) = (self.fdt.strings, self.extra_strings);
+        self.write(strings);
+        self.write(&extra_strings[..self.extra_len]);
+        if self.overflow {
+            return Err(Error::NoSpace)
+        }
+        let header = [
+            MAGIC,
+            self.offset as u32,
+            self.structure_offset as u32,
+            strings_offset as u32,
+            HEADER_SIZE as u32,
+            VERSION,
+            LAST_COMPATIBLE_VERSION,
+            be32(self.fdt.data, 28), // boot_cpuid_phys
+            (strings.len() + self.extra_len) as u32,
+            structure_size as u32,
+        ];
+        for (i, field) in header.iter().enumerate() {
+            self.buffer[i * 4..i * 4 + 4].copy_from_slice(&field.to_be_bytes());
+        }
+        Ok(self.offset)
+    }
+
+    fn property_header(&mut self, name: &str, len: usize) {
+        let name_offset = self.string_offset(name);
+        self.write(&TOKEN_PROP.to_be_bytes());
+        self.write(&(len as u32).to_be_bytes());
+        self.write(&name_offset.to_be_bytes());
+    }
+
+    // 属性名在新的字符串块中的位置，原来没有时加在后面
+    fn string_offset(&mut self, name: &str) -> u32 {
+        let strings = self.fdt.strings;
+        if let Some(offset) = find_string(strings, name) {
+            return offset as u32
+        }
+        if let Some(offset) = find_string(&self.extra_strings[..self.extra_len], name) {
+            return (strings.len() + offset) as u32
+        }
+        let offset = self.extra_len;
+        match self.extra_strings.get_mut(offset..offset + name.len() + 1) {
+            Some(target) => {
+                target[..name.len()].copy_from_slice(name.as_bytes());
+                target[name.len()] = 0;
+                self.extra_len += name.len() + 1;
+            }
+            None => self.overflow = true,
+        }
+        (strings.len() + offset) as u32
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        match self.buffer.get_mut(self.offset..self.offset + bytes.len()) {
+            Some(target) => target.copy_from_slice(bytes),
+            None => self.overflow = true,
+        }
+        self.offset += bytes.len();
+    }
+
+    fn pad(&mut self) {
+        while self.offset & 3 != 0 {
+            self.write(&[0]);
+        }
+    }
+}
+
+// 以0结尾的字符串在字符串块中的位置；属性名可以指向另一个字符串的后半部分
+fn find_string(strings: &[u8], name: &str) -> Option<usize> {
+    let name = name.as_bytes();
+    (0..strings.len()).find(|&i| strings[i..].starts_with(name) && strings.get(i + name.len()) == Some(&0))
+}
+
+/// 属性值中的大端32位单元
+pub fn cells(value: &[u8]) -> impl Iterator<Item = u32> + '_ {
+    value.chunks_exact(4).map(|cell| u32::from_be_bytes([cell[0], cell[1], cell[2], cell[3]]))
+}
+
+// 读取count个单元组成的数，返回剩下的部分
+fn read_cells(value: &[u8], count: usize) -> Option<(usize, &[u8])> {
+    let bytes = value.get(..count * 4)?;
+    let number = cells(bytes).fold(0u64, |number, cell| (number << 32) | cell as u64);
+    Some((number as usize, &value[count * 4..]))
+}
+
+fn be32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    // 按规范的布局生成设备树：头部、空的保留内存表、结构块、字符串块
+    struct Builder {
+        structure: Vec<u8>,
+        strings: Vec<u8>,
+    }
+
+    impl Builder {
+        fn new() -> Self {
+            Builder { structure: Vec::new(), strings: Vec::new() }
+        }
+
+        fn token(&mut self, token: u32) -> &mut Self {
+            self.structure.extend_from_slice(&token.to_be_bytes());
+            self
+        }
+
+        fn begin(&mut self, name: &str) -> &mut Self {
+            self.token(TOKEN_BEGIN_NODE);
+            self.structure.extend_from_slice(name.as_bytes());
+            self.structure.push(0);
+            self.pad()
+        }
+
+        fn end(&mut self) -> &mut Self {
+            self.token(TOKEN_END_NODE)
+        }
+
+        fn property(&mut self, name: &str, value: &[u8]) -> &mut Self {
+            let name_offset = self.strings.len() as u32;
+            self.strings.extend_from_slice(name.as_bytes());
+            self.strings.push(0);
+            self.token(TOKEN_PROP).token(value.len() as u32).token(name_offset);
+            self.structure.extend_from_slice(value);
+            self.pad()
+        }
+
+        fn cells(&mut self, name: &str, cells: &[u32]) -> &mut Self {
+            let value: Vec<u8> = cells.iter().flat_map(|cell| cell.to_be_bytes().to_vec()).collect();
+            self.property(name, &value)
+        }
+
+        fn pad(&mut self) -> &mut Self {
+            while self.structure.len() & 3 != 0 {
+                self.structure.push(0);
+            }
+            self
+        }
+
+        fn finish(&mut self) -> Vec<u8> {
+            self.token(9); // FDT_END
+            let structure_offset = HEADER_SIZE + 16;
+            let strings_offset = structure_offset + self.structure.len();
+            let total = strings_offset + self.strings.len();
+            let header = [MAGIC, total as u32, structure_offset as u32, strings_offset as u32,
+                HEADER_SIZE as u32, 17, 16, 0, self.strings.len() as u32, self.structure.len() as u32];
+            let mut data: Vec<u8> = header.iter().flat_map(|word| word.to_be_bytes().to_vec()).collect();
+            data.extend_from_slice(&[0; 16]);
+            data.extend_from_slice(&self.structure);
+            data.extend_from_slice(&self.strings);
+            data
+        }
+    }
+
+    fn sample() -> Vec<u8> {
+        Builder::new()
+            .begin("").cells("#address-cells", &[2]).cells("#size-cells", &[2])
+                .begin("memory@80000000").property("device_type", b"memory\0").cells("reg", &[0, 0x8000_0000, 0, 0x1000_0000]).end()
+                .begin("cpus").cells("#address-cells", &[1]).cells("#size-cells", &[0]).cells("timebase-frequency", &[10_000_000])
+                    .begin("cpu@0").property("device_type", b"cpu\0").cells("reg", &[0])
+                        .begin("interrupt-controller").property("compatible", b"riscv,cpu-intc\0").cells("phandle", &[2]).end()
+                    .end()
+                    .begin("cpu@1").property("device_type", b"cpu\0").cells("reg", &[1]).property("status", b"disabled\0").end()
+                .end()
+                .begin("soc").cells("#address-cells", &[2]).cells("#size-cells", &[2])
+                    .token(TOKEN_NOP)
+                    .begin("serial@10000000").property("compatible", b"ns16550a\0").cells("reg", &[0, 0x1000_0000, 0, 0x100])
+                        .cells("interrupts", &[10]).cells("clock-frequency", &[0x38_4000]).end()
+                    .begin("test@100000").property("compatible", b"sifive,test1\0sifive,test0\0syscon\0")
+                        .cells("reg", &[0, 0x10_0000, 0, 0x1000]).cells("phandle", &[4]).end()
+                .end()
+            .end()
+            .finish()
+    }
+
+    #[test]
+    fn nodes_and_properties() {
+        let data = sample();
+        let fdt = Fdt::new(&data).unwrap();
+        let names: Vec<(&str, usize)> = fdt.nodes().map(|node| (node.name, node.depth)).collect();
+        assert_eq!(names, [("", 0), ("memory@80000000", 1), ("cpus", 1), ("cpu@0", 2), ("interrupt-controller", 3),
+            ("cpu@1", 2), ("soc", 1), ("serial@10000000", 2), ("test@100000", 2)]);
+        let memory = fdt.nodes().find(|node| node.property_str("device_type") == Some("memory")).unwrap();
+        assert_eq!(memory.reg(), Some((0x8000_0000, 0x1000_0000)));
+        let cpu = fdt.nodes().find(|node| node.name == "cpu@1").unwrap();
+        assert_eq!((cpu.reg(), cpu.is_enabled()), (Some((1, 0)), false));
+        let uart = fdt.find_compatible(&["ns16550", "ns16550a"]).unwrap();
+        assert_eq!(uart.reg(), Some((0x1000_0000, 0x100)));
+        assert_eq!(uart.property_u32("clock-frequency"), Some(0x38_4000));
+        assert_eq!(uart.property_u32("reg-shift"), None);
+        let test = fdt.find_phandle(4).unwrap();
+        assert!(test.is_compatible("sifive,test0") && !test.is_compatible("sifive,test"));
+        assert_eq!(fdt.find_phandle(2).map(|node| node.name), Some("interrupt-controller"));
+        assert_eq!(cells(&[0, 0, 0, 1, 0, 0, 0, 11]).collect::<Vec<_>>(), [1, 11]);
+    }
+
+    #[test]
+    fn rewrite() {
+        let data = sample();
+        let fdt = Fdt::new(&data).unwrap();
+        let mut buffer = [0u64; 128];
+        let bytes = unsafe { core::slice::from_raw_parts_mut(buffer.as_mut_ptr() as *mut u8, 1024) };
+        let mut writer = Writer::new(&fdt, bytes);
+        let mut open = 0;
+        for node in fdt.nodes().filter(|node| node.name != "cpu@1") {
+            while open > node.depth {
+                writer.end_node();
+                open -= 1;
+            }
+            writer.begin_node(node.name);
+            for property in node.properties() {
+                writer.property(property.name, property.value);
+            }
+            if node.name == "cpus" {
+                writer.property_str("tiaoshi-dashi", "patched");
+                writer.property_cells("reg", &[1, 2]);
+            }
+            open += 1;
+        }
+        while open > 0 {
+            writer.end_node();
+            open -= 1;
+        }
+        let len = writer.finish().unwrap();
+        let patched = Fdt::new(&bytes[..len]).unwrap();
+        assert_eq!(patched.nodes().count(), fdt.nodes().count() - 1);
+        let cpus = patched.nodes().find(|node| node.name == "cpus").unwrap();
+        assert_eq!(cpus.property_str("tiaoshi-dashi"), Some("patched"));
+        assert_eq!(cpus.property_u32("timebase-frequency"), Some(10_000_000));
+        assert_eq!(cells(cpus.property("reg").unwrap()).collect::<Vec<_>>(), [1, 2]);
+        assert_eq!(patched.find_compatible(&["ns16550a"]).and_then(|node| node.reg()), Some((0x1000_0000, 0x100)));
+        // 新的属性名只加一次，已有的属性名不重复
+        assert_eq!(len - be32(bytes, 12) as usize, fdt.strings.len() + "tiaoshi-dashi\0".len());
+        assert_eq!(Writer::new(&fdt, &mut [0; 64]).finish().err(), Some(Error::NoSpace));
+    }
+
+    #[test]
+    fn reservation() {
+        let data = sample();
+        let fdt = Fdt::new(&data).unwrap();
+        let mut buffer = [0u64; 128];
+        let bytes = unsafe { core::slice::from_raw_parts_mut(buffer.as_mut_ptr() as *mut u8, 1024) };
+        let mut writer = Writer::with_reservation(&fdt, bytes, Some((0x8000_0000, 0x4_0000)));
+        writer.copy_structure();
+        let len = writer.finish().unwrap();
+        let copied = Fdt::new(&bytes[..len]).unwrap();
+        assert_eq!(copied.reservations.len(), 32);
+        let (base, rest) = read_cells(copied.reservations, 2).unwrap();
+        assert_eq!((base, read_cells(rest, 2).map(|(size, _)| size)), (0x8000_0000, Some(0x4_0000)));
+        assert_eq!(copied.structure, fdt.structure);
+        assert!(copied.nodes().map(|node| node.name).eq(fdt.nodes().map(|node| node.name)));
+    }
+
+    #[test]
+    fn bad_blobs() {
+        let mut data = sample();
+        assert_eq!(Fdt::new(&data[..20]).err(), Some(Error::Truncated));
+        let len = data.len();
+        assert_eq!(Fdt::new(&data[..len - 1]).err(), Some(Error::Truncated));
+        let mut short = data.clone();
+        short[4..8].copy_from_slice(&(HEADER_SIZE as u32 - 1).to_be_bytes());
+        assert_eq!(Fdt::new(&short).err(), Some(Error::Truncated));
+        data[0] = 0;
+        assert_eq!(Fdt::new(&data).err(), Some(Error::BadMagic));
+    }
+}
@@ -0,0 +1,12 @@
+// 调试大师中不访问硬件的解码代码：反汇编、单步需要的指令分析、页表翻译和设备树
+//
+// 调试大师本身只能编译到RISC-V裸机目标，这些代码单独成为一个库，测试可以在开发机上运行：
+// 在这个目录下执行cargo test
+#![cfg_attr(not(test), no_std)]
+
+extern crate alloc;
+
+pub mod disasm;
+pub mod fdt;
+pub mod paging;
+pub mod step;
@@ -0,0 +1,312 @@
+// 软件实现的页表翻译，支持Sv39、Sv48和Sv57
+//
+// 调试器需要以S或U特权级的视角访问内存，这里按照特权级规范的流程逐级查找页表，
+// 并记录每一级的页表项，翻译失败时可以打印出完整的翻译过程
+use alloc::vec::Vec;
+use core::fmt;
+
+pub const SATP_MODE_BARE: usize = 0;
+pub const SATP_MODE_SV39: usize = 8;
+pub const SATP_MODE_SV48: usize = 9;
+pub const SATP_MODE_SV57: usize = 10;
+
+const SATP_PPN_MASK: usize = (1 << 44) - 1;
+const PTE_PPN_MASK: usize = (1 << 44) - 1;
+const PAGE_SHIFT: usize = 12;
+const VPN_BITS: usize = 9;
+const PTE_SIZE: usize = 8;
+
+pub const PTE_V: usize = 1 << 0;
+pub const PTE_R: usize = 1 << 1;
+pub const PTE_W: usize = 1 << 2;
+pub const PTE_X: usize = 1 << 3;
+pub const PTE_U: usize = 1 << 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Privilege {
+    Supervisor,
+    User,
+}
+
+/// 一次翻译需要的处理器状态
+#[derive(Clone, Copy, Debug)]
+pub struct Translation {
+    pub satp: usize,
+    pub privilege: Privilege,
+    /// mstatus.SUM，允许S态访问U态页面
+    pub sum: bool,
+    /// mstatus.MXR，允许读取只可执行的页面
+    pub mxr: bool,
+}
+
+/// 翻译过程中访问过的一个页表项
+#[derive(Clone, Copy, Debug)]
+pub struct Step {
+    pub level: usize,
+    pub pte_address: usize,
+    pub pte: usize,
+}
+
+impl Step {
+    pub fn is_leaf(&self) -> bool {
+        self.pte & PTE_V != 0 && self.pte & (PTE_R | PTE_X) != 0
+    }
+
+    pub fn next_table(&self) -> usize {
+        ((self.pte >> 10) & PTE_PPN_MASK) << PAGE_SHIFT
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    UnsupportedMode(usize),
+    PteAccessFault { address: usize, cause: usize },
+    NonCanonical,
+    Invalid,
+    Reserved,
+    NoLeaf,
+    MisalignedSuperpage,
+    NotUserPage,
+    UserPageWithoutSum,
+    UserPageExecute,
+    NotReadable,
+    NotWritable,
+    NotExecutable,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fault::UnsupportedMode(mode) => write!(f, "unsupported satp mode {}", mode),
+            Fault::PteAccessFault { address, cause } =>
+                write!(f, "access fault reading PTE at PhysMem[{:#x}], mcause = {:#x}", address, cause),
+            Fault::NonCanonical => write!(f, "virtual address is not canonical"),
+            Fault::Invalid => write!(f, "PTE is not valid (V = 0)"),
+            Fault::Reserved => write!(f, "reserved PTE encoding (W = 1, R = 0)"),
+            Fault::NoLeaf => write!(f, "no leaf PTE at the last level"),
+            Fault::MisalignedSuperpage => write!(f, "misaligned superpage (low PPN bits not zero)"),
+            Fault::NotUserPage => write!(f, "user access to a page without U bit"),
+            Fault::UserPageWithoutSum => write!(f, "supervisor access to a user page while mstatus.SUM = 0"),
+            Fault::UserPageExecute => write!(f, "supervisor cannot execute a user page"),
+            Fault::NotReadable => write!(f, "page is not readable"),
+            Fault::NotWritable => write!(f, "page is not writable"),
+            Fault::NotExecutable => write!(f, "page is not executable"),
+        }
+    }
+}
+
+/// 翻译的结果，包括访问过的所有页表项
+#[derive(Clone, Debug)]
+pub struct Walk {
+    pub steps: Vec<Step>,
+    pub result: Result<usize, Fault>,
+}
+
+pub fn satp_mode(satp: usize) -> usize {
+    satp >> 60
+}
+
+pub fn satp_root(satp: usize) -> usize {
+    (satp & SATP_PPN_MASK) << PAGE_SHIFT
+}
+
+pub fn mode_name(mode: usize) -> &'static str {
+    match mode {
+        SATP_MODE_BARE => "Bare",
+        SATP_MODE_SV39 => "Sv39",
+        SATP_MODE_SV48 => "Sv48",
+        SATP_MODE_SV57 => "Sv57",
+        _ => "Unknown",
+    }
+}
+
+/// 把页表项的标志位写成“DAGUXWRV”的形式，清零的位用“-”表示
+pub fn flags(pte: usize) -> [u8; 8] {
+    let mut ans = *b"DAGUXWRV";
+    for (i, ch) in ans.iter_mut().enumerate() {
+        if pte & (1 << (7 - i)) == 0 {
+            *ch = b'-';
+        }
+    }
+    ans
+}
+
+impl Translation {
+    /// 翻译虚拟地址；`read_pte`用于读取给定物理地址上的页表项，读取失败时返回mcause
+    pub fn walk<F: FnMut(usize) -> Result<usize, usize>>(&self, vaddr: usize, access: Access, mut read_pte: F) -> Walk {
+        let mut steps = Vec::new();
+        let levels = match satp_mode(self.satp) {
+            SATP_MODE_BARE => return Walk { steps, result: Ok(vaddr) },
+            SATP_MODE_SV39 => 3,
+            SATP_MODE_SV48 => 4,
+            SATP_MODE_SV57 => 5,
+            mode => return Walk { steps, result: Err(Fault::UnsupportedMode(mode)) },
+        };
+        // 高位必须是最高有效位的符号扩展
+        let va_bits = PAGE_SHIFT + VPN_BITS * levels;
+        let high = (vaddr as isize) >> (va_bits - 1);
+        if high != 0 && high != -1 {
+            return Walk { steps, result: Err(Fault::NonCanonical) };
+        }
+        let mut table = satp_root(self.satp);
+        for level in (0..levels).rev() {
+            let vpn = (vaddr >> (PAGE_SHIFT + VPN_BITS * level)) & ((1 << VPN_BITS) - 1);
+            let pte_address = table + vpn * PTE_SIZE;
+            let pte = match read_pte(pte_address) {
+                Ok(pte) => pte,
+                Err(cause) => return Walk { steps, result: Err(Fault::PteAccessFault { address: pte_address, cause }) },
+            };
+            let step = Step { level, pte_address, pte };
+            steps.push(step);
+            if pte & PTE_V == 0 {
+                return Walk { steps, result: Err(Fault::Invalid) };
+            }
+            if pte & PTE_R == 0 && pte & PTE_W != 0 {
+                return Walk { steps, result: Err(Fault::Reserved) };
+            }
+            if !step.is_leaf() {
+                table = step.next_table();
+                continue;
+            }
+            let ppn = (pte >> 10) & PTE_PPN_MASK;
+            if ppn & ((1 << (VPN_BITS * level)) - 1) != 0 {
+                return Walk { steps, result: Err(Fault::MisalignedSuperpage) };
+            }
+            if let Err(fault) = self.check_permission(pte, access) {
+                return Walk { steps, result: Err(fault) };
+            }
+            let offset_mask = (1 << (PAGE_SHIFT + VPN_BITS * level)) - 1;
+            let paddr = ((ppn << PAGE_SHIFT) & !offset_mask) | (vaddr & offset_mask);
+            return Walk { steps, result: Ok(paddr) };
+        }
+        Walk { steps, result: Err(Fault::NoLeaf) }
+    }
+
+    fn check_permission(&self, pte: usize, access: Access) -> Result<(), Fault> {
+        let user_page = pte & PTE_U != 0;
+        match self.privilege {
+            Privilege::User if !user_page => return Err(Fault::NotUserPage),
+            Privilege::Supervisor if user_page && access == Access::Execute => return Err(Fault::UserPageExecute),
+            Privilege::Supervisor if user_page && !self.sum => return Err(Fault::UserPageWithoutSum),
+            _ => {}
+        }
+        match access {
+            Access::Read if pte & PTE_R == 0 && !(self.mxr && pte & PTE_X != 0) => Err(Fault::NotReadable),
+            Access::Write if pte & PTE_W == 0 => Err(Fault::NotWritable),
+            Access::Execute if pte & PTE_X == 0 => Err(Fault::NotExecutable),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Sv39，根页表在0x1000，下一级页表在0x2000；虚拟地址0x4020_1234的VPN[2]和VPN[1]都是1
+    const SATP: usize = (SATP_MODE_SV39 << 60) | 0x1;
+    const VADDR: usize = 0x4020_1234;
+    const ROOT_PTE: usize = 0x1008;
+    const LEVEL1_PTE: usize = 0x2008;
+    const PTE_TABLE: usize = (0x2 << 10) | PTE_V;
+
+    fn translation(privilege: Privilege, sum: bool, mxr: bool) -> Translation {
+        Translation { satp: SATP, privilege, sum, mxr }
+    }
+
+    // 物理内存中只有根页表项和一个2 MiB大页的页表项，其它地址读出0
+    fn walk_leaf(translation: Translation, leaf: usize, access: Access) -> Walk {
+        translation.walk(VADDR, access, |address| match address {
+            ROOT_PTE => Ok(PTE_TABLE),
+            LEVEL1_PTE => Ok(leaf),
+            _ => Ok(0),
+        })
+    }
+
+    #[test]
+    fn superpage() {
+        let leaf = (0x80200 << 10) | PTE_V | PTE_R | PTE_W;
+        let walk = walk_leaf(translation(Privilege::Supervisor, false, false), leaf, Access::Write);
+        assert_eq!(walk.result, Ok(0x8020_1234));
+        assert_eq!(walk.steps.len(), 2);
+        assert_eq!((walk.steps[0].level, walk.steps[0].pte_address), (2, ROOT_PTE));
+        assert_eq!(walk.steps[0].next_table(), 0x2000);
+        assert_eq!((walk.steps[1].level, walk.steps[1].pte_address), (1, LEVEL1_PTE));
+        assert!(walk.steps[1].is_leaf());
+    }
+
+    #[test]
+    fn misaligned_superpage() {
+        let leaf = (0x80201 << 10) | PTE_V | PTE_R;
+        let walk = walk_leaf(translation(Privilege::Supervisor, false, false), leaf, Access::Read);
+        assert_eq!(walk.result, Err(Fault::MisalignedSuperpage));
+    }
+
+    #[test]
+    fn non_canonical() {
+        let walk = translation(Privilege::Supervisor, false, false)
+            .walk(0x40_0000_0000, Access::Read, |_| panic!("no PTE should be read"));
+        assert_eq!(walk.result, Err(Fault::NonCanonical));
+        assert!(walk.steps.is_empty());
+        // 符号扩展后的高半部分地址是合法的
+        let walk = translation(Privilege::Supervisor, false, false)
+            .walk(0xffff_ffc0_0000_0000, Access::Read, |_| Ok(0));
+        assert_eq!(walk.result, Err(Fault::Invalid));
+    }
+
+    #[test]
+    fn user_page() {
+        let leaf = (0x80200 << 10) | PTE_V | PTE_R | PTE_X | PTE_U;
+        let walk = walk_leaf(translation(Privilege::Supervisor, false, false), leaf, Access::Read);
+        assert_eq!(walk.result, Err(Fault::UserPageWithoutSum));
+        let walk = walk_leaf(translation(Privilege::Supervisor, true, false), leaf, Access::Read);
+        assert_eq!(walk.result, Ok(0x8020_1234));
+        // SUM只允许读写，S态永远不能执行U态页面
+        let walk = walk_leaf(translation(Privilege::Supervisor, true, false), leaf, Access::Execute);
+        assert_eq!(walk.result, Err(Fault::UserPageExecute));
+        let walk = walk_leaf(translation(Privilege::User, false, false), leaf, Access::Execute);
+        assert_eq!(walk.result, Ok(0x8020_1234));
+        let leaf = leaf & !PTE_U;
+        let walk = walk_leaf(translation(Privilege::User, false, false), leaf, Access::Read);
+        assert_eq!(walk.result, Err(Fault::NotUserPage));
+    }
+
+    #[test]
+    fn execute_only() {
+        let leaf = (0x80200 << 10) | PTE_V | PTE_X;
+        let walk = walk_leaf(translation(Privilege::Supervisor, false, false), leaf, Access::Read);
+        assert_eq!(walk.result, Err(Fault::NotReadable));
+        let walk = walk_leaf(translation(Privilege::Supervisor, false, true), leaf, Access::Read);
+        assert_eq!(walk.result, Ok(0x8020_1234));
+        // MXR不允许写入
+        let walk = walk_leaf(translation(Privilege::Supervisor, false, true), leaf, Access::Write);
+        assert_eq!(walk.result, Err(Fault::NotWritable));
+    }
+
+    #[test]
+    fn reserved() {
+        let leaf = (0x80200 << 10) | PTE_V | PTE_W;
+        let walk = walk_leaf(translation(Privilege::Supervisor, false, false), leaf, Access::Write);
+        assert_eq!(walk.result, Err(Fault::Reserved));
+        assert_eq!(walk.steps.len(), 2);
+    }
+
+    #[test]
+    fn pte_access_fault() {
+        // 读不到的页表项不在记录的页表项中，记录的页表项都指向下一级页表
+        let walk = translation(Privilege::Supervisor, false, false).walk(VADDR, Access::Read, |address| match address {
+            ROOT_PTE => Ok(PTE_TABLE),
+            _ => Err(5),
+        });
+        assert_eq!(walk.result, Err(Fault::PteAccessFault { address: LEVEL1_PTE, cause: 5 }));
+        assert_eq!(walk.steps.len(), 1);
+        assert!(!walk.steps[0].is_leaf());
+    }
+}
@@ -0,0 +1,164 @@
+// 单步执行需要的指令分析
+//
+// 没有icount触发器时，调试器在当前指令所有可能的后继地址上设置临时断点来实现单步
+use crate::disasm;
+use alloc::vec::Vec;
+
+/// 指令执行后可能到达的所有地址，不包括陷入的情况
+///
+/// `x`读取通用寄存器，`sepc`是sret返回的地址
+pub fn successors(ins: u32, pc: usize, x: impl Fn(usize) -> usize, sepc: usize) -> Vec<usize> {
+    let len = disasm::instruction_length(ins as u16);
+    let next = pc.wrapping_add(len);
+    let mut ans = Vec::new();
+    if len == 2 {
+        let funct3 = (ins >> 13) & 0b111;
+        let rs1 = ((ins >> 7) & 0x1f) as usize;
+        let rs2 = (ins >> 2) & 0x1f;
+        match (ins & 0b11, funct3) {
+            // c.j
+            (0b01, 0b101) => ans.push(disasm::target(pc, disasm::c_jump_offset(ins))),
+            // c.beqz、c.bnez
+            (0b01, 0b110) | (0b01, 0b111) => {
+                ans.push(next);
+                ans.push(disasm::target(pc, disasm::c_branch_offset(ins)));
+            }
+            // c.jr、c.jalr
+            (0b10, 0b100) if rs1 != 0 && rs2 == 0 => ans.push(x(rs1) & !1),
+            _ => ans.push(next),
+        }
+    } else {
+        let rs1 = ((ins >> 15) & 0x1f) as usize;
+        match ins & 0x7f {
+            // jal
+            0x6f => ans.push(disasm::target(pc, disasm::imm_j(ins))),
+            // jalr
+            0x67 => ans.push(x(rs1).wrapping_add(disasm::imm_i(ins) as isize as usize) & !1),
+            // 条件分支
+            0x63 => {
+                ans.push(next);
+                ans.push(disasm::target(pc, disasm::imm_b(ins)));
+            }
+            // sret
+            0x73 if ins == 0x1020_0073 => ans.push(sepc),
+            _ => ans.push(next),
+        }
+    }
+    ans
+}
+
+/// 是否为函数调用，即链接寄存器为ra或t0的jal、jalr和c.jalr
+pub fn is_call(ins: u32) -> bool {
+    if disasm::instruction_length(ins as u16) == 2 {
+        // c.jalr
+        (ins & 0xf07f) == 0x9002 && (ins >> 7) & 0x1f != 0
+    } else {
+        let rd = (ins >> 7) & 0x1f;
+        (ins & 0x7f == 0x6f || ins & 0x7f == 0x67) && (rd == 1 || rd == 5)
+    }
+}
+
+/// 访存指令访问的地址范围
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryAccess {
+    pub address: usize,
+    /// 访问的字节数
+    pub width: usize,
+    pub store: bool,
+}
+
+/// 解码访存指令，用`x`读取通用寄存器计算访问的地址；不是访存指令时返回None
+pub fn memory_access(ins: u32, x: impl Fn(usize) -> usize) -> Option<MemoryAccess> {
+    let bits = |hi: u32, lo: u32| ((ins >> lo) & ((1 << (hi - lo + 1)) - 1)) as usize;
+    let access = |base: usize, offset: isize, width: usize, store: bool| {
+        Some(MemoryAccess { address: x(base).wrapping_add(offset as usize), width, store })
+    };
+    if disasm::instruction_length(ins as u16) == 2 {
+        // rs1'只能表示x8到x15
+        let rs1_short = 8 + bits(9, 7);
+        let offset_word = ((bits(12, 10) << 3) | (bits(6, 6) << 2) | (bits(5, 5) << 6)) as isize;
+        let offset_double = ((bits(12, 10) << 3) | (bits(6, 5) << 6)) as isize;
+        let offset_word_sp = ((bits(12, 12) << 5) | (bits(6, 4) << 2) | (bits(3, 2) << 6)) as isize;
+        let offset_double_sp = ((bits(12, 12) << 5) | (bits(6, 5) << 3) | (bits(4, 2) << 6)) as isize;
+        let offset_sw_sp = ((bits(12, 9) << 2) | (bits(8, 7) << 6)) as isize;
+        let offset_sd_sp = ((bits(12, 10) << 3) | (bits(9, 7) << 6)) as isize;
+        return match (ins & 0b11, bits(15, 13)) {
+            (0b00, 0b001) => access(rs1_short, offset_double, 8, false), // c.fld
+            (0b00, 0b010) => access(rs1_short, offset_word, 4, false), // c.lw
+            (0b00, 0b011) => access(rs1_short, offset_double, 8, false), // c.ld
+            (0b00, 0b101) => access(rs1_short, offset_double, 8, true), // c.fsd
+            (0b00, 0b110) => access(rs1_short, offset_word, 4, true), // c.sw
+            (0b00, 0b111) => access(rs1_short, offset_double, 8, true), // c.sd
+            (0b10, 0b001) => access(2, offset_double_sp, 8, false), // c.fldsp
+            (0b10, 0b010) => access(2, offset_word_sp, 4, false), // c.lwsp
+            (0b10, 0b011) => access(2, offset_double_sp, 8, false), // c.ldsp
+            (0b10, 0b101) => access(2, offset_sd_sp, 8, true), // c.fsdsp
+            (0b10, 0b110) => access(2, offset_sw_sp, 4, true), // c.swsp
+            (0b10, 0b111) => access(2, offset_sd_sp, 8, true), // c.sdsp
+            _ => None,
+        }
+    }
+    let rs1 = bits(19, 15);
+    let width = 1 << (bits(14, 12) & 0b11);
+    match ins & 0x7f {
+        // 整数和浮点读取
+        0x03 | 0x07 => access(rs1, disasm::imm_i(ins) as isize, width, false),
+        // 整数和浮点写入
+        0x23 | 0x27 => access(rs1, disasm::imm_s(ins) as isize, width, true),
+        // 原子指令，lr只读取，其它指令都会写入
+        0x2f => access(rs1, 0, width, bits(31, 27) != 0b00010),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    // 寄存器xN的值为0x1000 * N
+    fn x(index: usize) -> usize {
+        0x1000 * index
+    }
+
+    #[test]
+    fn successors_of_jumps() {
+        let pc = 0x8020_0000;
+        // jal ra,pc+8
+        assert_eq!(successors(0x008000ef, pc, x, 0), vec![pc + 8]);
+        // beq a0,a1,pc+16
+        assert_eq!(successors(0x00b50863, pc, x, 0), vec![pc + 4, pc + 16]);
+        // c.jr ra
+        assert_eq!(successors(0x8082, pc, x, 0), vec![0x1000]);
+        // jalr ra,4(a5)
+        assert_eq!(successors(0x004780e7, pc, x, 0), vec![0xf004]);
+        // sret
+        assert_eq!(successors(0x10200073, pc, x, 0x8020_1000), vec![0x8020_1000]);
+        // c.addi sp,-16
+        assert_eq!(successors(0x1141, pc, x, 0), vec![pc + 2]);
+    }
+
+    #[test]
+    fn calls() {
+        assert!(is_call(0x008000ef)); // jal ra
+        assert!(is_call(0x004780e7)); // jalr ra
+        assert!(is_call(0x9782)); // c.jalr a5
+        assert!(!is_call(0x8082)); // c.jr ra
+        assert!(!is_call(0x0080006f)); // j
+    }
+
+    #[test]
+    fn memory_accesses() {
+        // sd ra,8(sp)
+        assert_eq!(memory_access(0x00113423, x), Some(MemoryAccess { address: 0x2008, width: 8, store: true }));
+        // lw a0,-4(s0)
+        assert_eq!(memory_access(0xffc42503, x), Some(MemoryAccess { address: 0x7ffc, width: 4, store: false }));
+        // c.ldsp ra,8(sp)
+        assert_eq!(memory_access(0x60a2, x), Some(MemoryAccess { address: 0x2008, width: 8, store: false }));
+        // lr.w a0,(a1)和sc.w a0,a2,(a1)
+        assert_eq!(memory_access(0x1005a52f, x), Some(MemoryAccess { address: 0xb000, width: 4, store: false }));
+        assert_eq!(memory_access(0x18c5a52f, x), Some(MemoryAccess { address: 0xb000, width: 4, store: true }));
+        // addi a0,a0,1
+        assert_eq!(memory_access(0x00150513, x), None);
+    }
+}
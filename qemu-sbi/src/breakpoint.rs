@@ -0,0 +1,175 @@
+// 软件断点
+//
+// 断点只在被调试程序运行时写入内存。进入调试器时先恢复所有原来的指令，
+// 这样打印内存和反汇编时看到的都是原来的内容；继续运行前再重新写入ebreak
+use crate::probe;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+const EBREAK: u32 = 0x0010_0073;
+const C_EBREAK: u32 = 0x9002;
+
+#[derive(Clone, Debug)]
+pub struct Breakpoint {
+    pub number: usize,
+    /// 断点所在的虚拟地址，和被调试程序的pc比较
+    pub address: usize,
+    /// 设置断点时翻译得到的物理地址，写入ebreak时使用
+    pub paddr: usize,
+    pub original: u32,
+    /// 原来指令的长度，决定写入ebreak还是c.ebreak
+    pub len: usize,
+    pub enabled: bool,
+    inserted: bool,
+    /// 单步执行时设置的临时断点，没有编号，不在断点列表中显示
+    temporary: bool,
+}
+
+struct Table {
+    list: Vec<Breakpoint>,
+    next_number: usize,
+}
+
+static BREAKPOINTS: Mutex<Table> = Mutex::new(Table { list: Vec::new(), next_number: 1 });
+
+/// 指令是不是ebreak或c.ebreak
+pub fn is_ebreak(ins: u32) -> bool {
+    ins == EBREAK || ins == C_EBREAK
+}
+
+/// 添加断点，返回断点编号；如果这个地址已经有断点，返回Err和已有断点的编号
+pub fn add(address: usize, paddr: usize, original: u32, len: usize) -> Result<usize, usize> {
+    let mut table = BREAKPOINTS.lock();
+    if let Some(bp) = table.list.iter().find(|bp| !bp.temporary && bp.address == address) {
+        return Err(bp.number)
+    }
+    let number = allocate_number(&mut table);
+    table.list.push(Breakpoint { number, address, paddr, original, len, enabled: true, inserted: false, temporary: false });
+    Ok(number)
+}
+
+/// 分配一个新的编号；观察点和断点使用同一套编号
+pub fn next_number() -> usize {
+    allocate_number(&mut BREAKPOINTS.lock())
+}
+
+fn allocate_number(table: &mut Table) -> usize {
+    let number = table.next_number;
+    table.next_number += 1;
+    number
+}
+
+/// 添加临时断点，下一次进入调试器时由`clear_temporary`删除
+pub fn add_temporary(address: usize, paddr: usize, original: u32, len: usize) {
+    let mut table = BREAKPOINTS.lock();
+    if table.list.iter().any(|bp| bp.temporary && bp.address == address) {
+        return
+    }
+    table.list.push(Breakpoint { number: 0, address, paddr, original, len, enabled: true, inserted: false, temporary: true });
+}
+
+/// 删除所有临时断点，调用前应当先`remove_all`
+pub fn clear_temporary() {
+    BREAKPOINTS.lock().list.retain(|bp| !bp.temporary);
+}
+
+/// 删除断点，断点不存在时返回false
+pub fn delete(number: usize) -> bool {
+    let mut table = BREAKPOINTS.lock();
+    match table.list.iter().position(|bp| !bp.temporary && bp.number == number) {
+        Some(index) => {
+            let bp = table.list.remove(index);
+            if bp.inserted {
+                restore(&bp);
+            }
+            true
+        }
+        None => false,
+    }
+}
+
+/// 启用或禁用断点，断点不存在时返回false
+pub fn set_enabled(number: usize, enabled: bool) -> bool {
+    let mut table = BREAKPOINTS.lock();
+    match table.list.iter_mut().find(|bp| !bp.temporary && bp.number == number) {
+        Some(bp) => {
+            bp.enabled = enabled;
+            true
+        }
+        None => false,
+    }
+}
+
+pub fn list() -> Vec<Breakpoint> {
+    BREAKPOINTS.lock().list.iter().filter(|bp| !bp.temporary).cloned().collect()
+}
+
+/// 找到写入在这个地址上的断点，返回断点编号
+pub fn find_inserted(address: usize) -> Option<usize> {
+    BREAKPOINTS.lock().list.iter()
+        .find(|bp| !bp.temporary && bp.inserted && bp.address == address)
+        .map(|bp| bp.number)
+}
+
+/// 这个地址上断点的编号
+pub fn number_at(address: usize) -> Option<usize> {
+    BREAKPOINTS.lock().list.iter()
+        .find(|bp| !bp.temporary && bp.address == address)
+        .map(|bp| bp.number)
+}
+
+/// 这个地址上是否写入了临时断点
+pub fn temporary_inserted_at(address: usize) -> bool {
+    BREAKPOINTS.lock().list.iter().any(|bp| bp.temporary && bp.inserted && bp.address == address)
+}
+
+/// 这个地址上是否有启用的断点
+pub fn enabled_at(address: usize) -> bool {
+    BREAKPOINTS.lock().list.iter().any(|bp| !bp.temporary && bp.enabled && bp.address == address)
+}
+
+/// 恢复所有断点处原来的指令
+pub fn remove_all() {
+    let mut table = BREAKPOINTS.lock();
+    for bp in table.list.iter_mut().filter(|bp| bp.inserted) {
+        restore(bp);
+        bp.inserted = false;
+    }
+    fence_i();
+}
+
+/// 在所有启用的断点处写入ebreak，地址为`skip`的断点除外
+///
+/// 被调试程序将从`skip`继续运行，这里的断点要等原来的指令执行之后才能写入
+pub fn insert_all(skip: Option<usize>) {
+    let mut table = BREAKPOINTS.lock();
+    for bp in table.list.iter_mut().filter(|bp| bp.enabled && !bp.inserted && Some(bp.address) != skip) {
+        let ebreak = if bp.len == 2 { C_EBREAK } else { EBREAK };
+        // 指令只保证对齐到2字节，按半字写入
+        let ok = (0..bp.len / 2).all(|i| {
+            probe::store(bp.paddr + i * 2, 16, ((ebreak >> (i * 16)) & 0xffff) as u128).is_ok()
+        });
+        if ok {
+            bp.inserted = true;
+        } else if bp.temporary {
+            rustsbi::println!("[DebugSBI] Cannot insert temporary breakpoint at {:#x}", bp.address);
+            restore(bp);
+            bp.enabled = false;
+        } else {
+            rustsbi::println!("[DebugSBI] Cannot insert breakpoint {} at {:#x}, disabled", bp.number, bp.address);
+            restore(bp);
+            bp.enabled = false;
+        }
+    }
+    fence_i();
+}
+
+fn restore(bp: &Breakpoint) {
+    for i in 0..bp.len / 2 {
+        let _ = probe::store(bp.paddr + i * 2, 16, ((bp.original >> (i * 16)) & 0xffff) as u128);
+    }
+}
+
+fn fence_i() {
+    unsafe { asm!("fence.i") };
+}
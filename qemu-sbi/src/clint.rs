@@ -0,0 +1,62 @@
+// 核心本地中断器（CLINT）
+//
+// 提供mtime计数器、每个硬件线程的定时器比较值和软件中断。SBI定时器扩展由它实现
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use riscv::register::{mhartid, mie, mip};
+
+pub struct Clint {
+    // 启动时从设备树得到地址
+    base: AtomicUsize,
+}
+
+mod offsets {
+    pub const MSIP: usize = 0x0;
+    pub const MTIMECMP: usize = 0x4000;
+    pub const MTIME: usize = 0xbff8;
+}
+
+impl Clint {
+    pub const fn new(base: usize) -> Self {
+        Self { base: AtomicUsize::new(base) }
+    }
+
+    pub fn set_base(&self, base: usize) {
+        self.base.store(base, Ordering::Relaxed);
+    }
+
+    fn base(&self) -> usize {
+        self.base.load(Ordering::Relaxed)
+    }
+
+    pub fn mtime(&self) -> u64 {
+        unsafe { read_volatile((self.base() + offsets::MTIME) as *const u64) }
+    }
+
+    pub fn mtimecmp(&self, hart_id: usize) -> u64 {
+        unsafe { read_volatile((self.base() + offsets::MTIMECMP + hart_id * 8) as *const u64) }
+    }
+
+    pub fn set_mtimecmp(&self, hart_id: usize, value: u64) {
+        unsafe { write_volatile((self.base() + offsets::MTIMECMP + hart_id * 8) as *mut u64, value) };
+    }
+
+    pub fn send_soft(&self, hart_id: usize) {
+        unsafe { write_volatile((self.base() + offsets::MSIP + hart_id * 4) as *mut u32, 1) };
+    }
+
+    pub fn clear_soft(&self, hart_id: usize) {
+        unsafe { write_volatile((self.base() + offsets::MSIP + hart_id * 4) as *mut u32, 0) };
+    }
+}
+
+impl rustsbi::Timer for Clint {
+    fn set_timer(&self, stime_value: u64) {
+        self.set_mtimecmp(mhartid::read(), stime_value);
+        // 清除转发给S态的定时器中断，重新打开M态定时器中断，到时间后再次转发
+        unsafe {
+            mip::clear_stimer();
+            mie::set_mtimer();
+        }
+    }
+}
@@ -0,0 +1,137 @@
+// SBI的调试控制台（DBCN）扩展和旧版的console_putchar
+//
+// 内核传入缓冲区的物理地址，按S态的权限访问；PMP不允许或者地址不存在时返回SBI_ERR_INVALID_PARAM。
+// 调试器使用串口时，内核的输出先放进缓冲区，在调试器的命令提示符之前按行打印，不会和调试器的输出混在一起
+use crate::error::{self, SBI_ERR_INVALID_PARAM, SBI_ERR_NOT_SUPPORTED};
+use crate::{halt, platform, probe, uart};
+use rustsbi::{SbiRet, println, legacy_stdio_putchar};
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+const EXTENSION_BASE: usize = 0x10;
+const FUNCTION_PROBE_EXTENSION: usize = 3;
+const EXTENSION_LEGACY_CONSOLE_PUTCHAR: usize = 0x01;
+const EXTENSION_DBCN: usize = 0x4442_434E;
+const FUNCTION_CONSOLE_WRITE: usize = 0;
+const FUNCTION_CONSOLE_READ: usize = 1;
+const FUNCTION_CONSOLE_WRITE_BYTE: usize = 2;
+
+// 一次调用最多写出和读入的字节数，剩下的由内核再次调用
+const WRITE_LIMIT: usize = 1024;
+const READ_LIMIT: usize = 256;
+// 调试器使用串口时最多缓存这么多内核的输出，之后的输出丢弃
+const BUFFER_LIMIT: usize = 4096;
+
+static BUFFERED: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+
+/// 处理调试控制台和旧版输出的SBI调用；其它调用返回None，交给RustSBI处理
+pub fn ecall(extension: usize, function: usize, param: [usize; 5]) -> Option<SbiRet> {
+    let ans = match (extension, function) {
+        // RustSBI不知道这个扩展，探测时由这里回答
+        (EXTENSION_BASE, FUNCTION_PROBE_EXTENSION) if param[0] == EXTENSION_DBCN => error::ok(1),
+        (EXTENSION_LEGACY_CONSOLE_PUTCHAR, _) | (EXTENSION_DBCN, FUNCTION_CONSOLE_WRITE_BYTE) => {
+            write(&[param[0] as u8]);
+            error::ok(0)
+        }
+        (EXTENSION_DBCN, FUNCTION_CONSOLE_WRITE) => console_write(param[0], param[1], param[2]),
+        (EXTENSION_DBCN, FUNCTION_CONSOLE_READ) => console_read(param[0], param[1], param[2]),
+        (EXTENSION_DBCN, _) => error::error(SBI_ERR_NOT_SUPPORTED),
+        _ => return None,
+    };
+    Some(ans)
+}
+
+// 写出缓冲区中的字节，返回写出的字节数；先读出所有字节，缓冲区不能访问时什么也不写
+fn console_write(num_bytes: usize, base_addr_lo: usize, base_addr_hi: usize) -> SbiRet {
+    let len = core::cmp::min(num_bytes, WRITE_LIMIT);
+    let base = match buffer(len, base_addr_lo, base_addr_hi) {
+        Some(base) => base,
+        None => return error::error(SBI_ERR_INVALID_PARAM),
+    };
+    let mut data = [0u8; WRITE_LIMIT];
+    for (offset, byte) in data[..len].iter_mut().enumerate() {
+        match probe::load_supervisor(base + offset, 8) {
+            Ok(value) => *byte = value as u8,
+            Err(_) => return error::error(SBI_ERR_INVALID_PARAM),
+        }
+    }
+    write(&data[..len]);
+    error::ok(len)
+}
+
+// 不等待地读入串口已经收到的字节，返回读入的字节数
+fn console_read(num_bytes: usize, base_addr_lo: usize, base_addr_hi: usize) -> SbiRet {
+    let len = core::cmp::min(num_bytes, READ_LIMIT);
+    let base = match buffer(len, base_addr_lo, base_addr_hi) {
+        Some(base) => base,
+        None => return error::error(SBI_ERR_INVALID_PARAM),
+    };
+    // 先确认缓冲区在内存中并且不在调试大师自己的内存中，再取出串口的输入；不写内核缓冲区原来的内容
+    if !writable(base, len) {
+        return error::error(SBI_ERR_INVALID_PARAM)
+    }
+    // 调试器使用串口时，输入属于调试器
+    if halt::debugger_active() {
+        return error::ok(0)
+    }
+    let mut count = 0;
+    while count < len {
+        match uart::try_read() {
+            Some(byte) => {
+                // 页表或PMP仍然可能不允许写，这时已经取出的字节会丢失
+                if probe::store_supervisor(base + count, 8, byte as usize).is_err() {
+                    if count == 0 {
+                        return error::error(SBI_ERR_INVALID_PARAM)
+                    }
+                    break
+                }
+                count += 1;
+            }
+            None => break,
+        }
+    }
+    error::ok(count)
+}
+
+// 缓冲区的起始物理地址；M态只能访问XLEN位的地址，高位不为0或者缓冲区越过地址空间的末尾时返回None
+fn buffer(len: usize, base_addr_lo: usize, base_addr_hi: usize) -> Option<usize> {
+    if base_addr_hi != 0 {
+        return None
+    }
+    base_addr_lo.checked_add(len).map(|_| base_addr_lo)
+}
+
+// 缓冲区整个在内存中，并且和调试大师自己的内存不重叠
+fn writable(base: usize, len: usize) -> bool {
+    let (memory_base, memory_size) = platform::get().memory;
+    let (firmware_base, firmware_size) = crate::firmware_region();
+    let end = base + len;
+    base >= memory_base && end - memory_base <= memory_size
+        && (end <= firmware_base || base >= firmware_base + firmware_size)
+}
+
+// 输出内核的字节；调试器使用串口时先放进缓冲区
+fn write(bytes: &[u8]) {
+    if halt::debugger_active() {
+        let mut buffered = BUFFERED.lock();
+        let room = BUFFER_LIMIT.saturating_sub(buffered.len());
+        buffered.extend_from_slice(&bytes[..core::cmp::min(room, bytes.len())]);
+        return
+    }
+    for &byte in bytes {
+        legacy_stdio_putchar(byte);
+    }
+}
+
+/// 打印调试器使用串口期间内核的输出，每行加上标记；在命令提示符之前和离开调试器后调用
+pub fn flush_buffered() {
+    let buffered = core::mem::take(&mut *BUFFERED.lock());
+    if buffered.is_empty() {
+        return
+    }
+    let text = String::from_utf8_lossy(&buffered);
+    for line in text.trim_end_matches('\n').split('\n') {
+        println!("[Kernel] {}", line.trim_end_matches('\r'));
+    }
+}
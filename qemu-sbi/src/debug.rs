@@ -1,30 +1,692 @@
-use crate::executor::KernelContext;
+use crate::executor::{KernelContext, MSTATUS_MPP, MSTATUS_SUM, MSTATUS_MXR, exception_name, trap_name};
+use crate::disasm;
+use crate::paging::{self, Access};
+use crate::probe;
+use crate::breakpoint;
+use crate::step;
+use crate::trigger;
+use crate::uart;
+use crate::gdbstub::{self, Command, BreakpointType};
+use crate::watchpoint::{self, WatchKind, WatchError};
+use crate::delegation;
+use crate::emulate;
+use crate::ipi::MAX_HARTS;
+use crate::hsm;
+use crate::halt;
+use crate::reset;
+use crate::console;
+use crate::tiaoshi;
+use crate::pmu;
+use crate::platform;
+use riscv::register::{mhartid, satp, stvec};
+use spin::Mutex;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use rustsbi::{print, println, legacy_stdio_putchar, legacy_stdio_getchar};
 use alloc::vec::Vec;
 use alloc::format;
 use alloc::string::String;
 
 const INPUT_LIMIT: usize = 256;
+// 数组一次最多打印的元素个数，防止输错长度时刷屏
+const ARRAY_LIMIT: usize = 256;
+// 打印数组时，每行显示的字节数
+const ARRAY_ROW_BYTES: usize = 16;
 
-pub fn on_breakpoint(ctx: &mut KernelContext) {
-    println!("[DebugSBI] Breakpoint at {:#x}", ctx.mepc);
-    loop {
-        match get_command() {
+pub fn on_breakpoint(ctx: &mut KernelContext, mtval: usize) {
+    halt::enter(ctx);
+    breakpoint_trap(ctx, mtval);
+    leave();
+}
+
+fn breakpoint_trap(ctx: &mut KernelContext, mtval: usize) {
+    let pc = ctx.mepc;
+    let hit = breakpoint::find_inserted(pc);
+    let temporary_hit = breakpoint::temporary_inserted_at(pc);
+    breakpoint::remove_all();
+    breakpoint::clear_temporary();
+    let stepping = STEPPING.lock().take();
+    if let Some(StepState { trigger: Some(index), .. }) = stepping {
+        trigger::free(index);
+    }
+    // 触发器或临时断点引起的陷入说明单步执行完成了
+    let step_done = match stepping {
+        Some(state) => state.trigger.is_some() || temporary_hit,
+        None => false,
+    };
+    let reason = if let (Some(StepState { kind: Stepping::Watch(watch), .. }), true) = (stepping, step_done) {
+        // 触发观察点的指令已经执行完，可以报告新的值了
+        watchpoint::arm_all();
+        Stop::Watch(watch)
+    } else if let Some(watch) = watch_hit(ctx, mtval, hit, temporary_hit, stepping) {
+        // 关闭观察点，先执行触发观察点的指令
+        watchpoint::disarm_all();
+        match arm_stepping(ctx, Stepping::Watch(watch)) {
+            Ok(()) => {
+                breakpoint::insert_all(Some(pc));
+                return
+            }
+            Err(error) => {
+                report_access_error(&AddressSpace::new(ctx, &None), &error);
+                watchpoint::arm_all();
+                Stop::Watch(watch)
+            }
+        }
+    } else {
+        if let (Some(state), true, None) = (stepping, step_done, hit) {
+            let next = match state.kind {
+                Stepping::Step { remaining } if remaining > 1 => Some(ControlFlow::Step(remaining - 1)),
+                Stepping::StepOverBreakpoint { return_to: None } => Some(ControlFlow::Break),
+                _ => None,
+            };
+            if let Some(flow) = next {
+                resume(ctx, flow);
+                return
+            }
+            // 越过了断点，现在可以写入所有断点，运行到返回地址
+            if let Stepping::StepOverBreakpoint { return_to: Some(address) } = state.kind {
+                run_to(ctx, address);
+                breakpoint::insert_all(None);
+                return
+            }
+        }
+        match hit {
+            Some(number) => Stop::Breakpoint(Some(number)),
+            None if step_done => Stop::Step,
+            // 等待调试器时，其它硬件线程在调试器中删除了这个断点，重新执行原来的指令
+            None if !is_ebreak_at(ctx, pc) => {
+                resume(ctx, ControlFlow::Break);
+                return
+            }
+            None => Stop::Breakpoint(None),
+        }
+    };
+    let flow = stop(ctx, &reason);
+    // 被调试程序自己的ebreak指令需要跳过；调试器设置的断点已经恢复成原来的指令，应当执行它
+    if reason == Stop::Breakpoint(None) && ctx.mepc == pc {
+        ctx.mepc = pc.wrapping_add(instruction_length_at(ctx, pc));
+    }
+    resume(ctx, flow);
+}
+
+/// 串口收到Ctrl+C时进入调试器，被调试程序停在当前的pc
+pub fn on_interrupt(ctx: &mut KernelContext) {
+    halt::enter(ctx);
+    cancel_stepping();
+    let flow = stop(ctx, &Stop::Interrupt);
+    resume(ctx, flow);
+    leave();
+}
+
+/// 被调试程序发生了调试大师不处理的异常或中断，停在调试器中做事后检查
+pub fn on_fault(ctx: &mut KernelContext, mcause: usize, mtval: usize) {
+    halt::enter(ctx);
+    cancel_stepping();
+    let flow = stop(ctx, &Stop::Fault { mcause, mtval });
+    resume(ctx, flow);
+    leave();
+}
+
+/// 内核的异常将要转交给内核的陷入处理函数，先停在调试器中；返回是否仍然转交
+///
+/// 在调试器中修改了pc时，认为异常已经处理过了，不再转交
+pub fn on_deliver(ctx: &mut KernelContext, mcause: usize, mtval: usize, vector: usize) -> bool {
+    halt::enter(ctx);
+    cancel_stepping();
+    let pc = ctx.mepc;
+    let flow = stop(ctx, &Stop::Deliver { mcause, mtval, vector });
+    resume(ctx, flow);
+    leave();
+    if ctx.mepc != pc {
+        println!("[DebugSBI] pc changed, {} is not delivered", trap_name(mcause));
+        return false
+    }
+    true
+}
+
+/// 内核运行时GDB连接到串口，停止内核，由GDB接管调试器
+pub fn on_gdb_attach(ctx: &mut KernelContext) {
+    halt::enter(ctx);
+    cancel_stepping();
+    let flow = serve_gdb(ctx, Some(&uart::GDB_ATTACH[1..]));
+    resume(ctx, flow);
+    leave();
+}
+
+/// 内核请求复位系统，先停在调试器中检查；继续运行后才复位
+pub fn on_reset(ctx: &mut KernelContext, reset_type: usize, reset_reason: usize) {
+    halt::enter(ctx);
+    cancel_stepping();
+    let flow = stop(ctx, &Stop::Reset { reset_type, reset_reason });
+    resume(ctx, flow);
+    leave();
+}
+
+/// 内核通过调试大师扩展请求停下，打印内核给出的消息
+pub fn on_kernel_stop(ctx: &mut KernelContext, message: usize, len: usize) {
+    halt::enter(ctx);
+    cancel_stepping();
+    let flow = stop(ctx, &Stop::Kernel { message, len });
+    resume(ctx, flow);
+    leave();
+}
+
+/// 内核通过调试大师扩展在自己当前的地址空间中设置断点，返回断点编号；不能设置时返回None
+pub fn add_breakpoint(ctx: &KernelContext, address: usize) -> Option<usize> {
+    let space = AddressSpace::new(ctx, &None);
+    let (paddr, original, len) = breakpoint_site(&space, address).ok()?;
+    if len != 2 && len != 4 {
+        return None
+    }
+    let number = breakpoint::add(address, paddr, original, len).unwrap_or_else(|number| number);
+    // 内核正在运行，断点马上写入；其它硬件线程在调试器中时，离开调试器时再写入
+    if !halt::debugger_active() {
+        breakpoint::insert_all(None);
+    }
+    Some(number)
+}
+
+// 返回内核前离开调试器。单步执行时其它硬件线程继续停着，避免它们运行到调试器设置的临时断点，
+// 只有让被调试程序继续运行时才一起返回内核
+fn leave() {
+    halt::leave(STEPPING.lock().is_none());
+    console::flush_buffered();
+}
+
+// 放弃被打断的单步执行
+fn cancel_stepping() {
+    breakpoint::remove_all();
+    breakpoint::clear_temporary();
+    if let Some(StepState { trigger: Some(index), .. }) = STEPPING.lock().take() {
+        trigger::free(index);
+    }
+    watchpoint::arm_all();
+}
+
+// 被调试程序停下的原因
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Stop {
+    // 调试器设置的断点（带编号），或被调试程序自己的ebreak
+    Breakpoint(Option<usize>),
+    // 单步执行完成
+    Step,
+    Watch(WatchHit),
+    // Ctrl+C
+    Interrupt,
+    // 调试大师不处理的异常或中断
+    Fault { mcause: usize, mtval: usize },
+    // 将要转交给内核陷入处理函数的异常
+    Deliver { mcause: usize, mtval: usize, vector: usize },
+    // 内核请求复位系统
+    Reset { reset_type: usize, reset_reason: usize },
+    // 内核通过调试大师扩展请求停下，消息在内核的地址空间中
+    Kernel { message: usize, len: usize },
+}
+
+// 报告停下的原因，然后读取命令；连接了GDB时交给GDB处理
+fn stop(ctx: &mut KernelContext, reason: &Stop) -> ControlFlow {
+    if GDB_ATTACHED.load(Ordering::Relaxed) {
+        let signal = match reason {
+            Stop::Interrupt => gdbstub::SIGINT,
+            Stop::Fault { mcause, .. } | Stop::Deliver { mcause, .. } => gdbstub::fault_signal(*mcause),
+            _ => gdbstub::SIGTRAP,
+        };
+        LAST_SIGNAL.store(signal as usize, Ordering::Relaxed);
+        let watch = match reason {
+            Stop::Watch(watch) => Some((gdb_watch_name(watch.kind), watch.address)),
+            _ => None,
+        };
+        gdbstub::send(gdbstub::stop_reply(signal, watch).as_bytes());
+        return serve_gdb(ctx, None)
+    }
+    let pc = ctx.mepc;
+    // 有多个硬件线程时，说明是哪个硬件线程进入了调试器
+    if crate::ONLINE_HARTS.load(Ordering::Relaxed).count_ones() > 1 && *reason != Stop::Step {
+        println!("[DebugSBI] [Hart {}]", mhartid::read());
+    }
+    match reason {
+        Stop::Breakpoint(Some(number)) => println!("[DebugSBI] Breakpoint {} at {:#x}{}", number, pc, symbolize(pc)),
+        Stop::Breakpoint(None) => println!("[DebugSBI] Breakpoint at {:#x}{}", pc, symbolize(pc)),
+        Stop::Step => {}
+        Stop::Watch(watch) => report_watch(ctx, watch),
+        Stop::Interrupt => println!("[DebugSBI] Interrupted at {:#x}", pc),
+        Stop::Fault { mcause, mtval } => {
+            println!("[DebugSBI] Unhandled trap: {} (mcause = {:#x}), mtval = {:#x}, at {:#x}",
+                trap_name(*mcause), mcause, mtval, pc);
+            print_registers(ctx);
+        }
+        Stop::Deliver { mcause, mtval, vector } =>
+            println!("[DebugSBI] About to deliver {} (mtval = {:#x}) at {:#x} to kernel handler at {:#x}",
+                trap_name(*mcause), mtval, pc, vector),
+        Stop::Kernel { message, len } =>
+            println!("[DebugSBI] Kernel stopped at {:#x}{}: {}", pc, symbolize(pc), read_message(ctx, *message, *len)),
+        Stop::Reset { reset_type, reset_reason } =>
+            println!("[DebugSBI] Kernel requested {} ({}) at {:#x}, continue to reset",
+                reset_type_name(*reset_type), reset_reason_name(*reset_reason), pc),
+    }
+    if !matches!(reason, Stop::Breakpoint(_)) {
+        let space = AddressSpace::new(ctx, &None);
+        if let Err(error) = print_instructions(&space, pc, 1) {
+            report_access_error(&space, &error);
+        }
+    }
+    prompt(ctx)
+}
+
+// 读取并执行命令，直到命令要求被调试程序继续运行；命令作用在thread选中的硬件线程上
+fn prompt(ctx: &mut KernelContext) -> ControlFlow {
+    let hart_id = mhartid::read();
+    SELECTED.store(hart_id, Ordering::Relaxed);
+    let flow = loop {
+        let selected = SELECTED.load(Ordering::Relaxed);
+        let target = match halt::context(selected) {
+            Some(other) if selected != hart_id => unsafe { &mut *other },
+            _ => &mut *ctx,
+        };
+        match get_command(target) {
             Ok(ControlFlow::Continue) => continue,
-            Ok(ControlFlow::Break) => break,
+            Ok(flow) => break flow,
+            // 命令提示符下读到了GDB的包
+            Err(ParseError::GdbPacket) => break serve_gdb(ctx, Some(&[])),
             Err(e) => println!("Error: {:?}", e),
         }
+    };
+    SELECTED.store(hart_id, Ordering::Relaxed);
+    flow
+}
+
+// 调试器命令作用的硬件线程，用thread命令切换
+static SELECTED: AtomicUsize = AtomicUsize::new(0);
+
+// 连接着GDB时，被调试程序停下后向GDB报告，不再打印到串口
+static GDB_ATTACHED: AtomicBool = AtomicBool::new(false);
+// 最近一次停下的信号，回复'?'包
+static LAST_SIGNAL: AtomicUsize = AtomicUsize::new(gdbstub::SIGTRAP as usize);
+
+// 处理GDB的包，直到GDB要求继续运行或者断开连接
+fn serve_gdb(ctx: &mut KernelContext, started: Option<&[u8]>) -> ControlFlow {
+    GDB_ATTACHED.store(true, Ordering::Relaxed);
+    let mut started = started;
+    loop {
+        let packet = gdbstub::receive(started.take());
+        let reply = match gdbstub::parse(&packet) {
+            Command::Continue(address) => {
+                if let Some(address) = address {
+                    ctx.mepc = address;
+                }
+                return ControlFlow::Break
+            }
+            Command::Step(address) => {
+                if let Some(address) = address {
+                    ctx.mepc = address;
+                }
+                return ControlFlow::Step(1)
+            }
+            Command::Detach => {
+                gdbstub::send(b"OK");
+                GDB_ATTACHED.store(false, Ordering::Relaxed);
+                return ControlFlow::Break
+            }
+            // 不能真的结束内核，断开连接后让内核继续运行
+            Command::Kill => {
+                GDB_ATTACHED.store(false, Ordering::Relaxed);
+                return ControlFlow::Break
+            }
+            command => gdb_reply(ctx, command),
+        };
+        gdbstub::send(&reply);
+    }
+}
+
+// 执行不需要被调试程序继续运行的包，返回回复的内容
+fn gdb_reply(ctx: &mut KernelContext, command: Command) -> Vec<u8> {
+    let reply = match command {
+        Command::Supported => gdbstub::supported(),
+        Command::StopReason => format!("S{:02x}", LAST_SIGNAL.load(Ordering::Relaxed)),
+        Command::Features(offset, len) => return gdbstub::features(offset, len),
+        Command::ReadRegisters => {
+            let mut ans = String::new();
+            for index in 0..gdbstub::REGISTER_COUNT {
+                ans.push_str(&gdbstub::encode_register(gdb_register(ctx, index)));
+            }
+            ans
+        }
+        Command::WriteRegisters(bytes) => {
+            let size = core::mem::size_of::<usize>();
+            for (index, chunk) in bytes.chunks(size).enumerate().take(gdbstub::REGISTER_COUNT) {
+                if chunk.len() == size {
+                    let value = chunk.iter().rev().fold(0, |value, &byte| (value << 8) | byte as usize);
+                    set_gdb_register(ctx, index, value);
+                }
+            }
+            String::from("OK")
+        }
+        Command::ReadRegister(index) if index < gdbstub::REGISTER_COUNT =>
+            gdbstub::encode_register(gdb_register(ctx, index)),
+        Command::WriteRegister(index, value) if index < gdbstub::REGISTER_COUNT => {
+            set_gdb_register(ctx, index, value);
+            String::from("OK")
+        }
+        Command::ReadRegister(_) | Command::WriteRegister(_, _) => String::from("E01"),
+        Command::ReadMemory(address, len) => {
+            let space = AddressSpace::new(ctx, &None);
+            let mut bytes = Vec::new();
+            for offset in 0..len {
+                match space.read(address.wrapping_add(offset), 8, Access::Read) {
+                    Ok((_, value)) => bytes.push(value as u8),
+                    Err(_) => break,
+                }
+            }
+            // 一个字节也读不到时回复错误，否则回复读到的部分
+            if bytes.is_empty() && len != 0 {
+                String::from("E14")
+            } else {
+                gdbstub::encode_hex(&bytes)
+            }
+        }
+        Command::WriteMemory(address, bytes) => {
+            let space = AddressSpace::new(ctx, &None);
+            let ok = bytes.iter().enumerate().all(|(offset, &byte)| {
+                space.write(address.wrapping_add(offset), 8, byte as u128).is_ok()
+            });
+            String::from(if ok { "OK" } else { "E14" })
+        }
+        Command::InsertBreakpoint(ty, address, kind) => String::from(gdb_insert_breakpoint(ctx, ty, address, kind)),
+        Command::RemoveBreakpoint(ty, address, kind) => String::from(gdb_remove_breakpoint(ty, address, kind)),
+        Command::Reply(reply) => String::from(reply),
+        _ => String::new(),
+    };
+    reply.into_bytes()
+}
+
+// GDB的寄存器编号：0到31为通用寄存器，32为pc
+fn gdb_register(ctx: &KernelContext, index: usize) -> usize {
+    if index == gdbstub::REGISTER_PC {
+        ctx.mepc
+    } else {
+        ctx.x(index)
+    }
+}
+
+fn set_gdb_register(ctx: &mut KernelContext, index: usize, value: usize) {
+    if index == gdbstub::REGISTER_PC {
+        ctx.mepc = value;
+    } else {
+        ctx.set_x(index, value);
+    }
+}
+
+fn gdb_insert_breakpoint(ctx: &KernelContext, ty: BreakpointType, address: usize, kind: usize) -> &'static str {
+    match ty {
+        BreakpointType::Software => {
+            let space = AddressSpace::new(ctx, &None);
+            match breakpoint_site(&space, address) {
+                // 这个地址已经有断点时，breakpoint::add返回Err，同样算作成功
+                Ok((paddr, original, len)) if len == 2 || len == 4 => {
+                    let _ = breakpoint::add(address, paddr, original, len);
+                    "OK"
+                }
+                _ => "E01",
+            }
+        }
+        BreakpointType::Hardware => "",
+        _ => {
+            if !kind.is_power_of_two() || address % kind != 0 {
+                return "E01"
+            }
+            match watchpoint::add(address, kind, gdb_watch_kind(ty)) {
+                Ok(_) => "OK",
+                Err(_) => "E01",
+            }
+        }
+    }
+}
+
+fn gdb_remove_breakpoint(ty: BreakpointType, address: usize, kind: usize) -> &'static str {
+    let number = match ty {
+        BreakpointType::Software => breakpoint::number_at(address),
+        BreakpointType::Hardware => return "",
+        _ => watchpoint::number_of(address, kind, gdb_watch_kind(ty)),
+    };
+    match number {
+        Some(number) if breakpoint::delete(number) || watchpoint::delete(number) => "OK",
+        _ => "E01",
+    }
+}
+
+fn gdb_watch_kind(ty: BreakpointType) -> WatchKind {
+    match ty {
+        BreakpointType::Read => WatchKind::Read,
+        BreakpointType::Access => WatchKind::Access,
+        _ => WatchKind::Write,
+    }
+}
+
+fn gdb_watch_name(kind: WatchKind) -> &'static str {
+    match kind {
+        WatchKind::Write => "watch",
+        WatchKind::Read => "rwatch",
+        WatchKind::Access => "awatch",
+    }
+}
+
+// 一次观察点触发
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+struct WatchHit {
+    number: usize,
+    kind: WatchKind,
+    // 访存指令的地址
+    pc: usize,
+    // 实际访问的地址和字节数
+    address: usize,
+    width: usize,
+    store: bool,
+    // 指令执行前的值，读取失败时为None
+    old: Option<u128>,
+}
+
+// 判断这次陷入是否由观察点引起
+fn watch_hit(ctx: &KernelContext, mtval: usize, hit: Option<usize>, temporary_hit: bool, stepping: Option<StepState>) -> Option<WatchHit> {
+    let space = AddressSpace::new(ctx, &None);
+    let access = read_instruction(&space, ctx.mepc).ok().and_then(|ins| step::memory_access(ins, ctx));
+    let wp = match watchpoint::find_hit() {
+        Some(wp) => wp,
+        None => {
+            // 硬件不一定实现hit位，这时根据访存指令的地址判断。
+            // 停在断点上，或者单步已经执行了一条指令时，当前的指令还没有执行，不会是它触发的
+            if hit.is_some() || temporary_hit {
+                return None
+            }
+            if let Some(state) = stepping {
+                if state.trigger.is_some() && state.pc != ctx.mepc {
+                    return None
+                }
+            }
+            let access = access?;
+            watchpoint::find_covering(access.address, access.width, access.store)?
+        }
+    };
+    let (address, width, store) = match access {
+        Some(access) if wp.covers(access.address, access.width, access.store) => (access.address, access.width, access.store),
+        // 无法解码时，mtval可能是访问的地址
+        _ if mtval != ctx.mepc && wp.contains(mtval) => (mtval, 1, wp.kind == WatchKind::Write),
+        _ => (wp.address, core::cmp::min(wp.len, 8), wp.kind == WatchKind::Write),
+    };
+    let old = space.read(address, (width * 8) as u8, Access::Read).ok().map(|(_, value)| value);
+    Some(WatchHit { number: wp.number, kind: wp.kind, pc: ctx.mepc, address, width, store, old })
+}
+
+fn report_watch(ctx: &KernelContext, watch: &WatchHit) {
+    let space = AddressSpace::new(ctx, &None);
+    let new = space.read(watch.address, (watch.width * 8) as u8, Access::Read).ok().map(|(_, value)| value);
+    let verb = if watch.store { "written" } else { "read" };
+    println!("[DebugSBI] {} {}: {:#x} {} by pc {:#x}", watch_name(watch.kind), watch.number, watch.address, verb, watch.pc);
+    if watch.store {
+        println!("[DebugSBI] Old value = {}", format_watch_value(watch.old));
+        println!("[DebugSBI] New value = {}", format_watch_value(new));
+    } else {
+        println!("[DebugSBI] Value = {}", format_watch_value(watch.old));
+    }
+}
+
+fn format_watch_value(value: Option<u128>) -> String {
+    match value {
+        Some(value) => format!("{:#x}", value),
+        None => String::from("<unreadable>"),
+    }
+}
+
+fn watch_name(kind: WatchKind) -> &'static str {
+    match kind {
+        WatchKind::Write => "Hardware watchpoint",
+        WatchKind::Read => "Hardware read watchpoint",
+        WatchKind::Access => "Hardware access (read/write) watchpoint",
+    }
+}
+
+// 读取指令的长度，读取失败时按4字节处理
+fn instruction_length_at(ctx: &KernelContext, address: usize) -> usize {
+    let space = AddressSpace::new(ctx, &None);
+    match read_instruction_half(&space, address) {
+        Ok(low) => disasm::instruction_length(low),
+        Err(_) => 4,
+    }
+}
+
+// pc处是不是ebreak；读不到指令时也当作是，停下报告
+fn is_ebreak_at(ctx: &KernelContext, pc: usize) -> bool {
+    match read_instruction(&AddressSpace::new(ctx, &None), pc) {
+        Ok(ins) => breakpoint::is_ebreak(ins),
+        Err(_) => true,
+    }
+}
+
+// 单步执行的种类
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Stepping {
+    // si：还要执行的指令条数
+    Step { remaining: usize },
+    // ni、finish：在返回地址的临时断点处停下
+    Return,
+    // 从启用的断点处继续运行时，先执行完断点处原来的指令，再写入这个断点；
+    // return_to不为None时，之后运行到这个返回地址
+    StepOverBreakpoint { return_to: Option<usize> },
+    // 执行触发观察点的指令，之后报告新的值
+    Watch(WatchHit),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct StepState {
+    kind: Stepping,
+    // 开始单步时的pc
+    pc: usize,
+    // 单步使用的icount触发器；为None时使用临时断点
+    trigger: Option<usize>,
+}
+
+// 继续运行前设置，下一次进入调试器时取出
+static STEPPING: Mutex<Option<StepState>> = Mutex::new(None);
+
+// 按照命令的要求让被调试程序继续运行
+fn resume(ctx: &mut KernelContext, flow: ControlFlow) {
+    // 在调试器中输入的Ctrl+C不再打断被调试程序
+    uart::take_ctrl_c();
+    let pc = ctx.mepc;
+    let kind = match flow {
+        ControlFlow::Step(count) => Stepping::Step { remaining: count },
+        ControlFlow::Next => match read_instruction(&AddressSpace::new(ctx, &None), pc) {
+            // 函数调用返回到下一条指令
+            Ok(ins) if step::is_call(ins) => return_to(ctx, pc.wrapping_add(disasm::instruction_length(ins as u16))),
+            _ => Stepping::Step { remaining: 1 },
+        },
+        // 只在函数还没有调用其它函数，或者已经恢复了ra时有效；不检查栈帧，函数调用过其它函数后ra指向函数内部
+        ControlFlow::Finish => return_to(ctx, ctx.ra),
+        _ if breakpoint::enabled_at(pc) => Stepping::StepOverBreakpoint { return_to: None },
+        _ => {
+            breakpoint::insert_all(None);
+            return
+        }
+    };
+    if let Err(error) = arm_stepping(ctx, kind) {
+        report_access_error(&AddressSpace::new(ctx, &None), &error);
+        println!("[DebugSBI] Cannot single step at {:#x}, continuing", pc);
+    }
+    breakpoint::insert_all(Some(pc));
+}
+
+// 设置单步执行的状态，下一次进入调试器时检查
+fn arm_stepping(ctx: &KernelContext, kind: Stepping) -> Result<(), AccessError> {
+    let trigger = match kind {
+        Stepping::Return => None,
+        _ => single_step(ctx).map_err(|error| {
+            breakpoint::clear_temporary();
+            error
+        })?,
+    };
+    *STEPPING.lock() = Some(StepState { kind, pc: ctx.mepc, trigger });
+    Ok(())
+}
+
+// 让被调试程序执行一条指令后回到调试器，优先使用icount触发器
+fn single_step(ctx: &KernelContext) -> Result<Option<usize>, AccessError> {
+    if let Some(index) = trigger::arm_icount() {
+        return Ok(Some(index))
+    }
+    let space = AddressSpace::new(ctx, &None);
+    let ins = read_instruction(&space, ctx.mepc)?;
+    for address in step::successors(ins, ctx.mepc, ctx) {
+        plant_temporary(&space, address)?;
+    }
+    // 指令可能发生异常，陷入S态的异常处理函数；处理函数不一定能在当前地址空间中访问，失败时忽略
+    let supervisor = AddressSpace::new(ctx, &Some(PrivilegeMode::Supervisor));
+    let _ = plant_temporary(&supervisor, stvec::read().address());
+    Ok(None)
+}
+
+fn plant_temporary(space: &AddressSpace, address: usize) -> Result<(), AccessError> {
+    let (paddr, original, len) = breakpoint_site(space, address)?;
+    breakpoint::add_temporary(address, paddr, original, len);
+    Ok(())
+}
+
+// 运行到返回地址。pc处有启用的断点时，先越过这个断点，否则递归调用回到pc时不会在这个断点停下
+fn return_to(ctx: &KernelContext, address: usize) -> Stepping {
+    if breakpoint::enabled_at(ctx.mepc) {
+        Stepping::StepOverBreakpoint { return_to: Some(address) }
+    } else {
+        plant_return_breakpoint(ctx, address);
+        Stepping::Return
+    }
+}
+
+// 在返回地址设置临时断点，继续运行直到到达它
+fn run_to(ctx: &KernelContext, address: usize) {
+    plant_return_breakpoint(ctx, address);
+    let _ = arm_stepping(ctx, Stepping::Return);
+}
+
+fn plant_return_breakpoint(ctx: &KernelContext, address: usize) {
+    let space = AddressSpace::new(ctx, &None);
+    if let Err(error) = plant_temporary(&space, address) {
+        report_access_error(&space, &error);
     }
 }
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 enum ControlFlow {
+    // 继续运行
     Break,
-    Continue
+    // 继续读取命令
+    Continue,
+    // 执行若干条指令
+    Step(usize),
+    // 执行一条指令，跳过函数调用
+    Next,
+    // 运行到当前函数返回
+    Finish,
 }
 
-fn get_command() -> Result<ControlFlow, ParseError> {
-    let buf = fill_input_buffer();
+fn get_command(ctx: &mut KernelContext) -> Result<ControlFlow, ParseError> {
+    let buf = fill_input_buffer()?;
     let string = match String::from_utf8(buf) {
         Ok(s) => s,
         Err(e) => return Err(ParseError::InvalidUtf8(e))
@@ -34,58 +696,774 @@ fn get_command() -> Result<ControlFlow, ParseError> {
     // println!("{:?}", lexer(&string).collect::<Vec<_>>());
     let mut sym = iter.next();
     command(&mut iter, &mut sym, &mut metadata).map_err(|_| ParseError::SyntaxError)?;
-    Ok(execute_command(&metadata))
+    Ok(execute_command(ctx, &metadata))
 }
 
-fn execute_command(metadata: &Metadata) -> ControlFlow {
+fn execute_command(ctx: &mut KernelContext, metadata: &Metadata) -> ControlFlow {
     // println!("Metadata: {:?}", metadata);
     if let Some(CommandType::X) = metadata.command_type {
-        let address = if let Some(address) = metadata.address_number {
-            address
+        let address = match &metadata.address {
+            Some(operand) => match resolve(ctx, operand) {
+                Some(address) => address,
+                None => return ControlFlow::Continue,
+            },
+            None => {
+                println!("[DebugSBI] Address not provided for command x");
+                return ControlFlow::Continue;
+            }
+        };
+        let space = AddressSpace::new(ctx, &metadata.privileged_mode);
+        let ty = metadata.data_type.clone().unwrap_or(
+            DataType::Basic(BasicType { signed: true, width: (core::mem::size_of::<usize>() * 8) as u8 })
+        );
+        let result = match ty {
+            DataType::Basic(basic_type) => read_basic(&space, address, &basic_type).map(|(paddr, raw)| {
+                let value = format_basic(raw, &basic_type, &metadata.print_mode);
+                println!("[DebugSBI] {} = {}", space.describe(address, paddr), value);
+            }),
+            DataType::Array(basic_type, len) => print_array(&space, address, &basic_type, len, &metadata.print_mode),
+            DataType::Instruction(count) => print_instructions(&space, address, count),
+        };
+        if let Err(error) = result {
+            report_access_error(&space, &error);
+        }
+    } else if let Some(CommandType::C) = metadata.command_type {
+        println!("[DebugSBI] Continuing.");
+        return ControlFlow::Break
+    } else if let Some(CommandType::Step) = metadata.command_type {
+        let count = metadata.number.unwrap_or(1);
+        if count > 0 && can_step() {
+            return ControlFlow::Step(count)
+        }
+    } else if let Some(CommandType::Next) = metadata.command_type {
+        if can_step() {
+            return ControlFlow::Next
+        }
+    } else if let Some(CommandType::Finish) = metadata.command_type {
+        if can_step() {
+            println!("[DebugSBI] Run till exit, return address {:#x}", ctx.ra);
+            return ControlFlow::Finish
+        }
+    } else if let Some(CommandType::Thread) = metadata.command_type {
+        if let Some(hart_id) = metadata.number {
+            select_hart(hart_id);
+        }
+    } else if let Some(CommandType::InfoThreads) = metadata.command_type {
+        print_threads();
+    } else if let Some(CommandType::InfoTasks) = metadata.command_type {
+        print_tasks(ctx);
+    } else if let Some(CommandType::InfoSymbol) = metadata.command_type {
+        if let Some(address) = metadata.address.as_ref().and_then(|operand| resolve(ctx, operand)) {
+            print_symbol(address);
+        }
+    } else if let Some(CommandType::CatchReset(stop)) = metadata.command_type {
+        reset::set_stop_in_debugger(stop);
+        if stop {
+            println!("[DebugSBI] Kernel reset requests will stop in the debugger");
         } else {
-            println!("[DebugSBI] Address not provided for command x");
-            return ControlFlow::Continue;
+            println!("[DebugSBI] Kernel reset requests will reset the system");
+        }
+    } else if let Some(CommandType::Reboot) = metadata.command_type {
+        if !reset::supported(rustsbi::reset::RESET_TYPE_COLD_REBOOT) {
+            println!("[DebugSBI] No reset device");
+            return ControlFlow::Continue
+        }
+        println!("[DebugSBI] Rebooting.");
+        reset::reset(rustsbi::reset::RESET_TYPE_COLD_REBOOT, rustsbi::reset::RESET_REASON_NO_REASON);
+    } else if let Some(CommandType::Shutdown) = metadata.command_type {
+        if !reset::supported(rustsbi::reset::RESET_TYPE_SHUTDOWN) {
+            println!("[DebugSBI] No reset device");
+            return ControlFlow::Continue
+        }
+        println!("[DebugSBI] Shutting down.");
+        reset::reset(rustsbi::reset::RESET_TYPE_SHUTDOWN, rustsbi::reset::RESET_REASON_NO_REASON);
+    } else if let Some(CommandType::InfoRegisters) = metadata.command_type {
+        print_registers(ctx);
+    } else if let Some(CommandType::Break) = metadata.command_type {
+        let address = match metadata.address.as_ref().and_then(|operand| resolve(ctx, operand)) {
+            Some(address) => address,
+            None => return ControlFlow::Continue,
+        };
+        set_breakpoint(ctx, address);
+    } else if let Some(CommandType::Watch(kind)) = metadata.command_type {
+        let address = match metadata.address.as_ref().and_then(|operand| resolve(ctx, operand)) {
+            Some(address) => address,
+            None => return ControlFlow::Continue,
+        };
+        set_watchpoint(address, metadata.length.unwrap_or(core::mem::size_of::<usize>()), kind);
+    } else if let Some(CommandType::InfoBreakpoints) = metadata.command_type {
+        print_breakpoints();
+    } else if let Some(CommandType::InfoWatchpoints) = metadata.command_type {
+        print_watchpoints();
+    } else if let Some(CommandType::InfoHarts) = metadata.command_type {
+        print_harts();
+    } else if let Some(CommandType::InfoTimer) = metadata.command_type {
+        print_timer();
+    } else if let Some(CommandType::Perf) = metadata.command_type {
+        print_perf(ctx);
+    } else if let Some(CommandType::InfoMisaligned) = metadata.command_type {
+        print_misaligned();
+    } else if let Some(CommandType::InfoDelegation) = metadata.command_type {
+        print_delegation();
+    } else if let Some(CommandType::Delegate(delegated)) = metadata.command_type {
+        if let Some(name) = &metadata.cause {
+            set_delegation(name, delegated);
+        }
+    } else if let Some(CommandType::Delete) = metadata.command_type {
+        check_breakpoint_number(metadata.number, |number| breakpoint::delete(number) || watchpoint::delete(number));
+    } else if let Some(CommandType::Enable) = metadata.command_type {
+        check_breakpoint_number(metadata.number, |number| {
+            breakpoint::set_enabled(number, true) || watchpoint::set_enabled(number, true)
+        });
+    } else if let Some(CommandType::Disable) = metadata.command_type {
+        check_breakpoint_number(metadata.number, |number| {
+            breakpoint::set_enabled(number, false) || watchpoint::set_enabled(number, false)
+        });
+    } else if let Some(CommandType::Set) = metadata.command_type {
+        let (name, operand) = match (&metadata.register, &metadata.value) {
+            (Some(name), Some(operand)) => (name, operand),
+            _ => return ControlFlow::Continue,
+        };
+        let register = match parse_register(name) {
+            Some(Register::X(0)) => {
+                println!("[DebugSBI] Register $zero is read only");
+                return ControlFlow::Continue;
+            }
+            Some(register) => register,
+            None => {
+                println!("[DebugSBI] Unknown register ${}", name);
+                return ControlFlow::Continue;
+            }
+        };
+        if let Some(value) = resolve(ctx, operand) {
+            write_register(ctx, register, value);
+            println!("[DebugSBI] ${} = {:#x}", name, value);
+        }
+    }
+    ControlFlow::Continue
+}
+
+fn set_breakpoint(ctx: &KernelContext, address: usize) {
+    // 断点设置在被调试程序当前的地址空间中
+    let space = AddressSpace::new(ctx, &None);
+    match breakpoint_site(&space, address) {
+        Ok((paddr, original, len)) if len == 2 || len == 4 => match breakpoint::add(address, paddr, original, len) {
+            Ok(number) => println!("[DebugSBI] Breakpoint {} at {:#x}", number, address),
+            Err(number) => println!("[DebugSBI] Breakpoint {} already at {:#x}", number, address),
+        },
+        Ok((_, _, len)) => println!("[DebugSBI] Cannot set breakpoint on {}-byte instruction", len),
+        Err(error) => report_access_error(&space, &error),
+    }
+}
+
+// 断点处指令的物理地址、原来的指令和长度
+fn breakpoint_site(space: &AddressSpace, address: usize) -> Result<(usize, u32, usize), AccessError> {
+    let original = read_instruction(space, address)?;
+    let paddr = space.translate(address, Access::Execute)?;
+    Ok((paddr, original, disasm::instruction_length(original as u16)))
+}
+
+fn set_watchpoint(address: usize, len: usize, kind: WatchKind) {
+    // 触发器按NAPOT方式匹配地址范围
+    if !len.is_power_of_two() || address % len != 0 {
+        println!("[DebugSBI] Watched length must be a power of two and the address aligned to it");
+        return;
+    }
+    match watchpoint::add(address, len, kind) {
+        Ok(number) => println!("[DebugSBI] {} {}: {:#x}, {} bytes", watch_name(kind), number, address, len),
+        Err(WatchError::NoTriggers) =>
+            println!("[DebugSBI] This hart has no hardware triggers, watchpoints are not available"),
+        Err(WatchError::Busy(count)) =>
+            println!("[DebugSBI] No free hardware trigger supports address matching ({} triggers in total)", count),
+        Err(WatchError::Unsupported) =>
+            println!("[DebugSBI] Hardware trigger cannot watch {} bytes at {:#x}", len, address),
+    }
+}
+
+fn check_breakpoint_number<F: FnOnce(usize) -> bool>(number: Option<usize>, f: F) {
+    if let Some(number) = number {
+        if !f(number) {
+            println!("[DebugSBI] No breakpoint number {}", number);
+        }
+    }
+}
+
+fn print_breakpoints() {
+    let list = breakpoint::list();
+    if list.is_empty() {
+        println!("[DebugSBI] No breakpoints.");
+        return;
+    }
+    println!("[DebugSBI] Num  Enb  Address             Instruction");
+    for bp in list {
+        let enabled = if bp.enabled { 'y' } else { 'n' };
+        println!("[DebugSBI] {:<4} {:<4} {:#018x}  {}", bp.number, enabled, bp.address,
+            disasm::disassemble(bp.original, bp.address));
+    }
+}
+
+fn print_watchpoints() {
+    println!("[DebugSBI] {} hardware triggers on this hart", trigger::count());
+    let list = watchpoint::list();
+    if list.is_empty() {
+        println!("[DebugSBI] No watchpoints.");
+        return;
+    }
+    println!("[DebugSBI] Num  Enb  Type        Address             Len");
+    for wp in list {
+        let enabled = if wp.enabled { 'y' } else { 'n' };
+        let ty = match wp.kind {
+            WatchKind::Write => "hw watch",
+            WatchKind::Read => "read watch",
+            WatchKind::Access => "acc watch",
         };
-        let mut ans: u128 = 0;
-        let mut ansi: i128 = 0;
-        let ans_signed;
-        // todo: 优化
-        let ty = if metadata.data_type == None {
-            Some(DataType::Basic(BasicType { signed: true, width: (core::mem::size_of::<usize>() * 8) as u8 }))
+        println!("[DebugSBI] {:<4} {:<4} {:<11} {:#018x}  {}", wp.number, enabled, ty, wp.address, wp.len);
+    }
+}
+
+// 单步使用的触发器和临时断点只对进入调试器的硬件线程有效，只能单步这个硬件线程
+fn can_step() -> bool {
+    let current = mhartid::read();
+    if SELECTED.load(Ordering::Relaxed) == current {
+        return true
+    }
+    println!("[DebugSBI] Only hart {} can be stepped, switch back with thread {}", current, current);
+    false
+}
+
+fn select_hart(hart_id: usize) {
+    let ctx = match halt::context(hart_id) {
+        // 停下的硬件线程在调试器继续运行前不会修改上下文
+        Some(ctx) => unsafe { &*ctx },
+        None => {
+            println!("[DebugSBI] Hart {} is not stopped in the debugger, see info threads", hart_id);
+            return
+        }
+    };
+    SELECTED.store(hart_id, Ordering::Relaxed);
+    println!("[DebugSBI] Switching to hart {}", hart_id);
+    let space = AddressSpace::new(ctx, &None);
+    if let Err(error) = print_instructions(&space, ctx.mepc, 1) {
+        report_access_error(&space, &error);
+    }
+}
+
+fn print_threads() {
+    let selected = SELECTED.load(Ordering::Relaxed);
+    let online = crate::ONLINE_HARTS.load(Ordering::Relaxed);
+    println!("[DebugSBI]   Hart  pc");
+    for hart_id in (0..MAX_HARTS).filter(|i| (online >> i) & 1 != 0) {
+        let mark = if hart_id == selected { '*' } else { ' ' };
+        match halt::context(hart_id) {
+            Some(ctx) => {
+                let ctx = unsafe { &*ctx };
+                let ins = match read_instruction(&AddressSpace::new(ctx, &None), ctx.mepc) {
+                    Ok(ins) => disasm::disassemble(ins, ctx.mepc),
+                    Err(_) => String::from("(cannot read instruction)"),
+                };
+                println!("[DebugSBI] {} {:<4}  {:#018x}{}  {}", mark, hart_id, ctx.mepc, symbolize(ctx.mepc), ins);
+            }
+            // 停止的硬件线程不接收核间中断，没有停在调试器中
+            None => println!("[DebugSBI] {} {:<4}  not stopped, {}", mark, hart_id, hart_state(hsm::status(hart_id))),
+        }
+    }
+}
+
+// 内核任务列表的布局：任务个数和任务数组的地址；每个任务依次为编号、名称（以0结尾的字符串，可以为0）、状态、pc和sp，都是usize
+const TASK_WORDS: usize = 5;
+// 任务名称最多显示的字节数
+const TASK_NAME_LIMIT: usize = 32;
+
+fn print_tasks(ctx: &KernelContext) {
+    let list = match tiaoshi::task_list() {
+        Some(list) => list,
+        None => {
+            println!("[DebugSBI] The kernel has not published a task list");
+            return
+        }
+    };
+    // 任务列表在内核的地址空间中，使用选中的硬件线程的页表
+    let space = AddressSpace::new(ctx, &None);
+    if let Err(error) = try_print_tasks(&space, list) {
+        report_access_error(&space, &error);
+    }
+}
+
+fn try_print_tasks(space: &AddressSpace, list: usize) -> Result<(), AccessError> {
+    let word = core::mem::size_of::<usize>();
+    let count = read_word(space, list)?;
+    let tasks = read_word(space, list.wrapping_add(word))?;
+    println!("[DebugSBI] {:<6} {:<6} {:<18}  {:<18}  Name", "Id", "State", "pc", "sp");
+    for index in 0..core::cmp::min(count, ARRAY_LIMIT) {
+        let task = tasks.wrapping_add(index * TASK_WORDS * word);
+        let mut fields = [0; TASK_WORDS];
+        for (i, field) in fields.iter_mut().enumerate() {
+            *field = read_word(space, task.wrapping_add(i * word))?;
+        }
+        let [id, name, state, pc, sp] = fields;
+        let name = if name == 0 { String::new() } else { read_task_name(space, name) };
+        println!("[DebugSBI] {:<6} {:<6} {:#018x}  {:#018x}  {}{}", id, state, pc, sp, name, symbolize(pc));
+    }
+    if count > ARRAY_LIMIT {
+        println!("[DebugSBI] ... {} more tasks not shown", count - ARRAY_LIMIT);
+    }
+    Ok(())
+}
+
+fn read_word(space: &AddressSpace, address: usize) -> Result<usize, AccessError> {
+    let width = (core::mem::size_of::<usize>() * 8) as u8;
+    space.read(address, width, Access::Read).map(|(_, value)| value as usize)
+}
+
+fn read_task_name(space: &AddressSpace, address: usize) -> String {
+    let mut bytes = Vec::new();
+    for offset in 0..TASK_NAME_LIMIT {
+        match space.read(address.wrapping_add(offset), 8, Access::Read) {
+            Ok((_, 0)) => break,
+            Ok((_, byte)) => bytes.push(byte as u8),
+            Err(_) => return String::from("<cannot read>"),
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+fn print_symbol(address: usize) {
+    match tiaoshi::lookup(address) {
+        Some((name, 0)) => println!("[DebugSBI] {}", name),
+        Some((name, offset)) => println!("[DebugSBI] {} + {}", name, offset),
+        None => println!("[DebugSBI] No symbol matches {:#x}", address),
+    }
+}
+
+fn print_harts() {
+    let current = riscv::register::mhartid::read();
+    let online = crate::ONLINE_HARTS.load(Ordering::Relaxed);
+    println!("[DebugSBI]   Hart  State");
+    for hart_id in (0..MAX_HARTS).filter(|i| (online >> i) & 1 != 0) {
+        let mark = if hart_id == current { '*' } else { ' ' };
+        println!("[DebugSBI] {} {:<4}  {}", mark, hart_id, hart_state(hsm::status(hart_id)));
+    }
+}
+
+fn reset_type_name(reset_type: usize) -> String {
+    match reset_type {
+        rustsbi::reset::RESET_TYPE_SHUTDOWN => String::from("shutdown"),
+        rustsbi::reset::RESET_TYPE_COLD_REBOOT => String::from("cold reboot"),
+        rustsbi::reset::RESET_TYPE_WARM_REBOOT => String::from("warm reboot"),
+        _ => format!("reset type {:#x}", reset_type),
+    }
+}
+
+fn reset_reason_name(reset_reason: usize) -> String {
+    match reset_reason {
+        rustsbi::reset::RESET_REASON_NO_REASON => String::from("no reason"),
+        rustsbi::reset::RESET_REASON_SYSTEM_FAILURE => String::from("system failure"),
+        _ => format!("reason {:#x}", reset_reason),
+    }
+}
+
+fn hart_state(status: hsm::Status) -> &'static str {
+    match status {
+        hsm::Status::Started => "started",
+        hsm::Status::Stopped => "stopped",
+        hsm::Status::StartPending => "start pending",
+        hsm::Status::StopPending => "stop pending",
+        hsm::Status::Suspended => "suspended",
+        hsm::Status::SuspendPending => "suspend pending",
+        hsm::Status::ResumePending => "resume pending",
+    }
+}
+
+fn print_timer() {
+    let mtime = crate::CLINT.mtime();
+    let frequency = platform::get().timebase_frequency;
+    println!("[DebugSBI] mtime = {:#x}, {} ticks per second", mtime, frequency);
+    println!("[DebugSBI] Hart  mtimecmp            Next tick");
+    let online = crate::ONLINE_HARTS.load(Ordering::Relaxed);
+    for hart_id in (0..MAX_HARTS).filter(|i| (online >> i) & 1 != 0) {
+        let mtimecmp = crate::CLINT.mtimecmp(hart_id);
+        let next = if mtimecmp == u64::MAX {
+            String::from("not set")
+        } else if mtimecmp <= mtime {
+            String::from("pending")
+        } else {
+            let ticks = mtimecmp - mtime;
+            let micros = ticks as u128 * 1_000_000 / frequency as u128;
+            format!("in {} ticks ({} us)", ticks, micros)
+        };
+        println!("[DebugSBI] {:<4}  {:#018x}  {}", hart_id, mtimecmp, next);
+    }
+}
+
+// 计数器只能在自己的硬件线程上读取，所以perf只作用在进入调试器的硬件线程上
+fn print_perf(ctx: &KernelContext) {
+    let current = mhartid::read();
+    if SELECTED.load(Ordering::Relaxed) != current {
+        println!("[DebugSBI] perf only reads counters of hart {}, switch with thread {}", current, current);
+        return
+    }
+    let (last, counters) = pmu::perf(ctx.mepc);
+    match last {
+        Some(pc) => println!("[DebugSBI] Since last perf at {:#x}{}", pc, symbolize(pc)),
+        None => println!("[DebugSBI] Since hart start"),
+    }
+    println!("[DebugSBI] Counter                   Total                 Delta");
+    for (name, total, delta) in counters {
+        println!("[DebugSBI] {:<24}  {:<20}  {}", name, total, delta);
+    }
+}
+
+fn print_misaligned() {
+    let counts = emulate::misaligned_counts();
+    if counts.is_empty() {
+        println!("[DebugSBI] No misaligned accesses.");
+        return;
+    }
+    println!("[DebugSBI] Address             Count       Instruction");
+    for (pc, ins, count) in counts {
+        println!("[DebugSBI] {:#018x}  {:<10}  {}", pc, count, disasm::disassemble(ins, pc));
+    }
+}
+
+fn print_delegation() {
+    println!("[DebugSBI] Name          mcause              Handled by");
+    for cause in delegation::CAUSES {
+        // 没有委托、也不在调试器中停下的陷入由调试大师模拟或转交
+        let handler = if delegation::is_delegated(cause) {
+            "S-mode"
+        } else if delegation::stops_on(cause.mcause()) {
+            "debugger"
         } else {
-            metadata.data_type.clone() 
+            "SBI"
         };
-        match ty {
-            Some(DataType::Basic(BasicType { signed, width })) => {
-                ans_signed = signed;
-                match (signed, width) {
-                    (false, 8) => ans = unsafe { core::ptr::read_volatile(address as *const u8) as u128 },
-                    (false, 16) => ans = unsafe { core::ptr::read_volatile(address as *const u16) as u128 },
-                    (false, 32) => ans = unsafe { core::ptr::read_volatile(address as *const u32) as u128 },
-                    (false, 64) => ans = unsafe { core::ptr::read_volatile(address as *const u64) as u128 },
-                    (false, 128) => ans = unsafe { core::ptr::read_volatile(address as *const u128) },
-                    (true, 8) => ansi = unsafe { core::ptr::read_volatile(address as *const i8) as i128 },
-                    (true, 16) => ansi = unsafe { core::ptr::read_volatile(address as *const i16) as i128 },
-                    (true, 32) => ansi = unsafe { core::ptr::read_volatile(address as *const i32) as i128 },
-                    (true, 64) => ansi = unsafe { core::ptr::read_volatile(address as *const i64) as i128 },
-                    (true, 128) => ansi = unsafe { core::ptr::read_volatile(address as *const i128) as i128 },
-                    _ => unreachable!()
+        println!("[DebugSBI] {:<13} {:#018x}  {:<9} ({})", cause.name, cause.mcause(), handler, trap_name(cause.mcause()));
+    }
+}
+
+fn set_delegation(name: &str, delegated: bool) {
+    let cause = match delegation::find(name) {
+        Some(cause) => cause,
+        None => {
+            println!("[DebugSBI] Unknown trap cause {}, see info delegation", name);
+            return;
+        }
+    };
+    if !delegation::set_delegated(cause, delegated) {
+        println!("[DebugSBI] This hart does not allow changing delegation of {}", trap_name(cause.mcause()));
+    } else if delegated {
+        println!("[DebugSBI] {} is now handled by S-mode", trap_name(cause.mcause()));
+    } else {
+        println!("[DebugSBI] {} now stops in the debugger", trap_name(cause.mcause()));
+    }
+}
+
+// 调试器可以访问的寄存器
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Register {
+    X(usize),
+    Pc,
+    Mstatus,
+}
+
+fn parse_register(name: &str) -> Option<Register> {
+    match name {
+        "pc" => return Some(Register::Pc),
+        "mstatus" => return Some(Register::Mstatus),
+        "fp" => return Some(Register::X(8)),
+        _ => {}
+    }
+    if let Some(index) = name.strip_prefix('x').and_then(|n| n.parse::<usize>().ok()) {
+        if index < 32 {
+            return Some(Register::X(index))
+        }
+    }
+    (0..32).find(|&i| disasm::xreg_name(i) == name).map(Register::X)
+}
+
+fn read_register(ctx: &KernelContext, register: Register) -> usize {
+    match register {
+        Register::X(index) => ctx.x(index),
+        Register::Pc => ctx.mepc,
+        Register::Mstatus => ctx.mstatus,
+    }
+}
+
+fn write_register(ctx: &mut KernelContext, register: Register, value: usize) {
+    match register {
+        Register::X(index) => ctx.set_x(index, value),
+        Register::Pc => ctx.mepc = value,
+        Register::Mstatus => ctx.mstatus = value,
+    }
+}
+
+// 得到操作数的值，寄存器不存在时打印错误并返回None
+fn resolve(ctx: &KernelContext, operand: &Operand) -> Option<usize> {
+    match operand {
+        Operand::Integer(value) => Some(*value),
+        Operand::Register(name) => match parse_register(name) {
+            Some(register) => Some(read_register(ctx, register)),
+            None => {
+                println!("[DebugSBI] Unknown register ${}", name);
+                None
+            }
+        },
+    }
+}
+
+fn print_registers(ctx: &KernelContext) {
+    let mut line = String::new();
+    for index in 1..32 {
+        line.push_str(&format!("{:<4} {:#018x}  ", disasm::xreg_name(index), ctx.x(index)));
+        if index % 4 == 0 || index == 31 {
+            println!("[DebugSBI] {}", line.trim_end());
+            line.clear();
+        }
+    }
+    println!("[DebugSBI] {:<4} {:#018x}  mstatus {:#018x}", "pc", ctx.mepc, ctx.mstatus);
+}
+
+// 调试器访问内存时使用的地址空间
+struct AddressSpace {
+    // 只会是Machine、Supervisor或User
+    mode: PrivilegeMode,
+    translation: paging::Translation,
+}
+
+impl AddressSpace {
+    fn new(ctx: &KernelContext, mode: &Option<PrivilegeMode>) -> AddressSpace {
+        let mode = match mode {
+            Some(PrivilegeMode::Current) | None => current_mode(ctx),
+            Some(mode) => mode.clone(),
+        };
+        let privilege = if mode == PrivilegeMode::User {
+            paging::Privilege::User
+        } else {
+            paging::Privilege::Supervisor
+        };
+        let translation = paging::Translation {
+            // 进入M态后satp没有变化，仍然是被调试程序的页表；其它停下的硬件线程使用它停下时的satp
+            satp: halt::satp_of(ctx).unwrap_or_else(|| satp::read().bits()),
+            privilege,
+            sum: ctx.mstatus & MSTATUS_SUM != 0,
+            mxr: ctx.mstatus & MSTATUS_MXR != 0,
+        };
+        AddressSpace { mode, translation }
+    }
+
+    fn translate(&self, address: usize, access: Access) -> Result<usize, AccessError> {
+        if self.mode == PrivilegeMode::Machine {
+            return Ok(address)
+        }
+        let walk = self.translation.walk(address, access, |pte_address| {
+            probe::load(pte_address, 64).map(|pte| pte as usize)
+        });
+        match walk.result {
+            Ok(paddr) => Ok(paddr),
+            Err(_) => Err(AccessError::PageFault { address, access, walk }),
+        }
+    }
+
+    // 按给定的方式翻译地址，然后读取物理内存
+    fn read(&self, address: usize, width: u8, access: Access) -> Result<(usize, u128), AccessError> {
+        let paddr = self.translate(address, access)?;
+        match probe::load(paddr, width) {
+            Ok(value) => Ok((paddr, value)),
+            Err(cause) => Err(AccessError::AccessFault { address, paddr, cause }),
+        }
+    }
+
+    // 按写入的方式翻译地址，然后写入物理内存
+    fn write(&self, address: usize, width: u8, value: u128) -> Result<usize, AccessError> {
+        let paddr = self.translate(address, Access::Write)?;
+        match probe::store(paddr, width, value) {
+            Ok(()) => Ok(paddr),
+            Err(cause) => Err(AccessError::AccessFault { address, paddr, cause }),
+        }
+    }
+
+    fn describe(&self, address: usize, paddr: usize) -> String {
+        match self.mode {
+            PrivilegeMode::Machine => format!("PhysMem[{:#x}], Machine", paddr),
+            PrivilegeMode::Supervisor => format!("VirtMem[{:#x}] -> PhysMem[{:#x}], Supervisor", address, paddr),
+            _ => format!("VirtMem[{:#x}] -> PhysMem[{:#x}], User", address, paddr),
+        }
+    }
+}
+
+// 被调试程序陷入M态前所在的特权级
+fn current_mode(ctx: &KernelContext) -> PrivilegeMode {
+    match (ctx.mstatus & MSTATUS_MPP) >> 11 {
+        0b11 => PrivilegeMode::Machine,
+        0b01 => PrivilegeMode::Supervisor,
+        _ => PrivilegeMode::User,
+    }
+}
+
+#[derive(Debug)]
+enum AccessError {
+    PageFault { address: usize, access: Access, walk: paging::Walk },
+    AccessFault { address: usize, paddr: usize, cause: usize },
+}
+
+fn report_access_error(space: &AddressSpace, error: &AccessError) {
+    match error {
+        AccessError::PageFault { address, access, walk } => {
+            let fault = walk.result.unwrap_err();
+            let mode = if space.mode == PrivilegeMode::User { "User" } else { "Supervisor" };
+            println!("[DebugSBI] Page fault: {} {:?} at VirtMem[{:#x}], {}", mode, access, address, fault);
+            let satp = space.translation.satp;
+            println!("[DebugSBI] satp = {:#x}, mode = {}, root page table at PhysMem[{:#x}]",
+                satp, paging::mode_name(paging::satp_mode(satp)), paging::satp_root(satp));
+            // 读不到页表项时，记录下来的页表项都指向下一级页表，出错的是没有读到的那个页表项
+            let read_failed = matches!(fault, paging::Fault::PteAccessFault { .. });
+            for (i, step) in walk.steps.iter().enumerate() {
+                let flags = paging::flags(step.pte);
+                let flags = core::str::from_utf8(&flags).unwrap();
+                let note = if i + 1 == walk.steps.len() && !read_failed {
+                    String::from("<- fault here")
+                } else {
+                    format!("next table PhysMem[{:#x}]", step.next_table())
                 };
+                println!("[DebugSBI]   level {}: PTE at PhysMem[{:#x}] = {:#018x} [{}] {}",
+                    step.level, step.pte_address, step.pte, flags, note);
+            }
+            if let paging::Fault::PteAccessFault { address, cause } = fault {
+                println!("[DebugSBI]   PTE at PhysMem[{:#x}] cannot be read, mcause = {:#x} ({}) <- fault here",
+                    address, cause, exception_name(cause));
             }
-            None => unreachable!(),
-            _ => todo!(),
         }
-        let value = if metadata.print_mode == Some(PrintMode::Decimal) {
-            if ans_signed { format!("{}", ansi) } else { format!("{}", ans) }
+        AccessError::AccessFault { address, paddr, cause } => {
+            if space.mode == PrivilegeMode::Machine {
+                println!("[DebugSBI] Access fault at PA {:#x}, mcause = {:#x} ({})",
+                    paddr, cause, exception_name(*cause));
+            } else {
+                println!("[DebugSBI] Access fault at PA {:#x} (VirtMem[{:#x}]), mcause = {:#x} ({})",
+                    paddr, address, cause, exception_name(*cause));
+            }
+        }
+    }
+}
+
+// 读取一个基本类型的值，返回物理地址和读到的值
+fn read_basic(space: &AddressSpace, address: usize, ty: &BasicType) -> Result<(usize, u128), AccessError> {
+    space.read(address, ty.width, Access::Read)
+}
+
+fn format_basic(raw: u128, ty: &BasicType, print_mode: &Option<PrintMode>) -> String {
+    if *print_mode == Some(PrintMode::Decimal) {
+        if ty.signed {
+            // 符号扩展到i128
+            let shift = 128 - ty.width as u32;
+            format!("{}", ((raw << shift) as i128) >> shift)
+        } else {
+            format!("{}", raw)
+        }
+    } else {
+        format!("{:#0width$x}", raw, width = ty.width as usize / 4 + 2)
+    }
+}
+
+// 十进制输出时，一个元素最多占用的字符数，用于对齐表格
+fn decimal_width(ty: &BasicType) -> usize {
+    let max = u128::MAX >> (128 - ty.width as u32);
+    if ty.signed {
+        format!("-{}", (max >> 1) + 1).len()
+    } else {
+        format!("{}", max).len()
+    }
+}
+
+fn read_instruction_half(space: &AddressSpace, address: usize) -> Result<u16, AccessError> {
+    space.read(address, 16, Access::Execute).map(|(_, value)| value as u16)
+}
+
+// 读取一条指令，超过4字节的指令只读取低32位
+fn read_instruction(space: &AddressSpace, address: usize) -> Result<u32, AccessError> {
+    let low = read_instruction_half(space, address)?;
+    if disasm::instruction_length(low) == 2 {
+        return Ok(low as u32)
+    }
+    let high = read_instruction_half(space, address.wrapping_add(2))?;
+    Ok((low as u32) | ((high as u32) << 16))
+}
+
+// 内核登记了符号表时，地址所在的符号，格式为“ <名称+偏移>”
+fn symbolize(address: usize) -> String {
+    match tiaoshi::lookup(address) {
+        Some((name, 0)) => format!(" <{}>", name),
+        Some((name, offset)) => format!(" <{}+{}>", name, offset),
+        None => String::new(),
+    }
+}
+
+// 读取内核给出的消息，不能读取的部分和不是UTF-8的字节用替换字符表示
+fn read_message(ctx: &KernelContext, address: usize, len: usize) -> String {
+    let space = AddressSpace::new(ctx, &None);
+    let mut bytes = Vec::new();
+    for offset in 0..len {
+        match space.read(address.wrapping_add(offset), 8, Access::Read) {
+            Ok((_, byte)) => bytes.push(byte as u8),
+            Err(_) => break,
+        }
+    }
+    let mut message = String::from_utf8_lossy(&bytes).into_owned();
+    if bytes.len() < len {
+        message.push_str("<cannot read>");
+    }
+    message
+}
+
+fn print_instructions(space: &AddressSpace, address: usize, count: usize) -> Result<(), AccessError> {
+    let mut pc = address;
+    for _ in 0..core::cmp::min(count, ARRAY_LIMIT) {
+        // 压缩指令只对齐到2字节，所以每次读半字
+        let low = read_instruction_half(space, pc)?;
+        let len = disasm::instruction_length(low);
+        let (ins, raw) = if len == 2 {
+            (low as u32, format!("{:04x}", low))
         } else {
-            if ans_signed { format!("{:#x}", ansi) } else { format!("{:#x}", ans) }
+            let high = read_instruction_half(space, pc.wrapping_add(2))?;
+            let ins = (low as u32) | ((high as u32) << 16);
+            (ins, format!("{:08x}", ins))
         };
-        println!("[DebugSBI] PhysMem[{:#x}], Machine = {}", address, value);
-    } else if let Some(CommandType::C) = metadata.command_type {
-        println!("[DebugSBI] Continuing.");
-        return ControlFlow::Break
+        println!("[DebugSBI] {:#x}{}: {:<8}  {}", pc, symbolize(pc), raw, disasm::disassemble(ins, pc));
+        pc = pc.wrapping_add(len);
     }
-    ControlFlow::Continue
+    if count > ARRAY_LIMIT {
+        println!("[DebugSBI] ... {} more instructions not shown, at most {} instructions are printed at once", count - ARRAY_LIMIT, ARRAY_LIMIT);
+    }
+    Ok(())
+}
+
+fn print_array(space: &AddressSpace, address: usize, ty: &BasicType, len: usize, print_mode: &Option<PrintMode>) -> Result<(), AccessError> {
+    let elem_bytes = ty.width as usize / 8;
+    let per_row = core::cmp::max(ARRAY_ROW_BYTES / elem_bytes, 1);
+    let shown = core::cmp::min(len, ARRAY_LIMIT);
+    let sign = if ty.signed { 'i' } else { 'u' };
+    let paddr = space.translate(address, Access::Read)?;
+    println!("[DebugSBI] {} = [{}{}; {}]", space.describe(address, paddr), sign, ty.width, len);
+    let pad = if *print_mode == Some(PrintMode::Decimal) { decimal_width(ty) } else { 0 };
+    for row in (0..shown).step_by(per_row) {
+        let row_address = address.wrapping_add(row * elem_bytes);
+        let mut line = format!("{:#x}:", row_address);
+        for i in row..core::cmp::min(row + per_row, shown) {
+            // 数组可能跨越多个页，每个元素单独翻译
+            let (_, raw) = match read_basic(space, address.wrapping_add(i * elem_bytes), ty) {
+                Ok(ans) => ans,
+                Err(error) => {
+                    println!("[DebugSBI] {}", line);
+                    return Err(error)
+                }
+            };
+            let value = format_basic(raw, ty, print_mode);
+            line.push_str(&format!(" {:>pad$}", value, pad = pad));
+        }
+        println!("[DebugSBI] {}", line);
+    }
+    if shown < len {
+        println!("[DebugSBI] ... {} more elements not shown, at most {} elements are printed at once", len - shown, ARRAY_LIMIT);
+    }
+    Ok(())
 }
 
 struct Lexer<I: Iterator> {
@@ -140,6 +1518,8 @@ impl<I: Iterator<Item = char>> Iterator for Lexer<I> {
             Some('[') => { self.iter.next(); Some(Word::LeftSquareBracket) },
             Some(']') => { self.iter.next(); Some(Word::RightSquareBracket) },
             Some(';') => { self.iter.next(); Some(Word::Semicolon) },
+            Some('$') => { self.iter.next(); Some(Word::Dollar) },
+            Some('=') => { self.iter.next(); Some(Word::Equal) },
             Some(' ') | Some('\t') => { 
                 loop {
                     match self.iter.peek() {
@@ -170,6 +1550,8 @@ enum Word {
     LeftSquareBracket,
     RightSquareBracket,
     Semicolon,
+    Dollar,
+    Equal,
     Space,
     Other,
 }
@@ -179,30 +1561,231 @@ struct Metadata {
     command_type: Option<CommandType>,
     privileged_mode: Option<PrivilegeMode>,
     data_type: Option<DataType>,
-    address_number: Option<usize>,
+    address: Option<Operand>,
     print_mode: Option<PrintMode>,
+    register: Option<String>,
+    value: Option<Operand>,
+    number: Option<usize>,
+    length: Option<usize>,
+    // 陷入原因的名称或异常编号
+    cause: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Operand {
+    Integer(usize),
+    Register(String),
 }
 
 fn command<I: Iterator<Item = Word>>(iter: &mut I, sym: &mut Option<Word>, m: &mut Metadata) -> Result<(), ()>  { 
+    // x命令后面直接跟着特权级，需要单独处理
     if *sym == Some(Word::Character('x')) {
-        x(iter, sym, m)
-    } else if *sym == Some(Word::Character('c')) {
-        c(iter, sym, m)
+        return x(iter, sym, m)
+    }
+    match keyword(iter, sym).as_str() {
+        "c" | "continue" => c(iter, sym, m),
+        "si" | "stepi" => stepi(iter, sym, m),
+        "ni" | "nexti" => {
+            m.command_type = Some(CommandType::Next);
+            Ok(())
+        }
+        "finish" => {
+            m.command_type = Some(CommandType::Finish);
+            Ok(())
+        }
+        "r" => {
+            m.command_type = Some(CommandType::InfoRegisters);
+            Ok(())
+        }
+        "info" => info(iter, sym, m),
+        "set" => set(iter, sym, m),
+        "b" | "break" => {
+            space(iter, sym)?;
+            m.command_type = Some(CommandType::Break);
+            m.address = Some(operand(iter, sym)?);
+            Ok(())
+        }
+        "watch" => watch(iter, sym, m, WatchKind::Write),
+        "rwatch" => watch(iter, sym, m, WatchKind::Read),
+        "awatch" => watch(iter, sym, m, WatchKind::Access),
+        "delegate" => delegate(iter, sym, m, true),
+        "undelegate" => delegate(iter, sym, m, false),
+        "d" | "delete" => number(iter, sym, m, CommandType::Delete),
+        "enable" => number(iter, sym, m, CommandType::Enable),
+        "disable" => number(iter, sym, m, CommandType::Disable),
+        "thread" => number(iter, sym, m, CommandType::Thread),
+        "catch" => catch(iter, sym, m, true),
+        "uncatch" => catch(iter, sym, m, false),
+        "perf" => {
+            m.command_type = Some(CommandType::Perf);
+            Ok(())
+        }
+        "reboot" => {
+            m.command_type = Some(CommandType::Reboot);
+            Ok(())
+        }
+        "shutdown" => {
+            m.command_type = Some(CommandType::Shutdown);
+            Ok(())
+        }
+        _ => Err(())
+    }
+}
+
+// 连续的字母和数字组成的单词，用于命令和寄存器名称
+fn keyword<I: Iterator<Item = Word>>(iter: &mut I, sym: &mut Option<Word>) -> String {
+    let mut ans = String::new();
+    loop {
+        match *sym {
+            Some(Word::Character(ch)) => ans.push(ch),
+            Some(Word::Integer(i)) if !ans.is_empty() => ans.push_str(&format!("{}", i)),
+            _ => break
+        }
+        *sym = iter.next();
+    }
+    ans
+}
+
+fn c<I: Iterator<Item = Word>>(_iter: &mut I, _sym: &mut Option<Word>, m: &mut Metadata) -> Result<(), ()>  { 
+    m.command_type = Some(CommandType::C);
+    Ok(())
+}
+
+// si [条数]
+fn stepi<I: Iterator<Item = Word>>(iter: &mut I, sym: &mut Option<Word>, m: &mut Metadata) -> Result<(), ()>  {
+    m.command_type = Some(CommandType::Step);
+    space(iter, sym)?;
+    if *sym == None {
+        return Ok(())
+    }
+    if let Some(Word::Integer(i)) = *sym {
+        *sym = iter.next();
+        m.number = Some(i);
+        Ok(())
     } else {
         Err(())
     }
 }
 
-fn c<I: Iterator<Item = Word>>(iter: &mut I, sym: &mut Option<Word>, m: &mut Metadata) -> Result<(), ()>  { 
-    if *sym == Some(Word::Character('c')) {
+// watch | rwatch | awatch 地址 [字节数]
+fn watch<I: Iterator<Item = Word>>(iter: &mut I, sym: &mut Option<Word>, m: &mut Metadata, kind: WatchKind) -> Result<(), ()>  {
+    space(iter, sym)?;
+    m.command_type = Some(CommandType::Watch(kind));
+    m.address = Some(operand(iter, sym)?);
+    space(iter, sym)?;
+    if let Some(Word::Integer(i)) = *sym {
+        *sym = iter.next();
+        m.length = Some(i);
+    }
+    Ok(())
+}
+
+// delegate | undelegate 原因名称或异常编号
+fn delegate<I: Iterator<Item = Word>>(iter: &mut I, sym: &mut Option<Word>, m: &mut Metadata, delegated: bool) -> Result<(), ()>  {
+    space(iter, sym)?;
+    let cause = match *sym {
+        Some(Word::Integer(i)) => {
+            *sym = iter.next();
+            format!("{}", i)
+        }
+        _ => keyword(iter, sym),
+    };
+    if cause.is_empty() {
+        return Err(())
+    }
+    m.command_type = Some(CommandType::Delegate(delegated));
+    m.cause = Some(cause);
+    Ok(())
+}
+
+// catch | uncatch reset
+fn catch<I: Iterator<Item = Word>>(iter: &mut I, sym: &mut Option<Word>, m: &mut Metadata, stop: bool) -> Result<(), ()>  {
+    space(iter, sym)?;
+    match keyword(iter, sym).as_str() {
+        "reset" => m.command_type = Some(CommandType::CatchReset(stop)),
+        _ => return Err(())
+    }
+    Ok(())
+}
+
+// info registers | info breakpoints | info watchpoints | info delegation | info misaligned | info timer | info harts | info threads | info tasks | info symbol 操作数
+fn info<I: Iterator<Item = Word>>(iter: &mut I, sym: &mut Option<Word>, m: &mut Metadata) -> Result<(), ()>  {
+    space(iter, sym)?;
+    match keyword(iter, sym).as_str() {
+        "r" | "reg" | "registers" => m.command_type = Some(CommandType::InfoRegisters),
+        "b" | "break" | "breakpoints" => m.command_type = Some(CommandType::InfoBreakpoints),
+        "watch" | "watchpoints" => m.command_type = Some(CommandType::InfoWatchpoints),
+        "deleg" | "delegation" => m.command_type = Some(CommandType::InfoDelegation),
+        "misaligned" => m.command_type = Some(CommandType::InfoMisaligned),
+        "timer" => m.command_type = Some(CommandType::InfoTimer),
+        "harts" => m.command_type = Some(CommandType::InfoHarts),
+        "threads" => m.command_type = Some(CommandType::InfoThreads),
+        "tasks" => m.command_type = Some(CommandType::InfoTasks),
+        "symbol" => {
+            space(iter, sym)?;
+            m.command_type = Some(CommandType::InfoSymbol);
+            m.address = Some(operand(iter, sym)?);
+        }
+        _ => return Err(())
+    }
+    Ok(())
+}
+
+// 以断点编号或硬件线程编号为参数的命令
+fn number<I: Iterator<Item = Word>>(iter: &mut I, sym: &mut Option<Word>, m: &mut Metadata, command_type: CommandType) -> Result<(), ()>  {
+    space(iter, sym)?;
+    if let Some(Word::Integer(i)) = *sym {
         *sym = iter.next();
-        m.command_type = Some(CommandType::C);
+        m.command_type = Some(command_type);
+        m.number = Some(i);
         Ok(())
     } else {
         Err(())
     }
 }
 
+// set $寄存器 = 操作数
+fn set<I: Iterator<Item = Word>>(iter: &mut I, sym: &mut Option<Word>, m: &mut Metadata) -> Result<(), ()>  {
+    space(iter, sym)?;
+    if *sym != Some(Word::Dollar) {
+        return Err(())
+    }
+    *sym = iter.next();
+    let register = keyword(iter, sym);
+    if register.is_empty() {
+        return Err(())
+    }
+    space(iter, sym)?;
+    if *sym != Some(Word::Equal) {
+        return Err(())
+    }
+    *sym = iter.next();
+    space(iter, sym)?;
+    m.command_type = Some(CommandType::Set);
+    m.register = Some(register);
+    m.value = Some(operand(iter, sym)?);
+    Ok(())
+}
+
+// O → 常数 | $寄存器
+fn operand<I: Iterator<Item = Word>>(iter: &mut I, sym: &mut Option<Word>) -> Result<Operand, ()>  {
+    match *sym {
+        Some(Word::Integer(i)) => {
+            *sym = iter.next();
+            Ok(Operand::Integer(i))
+        }
+        Some(Word::Dollar) => {
+            *sym = iter.next();
+            let register = keyword(iter, sym);
+            if register.is_empty() {
+                return Err(())
+            }
+            Ok(Operand::Register(register))
+        }
+        _ => Err(())
+    }
+}
+
 fn x<I: Iterator<Item = Word>>(iter: &mut I, sym: &mut Option<Word>, m: &mut Metadata) -> Result<(), ()>  {
     // println!("x; sym = {:?}", *sym);
     if *sym == Some(Word::Character('x')) {
@@ -214,13 +1797,8 @@ fn x<I: Iterator<Item = Word>>(iter: &mut I, sym: &mut Option<Word>, m: &mut Met
             print_mode(iter, sym, m)?;
         } 
         space(iter, sym)?;
-        let address_number = if let Some(Word::Integer(i)) = *sym {
-            i
-        } else {
-            return Err(())
-        };
         m.command_type = Some(CommandType::X);
-        m.address_number = Some(address_number);
+        m.address = Some(operand(iter, sym)?);
         Ok(())
     } else {
         Err(())
@@ -250,7 +1828,7 @@ fn privilege_mode<I: Iterator<Item = Word>>(iter: &mut I, sym: &mut Option<Word>
     }
 }
 
-// T → 类型 | [类型; 常数]
+// T → 类型 | [类型; 常数] | z | 常数z
 fn data_type<I: Iterator<Item = Word>>(iter: &mut I, sym: &mut Option<Word>, m: &mut Metadata) -> Result<(), ()>  {
     // println!("data_type; sym = {:?}", *sym);
     if *sym == Some(Word::LeftSquareBracket) {
@@ -276,7 +1854,16 @@ fn data_type<I: Iterator<Item = Word>>(iter: &mut I, sym: &mut Option<Word>, m:
         Ok(())
     } else if *sym == Some(Word::Character('z')) {
         *sym = iter.next();
-        m.data_type = Some(DataType::Instruction);
+        m.data_type = Some(DataType::Instruction(1));
+        Ok(())
+    } else if let Some(Word::Integer(count)) = *sym {
+        // x/{数量}z：连续打印多条指令
+        *sym = iter.next();
+        if *sym != Some(Word::Character('z')) {
+            return Err(())
+        }
+        *sym = iter.next();
+        m.data_type = Some(DataType::Instruction(count));
         Ok(())
     } else {
         if let Ok(basic_type) = basic_type(iter, sym) {
@@ -346,9 +1933,13 @@ fn print_mode<I: Iterator<Item = Word>>(iter: &mut I, sym: &mut Option<Word>, m:
 enum ParseError {
     InvalidUtf8(alloc::string::FromUtf8Error),
     SyntaxError,
+    // 行首读到了'$'，连接的是GDB
+    GdbPacket,
 }
 
-fn fill_input_buffer() -> Vec<u8> {
+fn fill_input_buffer() -> Result<Vec<u8>, ParseError> {
+    // 其它还在运行的硬件线程的输出，在命令提示符之前打印
+    console::flush_buffered();
     print!("[DebugSBI] (debug) ");
     let mut input_buf = Vec::new();
     loop {
@@ -373,6 +1964,9 @@ fn fill_input_buffer() -> Vec<u8> {
                 println!("");
                 break
             }
+            // GDB的包以'$'开头，确认上一个包的'+'可以忽略
+            b'$' if input_buf.is_empty() => return Err(ParseError::GdbPacket),
+            b'+' if input_buf.is_empty() => {}
             // 其它字符
             input => {
                 if input_buf.len() < INPUT_LIMIT {
@@ -382,13 +1976,40 @@ fn fill_input_buffer() -> Vec<u8> {
             }
         }
     }
-    input_buf
+    Ok(input_buf)
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 enum CommandType {
     X,
     C,
+    Step,
+    Next,
+    Finish,
+    InfoRegisters,
+    Set,
+    Break,
+    Delete,
+    Enable,
+    Disable,
+    InfoBreakpoints,
+    Watch(WatchKind),
+    InfoWatchpoints,
+    // 参数为是否委托给S态
+    Delegate(bool),
+    InfoDelegation,
+    InfoMisaligned,
+    InfoTimer,
+    InfoHarts,
+    Thread,
+    InfoThreads,
+    InfoTasks,
+    InfoSymbol,
+    // 参数为内核请求复位时是否停在调试器中
+    CatchReset(bool),
+    Perf,
+    Reboot,
+    Shutdown,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -403,7 +2024,7 @@ enum PrivilegeMode {
 enum DataType {
     Basic(BasicType),
     Array(BasicType, usize),
-    Instruction,
+    Instruction(usize),
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -0,0 +1,124 @@
+// 把异常和中断委托给S态（medeleg和mideleg）
+//
+// 委托的陷入直接进入内核的陷入处理函数，不经过调试大师。调试时可以取消某个原因的委托，
+// 这样内核发生这种陷入时会先停在调试器中
+use crate::executor::INTERRUPT_BIT;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+// 在调试器中取消了委托的异常，转交给内核前先停在调试器中
+static STOP_EXCEPTIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// 一种可以委托的陷入
+pub struct Cause {
+    /// 异常编号或中断编号
+    pub code: usize,
+    pub interrupt: bool,
+    /// 调试器命令中使用的名称
+    pub name: &'static str,
+    /// 启动时是否委托给S态
+    pub default: bool,
+}
+
+const fn exception(code: usize, name: &'static str, default: bool) -> Cause {
+    Cause { code, interrupt: false, name, default }
+}
+
+const fn interrupt(code: usize, name: &'static str) -> Cause {
+    Cause { code, interrupt: true, name, default: true }
+}
+
+// 断点异常和S态环境调用（即SBI调用）必须由调试大师处理，不在表中；
+// 非法指令和地址不对齐的读写先在M态尝试模拟，无法模拟时再转交给S态。
+// 访问错误和OpenSBI一样不委托：访问调试大师自己的内存时停在调试器中报告，其它的再转交给S态
+pub const CAUSES: &[Cause] = &[
+    exception(0, "imisaligned", true),
+    exception(1, "ifault", false),
+    exception(2, "illegal", false),
+    exception(4, "lmisaligned", false),
+    exception(5, "lfault", false),
+    exception(6, "smisaligned", false),
+    exception(7, "sfault", false),
+    exception(8, "uecall", true),
+    exception(12, "ipage", true),
+    exception(13, "lpage", true),
+    exception(15, "spage", true),
+    interrupt(1, "ssoft"),
+    interrupt(5, "stimer"),
+    interrupt(9, "sext"),
+];
+
+impl Cause {
+    /// 这种陷入对应的mcause
+    pub fn mcause(&self) -> usize {
+        if self.interrupt { INTERRUPT_BIT | self.code } else { self.code }
+    }
+}
+
+/// 按名称或异常编号查找
+pub fn find(name: &str) -> Option<&'static Cause> {
+    match name.parse::<usize>() {
+        Ok(code) => CAUSES.iter().find(|cause| !cause.interrupt && cause.code == code),
+        Err(_) => CAUSES.iter().find(|cause| cause.name == name),
+    }
+}
+
+/// 启动时设置默认的委托
+pub fn init() {
+    let (mut medeleg, mut mideleg) = (0, 0);
+    for cause in CAUSES.iter().filter(|cause| cause.default) {
+        if cause.interrupt {
+            mideleg |= 1 << cause.code;
+        } else {
+            medeleg |= 1 << cause.code;
+        }
+    }
+    unsafe {
+        asm!("csrw medeleg, {}", in(reg) medeleg);
+        asm!("csrw mideleg, {}", in(reg) mideleg);
+    }
+}
+
+/// 这个原因当前是否委托给了S态
+pub fn is_delegated(cause: &Cause) -> bool {
+    (read(cause.interrupt) >> cause.code) & 1 != 0
+}
+
+/// 设置是否委托，返回设置是否生效；medeleg和mideleg的位是WARL的，硬件可能不允许委托
+///
+/// 取消委托的异常会在转交给内核前停在调试器中
+pub fn set_delegated(cause: &Cause, delegated: bool) -> bool {
+    let bit: usize = 1 << cause.code;
+    if !cause.interrupt {
+        if delegated {
+            STOP_EXCEPTIONS.fetch_and(!bit, Ordering::Relaxed);
+        } else {
+            STOP_EXCEPTIONS.fetch_or(bit, Ordering::Relaxed);
+        }
+    }
+    unsafe {
+        match (cause.interrupt, delegated) {
+            (false, true) => asm!("csrs medeleg, {}", in(reg) bit),
+            (false, false) => asm!("csrc medeleg, {}", in(reg) bit),
+            (true, true) => asm!("csrs mideleg, {}", in(reg) bit),
+            (true, false) => asm!("csrc mideleg, {}", in(reg) bit),
+        }
+    }
+    is_delegated(cause) == delegated
+}
+
+/// 转交这个异常前是否要停在调试器中
+pub fn stops_on(mcause: usize) -> bool {
+    mcause & INTERRUPT_BIT == 0 && (STOP_EXCEPTIONS.load(Ordering::Relaxed) >> mcause) & 1 != 0
+}
+
+fn read(interrupt: bool) -> usize {
+    let bits: usize;
+    unsafe {
+        if interrupt {
+            asm!("csrr {}, mideleg", out(reg) bits);
+        } else {
+            asm!("csrr {}, medeleg", out(reg) bits);
+        }
+    }
+    bits
+}
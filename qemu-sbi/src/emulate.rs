@@ -0,0 +1,215 @@
+// 在M态模拟硬件没有实现的指令
+//
+// 模拟读取CSR的指令，需要模拟的CSR登记在CSRS表中；模拟地址不对齐的读写，按字节访问内存。
+// 模拟成功时写入目的寄存器并跳过这条指令，否则仍然把异常转交给内核
+use crate::executor::{KernelContext, MSTATUS_MXR};
+use crate::{disasm, pmu, probe, step};
+use alloc::vec::Vec;
+use spin::Mutex;
+
+// 被模拟的CSR和读取它的函数
+static CSRS: &[(usize, fn() -> usize)] = &[
+    (CSR_TIME, read_time),
+    #[cfg(target_pointer_width = "32")]
+    (CSR_TIMEH, read_timeh),
+];
+
+const CSR_TIME: usize = 0xc01;
+#[cfg(target_pointer_width = "32")]
+const CSR_TIMEH: usize = 0xc81;
+
+// 没有time寄存器的平台上，用CLINT的mtime代替
+fn read_time() -> usize {
+    crate::CLINT.mtime() as usize
+}
+
+#[cfg(target_pointer_width = "32")]
+fn read_timeh() -> usize {
+    (crate::CLINT.mtime() >> 32) as usize
+}
+
+/// 模拟发生非法指令异常的指令，返回是否模拟成功
+pub fn illegal_instruction(ctx: &mut KernelContext, mtval: usize) -> bool {
+    // mtval可能为0，这时从mepc读取指令
+    let ins = if mtval & 0b11 == 0b11 {
+        mtval as u32
+    } else if mtval == 0 {
+        match fetch(ctx) {
+            Some(ins) if ins & 0b11 == 0b11 => ins,
+            _ => return false,
+        }
+    } else {
+        // 压缩指令都不需要模拟
+        return false
+    };
+    if emulate_csr(ctx, ins) {
+        ctx.mepc = ctx.mepc.wrapping_add(4);
+        pmu::record(pmu::Event::IllegalInstruction);
+        true
+    } else {
+        false
+    }
+}
+
+// 按被调试程序的地址空间读取pc处的指令，pc可能只对齐到2个字节。
+// 用读取代替取指，设置MXR后只能执行的页也可以读取
+fn fetch(ctx: &KernelContext) -> Option<u32> {
+    let mstatus = ctx.mstatus | MSTATUS_MXR;
+    let low = probe::load_virtual(ctx.mepc, 16, mstatus).ok()?;
+    if disasm::instruction_length(low as u16) == 2 {
+        return Some(low as u32)
+    }
+    let high = probe::load_virtual(ctx.mepc.wrapping_add(2), 16, mstatus).ok()?;
+    Some((low | (high << 16)) as u32)
+}
+
+// 只读取CSR的指令：csrrs和csrrc的rs1为x0，或csrrsi和csrrci的立即数为0
+fn emulate_csr(ctx: &mut KernelContext, ins: u32) -> bool {
+    if ins & 0x7f != 0x73 {
+        return false
+    }
+    let rd = ((ins >> 7) & 0x1f) as usize;
+    let funct3 = (ins >> 12) & 0b111;
+    let rs1 = (ins >> 15) & 0x1f;
+    let csr = (ins >> 20) as usize;
+    if !matches!(funct3, 2 | 3 | 6 | 7) || rs1 != 0 {
+        return false
+    }
+    match CSRS.iter().find(|(number, _)| *number == csr) {
+        Some((_, read)) => {
+            ctx.set_x(rd, read());
+            true
+        }
+        None => false,
+    }
+}
+
+// 每条发生过地址不对齐的指令：pc、指令和次数
+static MISALIGNED: Mutex<Vec<(usize, u32, usize)>> = Mutex::new(Vec::new());
+
+/// 发生过地址不对齐的指令和次数，按pc排序
+pub fn misaligned_counts() -> Vec<(usize, u32, usize)> {
+    let mut ans = MISALIGNED.lock().clone();
+    ans.sort_unstable_by_key(|&(pc, _, _)| pc);
+    ans
+}
+
+// 读写指令的数据寄存器和数据格式
+struct Transfer {
+    // 整数寄存器或浮点寄存器的编号
+    register: usize,
+    float: bool,
+    // 整数读取是否符号扩展
+    signed: bool,
+}
+
+// 解码整数和浮点读写指令，不包括原子指令
+fn decode_transfer(ins: u32) -> Option<Transfer> {
+    let bits = |hi: u32, lo: u32| ((ins >> lo) & ((1 << (hi - lo + 1)) - 1)) as usize;
+    let transfer = |register: usize, float: bool, signed: bool| Some(Transfer { register, float, signed });
+    if disasm::instruction_length(ins as u16) == 2 {
+        // rd'和rs2'只能表示x8到x15（或f8到f15）
+        let rd_short = 8 + bits(4, 2);
+        return match (ins & 0b11, bits(15, 13)) {
+            (0b00, 0b001) => transfer(rd_short, true, false), // c.fld
+            (0b00, 0b010) => transfer(rd_short, false, true), // c.lw
+            (0b00, 0b011) => transfer(rd_short, false, true), // c.ld
+            (0b00, 0b101) => transfer(rd_short, true, false), // c.fsd
+            (0b00, 0b110) | (0b00, 0b111) => transfer(rd_short, false, false), // c.sw、c.sd
+            (0b10, 0b001) => transfer(bits(11, 7), true, false), // c.fldsp
+            (0b10, 0b010) | (0b10, 0b011) => transfer(bits(11, 7), false, true), // c.lwsp、c.ldsp
+            (0b10, 0b101) => transfer(bits(6, 2), true, false), // c.fsdsp
+            (0b10, 0b110) | (0b10, 0b111) => transfer(bits(6, 2), false, false), // c.swsp、c.sdsp
+            _ => None,
+        }
+    }
+    let funct3 = bits(14, 12);
+    match ins & 0x7f {
+        // lb、lh、lw、ld、lbu、lhu、lwu
+        0x03 if funct3 != 0b111 => transfer(bits(11, 7), false, funct3 < 0b100),
+        // sb、sh、sw、sd
+        0x23 if funct3 < 0b100 => transfer(bits(24, 20), false, false),
+        // flw、fld
+        0x07 if funct3 == 0b010 || funct3 == 0b011 => transfer(bits(11, 7), true, false),
+        // fsw、fsd
+        0x27 if funct3 == 0b010 || funct3 == 0b011 => transfer(bits(24, 20), true, false),
+        _ => None,
+    }
+}
+
+/// 模拟地址不对齐的读写；返回仍然要转交给内核的异常（mcause和mtval），模拟成功时返回None
+///
+/// 按字节读写时发生的页错误和访问错误也转交给内核，mtval为出错字节的地址
+pub fn misaligned(ctx: &mut KernelContext, mcause: usize, mtval: usize) -> Option<(usize, usize)> {
+    let ins = match fetch(ctx) {
+        Some(ins) => ins,
+        None => return Some((mcause, mtval)),
+    };
+    let (access, transfer) = match (step::memory_access(ins, ctx), decode_transfer(ins)) {
+        (Some(access), Some(transfer)) => (access, transfer),
+        _ => return Some((mcause, mtval)),
+    };
+    let pc = ctx.mepc;
+    {
+        let mut counts = MISALIGNED.lock();
+        match counts.iter_mut().find(|(address, _, _)| *address == pc) {
+            Some((_, _, count)) => *count += 1,
+            None => counts.push((pc, ins, 1)),
+        }
+    }
+    if access.store {
+        let value = if transfer.float { read_float(transfer.register) } else { ctx.x(transfer.register) };
+        for i in 0..access.width {
+            let address = access.address.wrapping_add(i);
+            if let Err(cause) = probe::store_virtual(address, 8, (value >> (8 * i)) & 0xff, ctx.mstatus) {
+                return Some((cause, address))
+            }
+        }
+    } else {
+        let mut value = 0;
+        for i in 0..access.width {
+            let address = access.address.wrapping_add(i);
+            match probe::load_virtual(address, 8, ctx.mstatus) {
+                Ok(byte) => value |= byte << (8 * i),
+                Err(cause) => return Some((cause, address)),
+            }
+        }
+        let shift = 8 * (core::mem::size_of::<usize>() - access.width);
+        if transfer.float {
+            // 单精度数写入双精度寄存器时，高32位全为1（NaN-boxing）
+            if access.width == 4 {
+                value |= !0xffff_ffff;
+            }
+            write_float(transfer.register, value);
+            ctx.mstatus |= MSTATUS_FS_DIRTY;
+        } else if transfer.signed && shift != 0 {
+            ctx.set_x(transfer.register, (((value << shift) as isize) >> shift) as usize);
+        } else {
+            ctx.set_x(transfer.register, value);
+        }
+    }
+    ctx.mepc = pc.wrapping_add(disasm::instruction_length(ins as u16));
+    pmu::record(if access.store { pmu::Event::MisalignedStore } else { pmu::Event::MisalignedLoad });
+    None
+}
+
+// 写入浮点寄存器后，被调试程序的浮点状态变为Dirty
+const MSTATUS_FS_DIRTY: usize = 0b11 << 13;
+
+// 调试大师按RV64IMAC编译，浮点指令直接写出编码，数据经过a0传递。需要D扩展
+const FMV_X_D_A0: u32 = 0xe200_0553; // fmv.x.d a0, f0
+const FMV_D_X_A0: u32 = 0xf205_0053; // fmv.d.x f0, a0
+
+fn read_float(index: usize) -> usize {
+    let value: usize;
+    register_match!(0..32, index, N: u32 => unsafe {
+        asm!(".word {}", const FMV_X_D_A0 | (N << 15), out("a0") value)
+    });
+    value
+}
+
+fn write_float(index: usize, value: usize) {
+    register_match!(0..32, index, N: u32 => unsafe {
+        asm!(".word {}", const FMV_D_X_A0 | (N << 7), in("a0") value)
+    });
+}
@@ -0,0 +1,19 @@
+// SBI规范中的错误码
+use rustsbi::SbiRet;
+
+pub const SBI_SUCCESS: usize = 0;
+pub const SBI_ERR_FAILED: usize = -1isize as usize;
+pub const SBI_ERR_NOT_SUPPORTED: usize = -2isize as usize;
+pub const SBI_ERR_INVALID_PARAM: usize = -3isize as usize;
+pub const SBI_ERR_INVALID_ADDRESS: usize = -5isize as usize;
+pub const SBI_ERR_ALREADY_AVAILABLE: usize = -6isize as usize;
+pub const SBI_ERR_ALREADY_STARTED: usize = -7isize as usize;
+pub const SBI_ERR_ALREADY_STOPPED: usize = -8isize as usize;
+
+pub fn ok(value: usize) -> SbiRet {
+    SbiRet { error: SBI_SUCCESS, value }
+}
+
+pub fn error(code: usize) -> SbiRet {
+    SbiRet { error: code, value: 0 }
+}
@@ -1,6 +1,5 @@
 use riscv::register::{
-    mstatus::Mstatus,
-    mtval, mcause::{self, Trap, Exception},
+    mtval, mcause::{self, Trap, Exception, Interrupt}, stvec,
     mtvec::{self, TrapMode},
     mstatus::{self, MPP},
 };
@@ -26,7 +25,7 @@ impl Runtime {
     pub fn new(mhartid: usize, opaque: usize, mepc: usize) -> Runtime {
         let mut context: KernelContext = unsafe { core::mem::MaybeUninit::zeroed().assume_init() };
         unsafe { mstatus::set_mpp(MPP::Supervisor) };
-        context.mstatus = mstatus::read();
+        context.mstatus = read_mstatus();
         context.mepc = mepc;
         context.a0 = mhartid;
         context.a1 = opaque;
@@ -36,6 +35,48 @@ impl Runtime {
     pub fn context_mut(&mut self) -> &mut KernelContext {
         &mut self.context
     }
+
+    /// 把异常转交给内核的陷入处理函数，效果和这个异常委托给S态时相同
+    ///
+    /// 内核还没有设置stvec时无法转交，返回false
+    pub fn redirect(&mut self, mcause: usize, mtval: usize) -> bool {
+        let vector = match trap_vector(mcause) {
+            Some(vector) => vector,
+            None => return false,
+        };
+        let ctx = &mut self.context;
+        unsafe {
+            asm!("csrw sepc, {}", in(reg) ctx.mepc);
+            asm!("csrw scause, {}", in(reg) mcause);
+            asm!("csrw stval, {}", in(reg) mtval);
+        }
+        // 陷入前的特权级放入SPP，SIE放入SPIE并关闭S态中断，然后在S态从陷入处理函数开始执行
+        let mut status = ctx.mstatus & !(MSTATUS_SPP | MSTATUS_SPIE | MSTATUS_SIE | MSTATUS_MPP);
+        if ctx.mstatus & MSTATUS_MPP != 0 {
+            status |= MSTATUS_SPP;
+        }
+        if ctx.mstatus & MSTATUS_SIE != 0 {
+            status |= MSTATUS_SPIE;
+        }
+        ctx.mstatus = status | MSTATUS_MPP_SUPERVISOR;
+        ctx.mepc = vector;
+        true
+    }
+}
+
+/// 内核处理这个陷入的入口地址；stvec还没有设置时返回None
+pub fn trap_vector(mcause: usize) -> Option<usize> {
+    let bits = stvec::read().bits();
+    let base = bits & !0b11;
+    if base == 0 {
+        return None
+    }
+    // 向量模式下，中断进入base + 4 * 中断编号
+    if bits & 0b11 == 1 && mcause & INTERRUPT_BIT != 0 {
+        Some(base + 4 * (mcause & !INTERRUPT_BIT))
+    } else {
+        Some(base)
+    }
 }
 
 impl Generator for Runtime {
@@ -46,23 +87,176 @@ impl Generator for Runtime {
         let mtval = mtval::read();
         let trap = match mcause::read().cause() {
             Trap::Exception(Exception::SupervisorEnvCall) => MachineTrap::SbiCall(),
-            Trap::Exception(Exception::Breakpoint) => MachineTrap::Breakpoint(),
+            Trap::Exception(Exception::Breakpoint) => MachineTrap::Breakpoint(mtval),
+            Trap::Exception(Exception::InstructionMisaligned) => MachineTrap::InstructionMisaligned(mtval),
+            Trap::Exception(Exception::InstructionFault) => MachineTrap::InstructionAccessFault(mtval),
+            Trap::Exception(Exception::IllegalInstruction) => MachineTrap::IllegalInstruction(mtval),
+            Trap::Exception(Exception::LoadMisaligned) => MachineTrap::LoadMisaligned(mtval),
             Trap::Exception(Exception::LoadFault) => MachineTrap::LoadAccessFault(mtval),
+            Trap::Exception(Exception::StoreMisaligned) => MachineTrap::StoreMisaligned(mtval),
             Trap::Exception(Exception::StoreFault) => MachineTrap::StoreAccessFault(mtval),
-            Trap::Exception(Exception::IllegalInstruction) => MachineTrap::IllegalInstruction(mtval),
-            e => panic!("unhandled exception: {:?}! mtval: {:#x?}, ctx: {:#x?}", e, mtval, self.context)
+            Trap::Exception(Exception::UserEnvCall) => MachineTrap::UserEnvCall(),
+            Trap::Exception(Exception::InstructionPageFault) => MachineTrap::InstructionPageFault(mtval),
+            Trap::Exception(Exception::LoadPageFault) => MachineTrap::LoadPageFault(mtval),
+            Trap::Exception(Exception::StorePageFault) => MachineTrap::StorePageFault(mtval),
+            Trap::Interrupt(Interrupt::SupervisorSoft) => MachineTrap::SupervisorSoft(),
+            Trap::Interrupt(Interrupt::SupervisorTimer) => MachineTrap::SupervisorTimer(),
+            Trap::Interrupt(Interrupt::SupervisorExternal) => MachineTrap::SupervisorExternal(),
+            Trap::Interrupt(Interrupt::MachineSoft) => MachineTrap::MachineSoft(),
+            Trap::Interrupt(Interrupt::MachineTimer) => MachineTrap::MachineTimer(),
+            Trap::Interrupt(Interrupt::MachineExternal) => MachineTrap::ExternalInterrupt(),
+            // M态环境调用不会来自被调试程序，和未定义的原因一样处理
+            _ => MachineTrap::Unknown(mcause::read().bits(), mtval),
         };
         GeneratorState::Yielded(trap)
     }
 }
 
+// mstatus中调试器用到的字段
+pub const MSTATUS_MPP: usize = 0b11 << 11;
+pub const MSTATUS_SUM: usize = 1 << 18;
+pub const MSTATUS_MXR: usize = 1 << 19;
+const MSTATUS_SIE: usize = 1 << 1;
+const MSTATUS_SPIE: usize = 1 << 5;
+const MSTATUS_SPP: usize = 1 << 8;
+pub const MSTATUS_MPP_SUPERVISOR: usize = 0b01 << 11;
+
+fn read_mstatus() -> usize {
+    let bits: usize;
+    unsafe { asm!("csrr {}, mstatus", out(reg) bits) };
+    bits
+}
+
+/// mcause对应的名称，包括中断
+pub fn trap_name(mcause: usize) -> &'static str {
+    if mcause & INTERRUPT_BIT == 0 {
+        return exception_name(mcause)
+    }
+    match mcause & !INTERRUPT_BIT {
+        1 => "supervisor software interrupt",
+        3 => "machine software interrupt",
+        5 => "supervisor timer interrupt",
+        7 => "machine timer interrupt",
+        9 => "supervisor external interrupt",
+        11 => "machine external interrupt",
+        _ => "reserved interrupt",
+    }
+}
+
+/// 异常编号对应的名称
+pub fn exception_name(cause: usize) -> &'static str {
+    match cause {
+        0 => "instruction address misaligned",
+        1 => "instruction access fault",
+        2 => "illegal instruction",
+        3 => "breakpoint",
+        4 => "load address misaligned",
+        5 => "load access fault",
+        6 => "store/AMO address misaligned",
+        7 => "store/AMO access fault",
+        8 => "environment call from U-mode",
+        9 => "environment call from S-mode",
+        11 => "environment call from M-mode",
+        12 => "instruction page fault",
+        13 => "load page fault",
+        15 => "store/AMO page fault",
+        _ => "reserved",
+    }
+}
+
 #[repr(C)]
 pub enum MachineTrap {
     SbiCall(),
-    Breakpoint(),
+    Breakpoint(usize),
+    // 以下异常的参数都是mtval
+    InstructionMisaligned(usize),
+    InstructionAccessFault(usize),
+    IllegalInstruction(usize),
+    LoadMisaligned(usize),
     LoadAccessFault(usize),
+    StoreMisaligned(usize),
     StoreAccessFault(usize),
-    IllegalInstruction(usize),
+    UserEnvCall(),
+    InstructionPageFault(usize),
+    LoadPageFault(usize),
+    StorePageFault(usize),
+    // 没有委托给S态的中断
+    SupervisorSoft(),
+    SupervisorTimer(),
+    SupervisorExternal(),
+    MachineSoft(),
+    MachineTimer(),
+    ExternalInterrupt(),
+    // 未定义的原因，参数为mcause和mtval
+    Unknown(usize, usize),
+}
+
+/// mcause的最高位表示中断
+pub const INTERRUPT_BIT: usize = 1 << (core::mem::size_of::<usize>() * 8 - 1);
+
+impl MachineTrap {
+    /// 是否为内核自己的异常，可以转交给内核处理
+    pub fn is_kernel_exception(&self) -> bool {
+        match self {
+            MachineTrap::InstructionMisaligned(_)
+            | MachineTrap::InstructionAccessFault(_)
+            | MachineTrap::IllegalInstruction(_)
+            | MachineTrap::LoadMisaligned(_)
+            | MachineTrap::LoadAccessFault(_)
+            | MachineTrap::StoreMisaligned(_)
+            | MachineTrap::StoreAccessFault(_)
+            | MachineTrap::UserEnvCall()
+            | MachineTrap::InstructionPageFault(_)
+            | MachineTrap::LoadPageFault(_)
+            | MachineTrap::StorePageFault(_) => true,
+            _ => false,
+        }
+    }
+
+    /// 这个陷入对应的mcause
+    pub fn mcause(&self) -> usize {
+        match self {
+            MachineTrap::InstructionMisaligned(_) => 0,
+            MachineTrap::InstructionAccessFault(_) => 1,
+            MachineTrap::IllegalInstruction(_) => 2,
+            MachineTrap::Breakpoint(_) => 3,
+            MachineTrap::LoadMisaligned(_) => 4,
+            MachineTrap::LoadAccessFault(_) => 5,
+            MachineTrap::StoreMisaligned(_) => 6,
+            MachineTrap::StoreAccessFault(_) => 7,
+            MachineTrap::UserEnvCall() => 8,
+            MachineTrap::SbiCall() => 9,
+            MachineTrap::InstructionPageFault(_) => 12,
+            MachineTrap::LoadPageFault(_) => 13,
+            MachineTrap::StorePageFault(_) => 15,
+            MachineTrap::SupervisorSoft() => INTERRUPT_BIT | 1,
+            MachineTrap::MachineSoft() => INTERRUPT_BIT | 3,
+            MachineTrap::SupervisorTimer() => INTERRUPT_BIT | 5,
+            MachineTrap::MachineTimer() => INTERRUPT_BIT | 7,
+            MachineTrap::SupervisorExternal() => INTERRUPT_BIT | 9,
+            MachineTrap::ExternalInterrupt() => INTERRUPT_BIT | 11,
+            MachineTrap::Unknown(mcause, _) => *mcause,
+        }
+    }
+
+    /// 陷入时的mtval，中断和环境调用为0
+    pub fn mtval(&self) -> usize {
+        match self {
+            MachineTrap::Breakpoint(mtval)
+            | MachineTrap::InstructionMisaligned(mtval)
+            | MachineTrap::InstructionAccessFault(mtval)
+            | MachineTrap::IllegalInstruction(mtval)
+            | MachineTrap::LoadMisaligned(mtval)
+            | MachineTrap::LoadAccessFault(mtval)
+            | MachineTrap::StoreMisaligned(mtval)
+            | MachineTrap::StoreAccessFault(mtval)
+            | MachineTrap::InstructionPageFault(mtval)
+            | MachineTrap::LoadPageFault(mtval)
+            | MachineTrap::StorePageFault(mtval)
+            | MachineTrap::Unknown(_, mtval) => *mtval,
+            _ => 0,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -99,11 +293,32 @@ pub struct KernelContext {
     pub t4: usize,
     pub t5: usize,
     pub t6: usize, // 30
-    pub mstatus: Mstatus, // 31
+    pub mstatus: usize, // 31
     pub mepc: usize, // 32
     pub machine_stack: usize, // 33
 }
 
+impl KernelContext {
+    /// 按编号读取通用寄存器，x0总是0
+    pub fn x(&self, index: usize) -> usize {
+        match index {
+            0 => 0,
+            // ra到t6按编号顺序排列在上下文的开头
+            1..=31 => unsafe { *(self as *const Self as *const usize).add(index - 1) },
+            _ => panic!("invalid register x{}", index),
+        }
+    }
+
+    /// 按编号写入通用寄存器，写入x0会被忽略
+    pub fn set_x(&mut self, index: usize, value: usize) {
+        match index {
+            0 => {}
+            1..=31 => unsafe { *(self as *mut Self as *mut usize).add(index - 1) = value },
+            _ => panic!("invalid register x{}", index),
+        }
+    }
+}
+
 #[naked]
 #[link_section = ".text"]
 unsafe extern "C" fn do_resume(_kernel_context: *mut KernelContext) {
@@ -0,0 +1,369 @@
+// GDB远程串行协议（RSP）
+//
+// 这里只负责包的收发和解析，命令由debug模块执行。调试器和GDB共用同一个串口，
+// 调试器在命令提示符下读到'$'时，就认为连接的是GDB
+use alloc::{format, string::String, vec::Vec};
+use rustsbi::{legacy_stdio_getchar, legacy_stdio_putchar};
+
+// 包的最大长度，在qSupported中告诉GDB
+const PACKET_SIZE: usize = 0x1000;
+
+// x0到x31和pc，编号与GDB的RISC-V寄存器编号相同
+pub const REGISTER_COUNT: usize = 33;
+pub const REGISTER_PC: usize = 32;
+
+pub const SIGINT: u8 = 2;
+pub const SIGILL: u8 = 4;
+pub const SIGTRAP: u8 = 5;
+pub const SIGBUS: u8 = 7;
+pub const SIGSEGV: u8 = 11;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+<architecture>riscv:rv64</architecture>
+<feature name="org.gnu.gdb.riscv.cpu">
+<reg name="zero" bitsize="64" type="int" regnum="0"/>
+<reg name="ra" bitsize="64" type="code_ptr"/>
+<reg name="sp" bitsize="64" type="data_ptr"/>
+<reg name="gp" bitsize="64" type="data_ptr"/>
+<reg name="tp" bitsize="64" type="data_ptr"/>
+<reg name="t0" bitsize="64" type="int"/>
+<reg name="t1" bitsize="64" type="int"/>
+<reg name="t2" bitsize="64" type="int"/>
+<reg name="fp" bitsize="64" type="data_ptr"/>
+<reg name="s1" bitsize="64" type="int"/>
+<reg name="a0" bitsize="64" type="int"/>
+<reg name="a1" bitsize="64" type="int"/>
+<reg name="a2" bitsize="64" type="int"/>
+<reg name="a3" bitsize="64" type="int"/>
+<reg name="a4" bitsize="64" type="int"/>
+<reg name="a5" bitsize="64" type="int"/>
+<reg name="a6" bitsize="64" type="int"/>
+<reg name="a7" bitsize="64" type="int"/>
+<reg name="s2" bitsize="64" type="int"/>
+<reg name="s3" bitsize="64" type="int"/>
+<reg name="s4" bitsize="64" type="int"/>
+<reg name="s5" bitsize="64" type="int"/>
+<reg name="s6" bitsize="64" type="int"/>
+<reg name="s7" bitsize="64" type="int"/>
+<reg name="s8" bitsize="64" type="int"/>
+<reg name="s9" bitsize="64" type="int"/>
+<reg name="s10" bitsize="64" type="int"/>
+<reg name="s11" bitsize="64" type="int"/>
+<reg name="t3" bitsize="64" type="int"/>
+<reg name="t4" bitsize="64" type="int"/>
+<reg name="t5" bitsize="64" type="int"/>
+<reg name="t6" bitsize="64" type="int"/>
+<reg name="pc" bitsize="64" type="code_ptr"/>
+</feature>
+</target>
+"#;
+
+/// 断点和观察点的种类，即Z和z包的第一个参数
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BreakpointType {
+    Software,
+    Hardware,
+    Write,
+    Read,
+    Access,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Command {
+    // qSupported
+    Supported,
+    // ?
+    StopReason,
+    // g
+    ReadRegisters,
+    // G，所有寄存器的原始字节
+    WriteRegisters(Vec<u8>),
+    // p
+    ReadRegister(usize),
+    // P
+    WriteRegister(usize, usize),
+    // m 地址,长度
+    ReadMemory(usize, usize),
+    // M和X
+    WriteMemory(usize, Vec<u8>),
+    // c、C和vCont;c，可以指定继续运行的地址
+    Continue(Option<usize>),
+    // s、S和vCont;s
+    Step(Option<usize>),
+    // Z 种类,地址,长度
+    InsertBreakpoint(BreakpointType, usize, usize),
+    // z 种类,地址,长度
+    RemoveBreakpoint(BreakpointType, usize, usize),
+    // qXfer:features:read:target.xml:偏移,长度
+    Features(usize, usize),
+    // D
+    Detach,
+    // k
+    Kill,
+    // 不需要处理、直接回复的包
+    Reply(&'static str),
+    // 不支持的包，回复空包
+    Unsupported,
+}
+
+/// 读取一个包，校验和正确时回复'+'
+///
+/// `started`不为None时，表示'$'和包开头的几个字节已经被读走了
+pub fn receive(started: Option<&[u8]>) -> Vec<u8> {
+    let mut started = started;
+    loop {
+        let mut data = match started.take() {
+            Some(prefix) => prefix.to_vec(),
+            None => {
+                while legacy_stdio_getchar() != b'$' {}
+                Vec::new()
+            }
+        };
+        loop {
+            match legacy_stdio_getchar() {
+                b'#' => break,
+                // 新的包开始了，丢弃之前读到的内容
+                b'$' => data.clear(),
+                ch if data.len() < PACKET_SIZE => data.push(ch),
+                _ => {}
+            }
+        }
+        let high = hex_value(legacy_stdio_getchar());
+        let low = hex_value(legacy_stdio_getchar());
+        let checksum = data.iter().fold(0u8, |sum, &ch| sum.wrapping_add(ch));
+        if let (Some(high), Some(low)) = (high, low) {
+            if (high << 4) | low == checksum {
+                legacy_stdio_putchar(b'+');
+                return data
+            }
+        }
+        legacy_stdio_putchar(b'-');
+    }
+}
+
+/// 发送一个包，直到GDB回复'+'
+pub fn send(data: &[u8]) {
+    let checksum = data.iter().fold(0u8, |sum, &ch| sum.wrapping_add(ch));
+    loop {
+        legacy_stdio_putchar(b'$');
+        for &ch in data {
+            legacy_stdio_putchar(ch);
+        }
+        legacy_stdio_putchar(b'#');
+        for &ch in format!("{:02x}", checksum).as_bytes() {
+            legacy_stdio_putchar(ch);
+        }
+        loop {
+            match legacy_stdio_getchar() {
+                b'+' => return,
+                b'-' => break,
+                _ => {}
+            }
+        }
+    }
+}
+
+pub fn parse(packet: &[u8]) -> Command {
+    let (&head, body) = match packet.split_first() {
+        Some(split) => split,
+        None => return Command::Unsupported,
+    };
+    let parsed = match head {
+        b'?' => Some(Command::StopReason),
+        b'g' => Some(Command::ReadRegisters),
+        b'G' => decode_hex(body).map(Command::WriteRegisters),
+        b'p' => parse_hex(body).map(Command::ReadRegister),
+        b'P' => split_once(body, b'=').and_then(|(index, value)| {
+            Some(Command::WriteRegister(parse_hex(index)?, decode_register(value)?))
+        }),
+        b'm' => split_once(body, b',').and_then(|(address, len)| {
+            Some(Command::ReadMemory(parse_hex(address)?, parse_hex(len)?))
+        }),
+        b'M' => split_once(body, b':').and_then(|(range, data)| {
+            let (address, _) = split_once(range, b',')?;
+            Some(Command::WriteMemory(parse_hex(address)?, decode_hex(data)?))
+        }),
+        b'X' => split_once(body, b':').and_then(|(range, data)| {
+            let (address, _) = split_once(range, b',')?;
+            Some(Command::WriteMemory(parse_hex(address)?, unescape(data)))
+        }),
+        b'c' => Some(Command::Continue(parse_hex(body))),
+        b's' => Some(Command::Step(parse_hex(body))),
+        // 忽略C和S中的信号
+        b'C' => Some(Command::Continue(split_once(body, b';').and_then(|(_, address)| parse_hex(address)))),
+        b'S' => Some(Command::Step(split_once(body, b';').and_then(|(_, address)| parse_hex(address)))),
+        b'Z' | b'z' => parse_breakpoint(body).map(|(ty, address, kind)| {
+            if head == b'Z' {
+                Command::InsertBreakpoint(ty, address, kind)
+            } else {
+                Command::RemoveBreakpoint(ty, address, kind)
+            }
+        }),
+        b'D' => Some(Command::Detach),
+        b'k' => Some(Command::Kill),
+        // 只有一个线程，设置线程和查询线程是否存活都回复OK
+        b'H' | b'T' => Some(Command::Reply("OK")),
+        b'q' => parse_query(body),
+        b'v' => parse_v(body),
+        _ => None,
+    };
+    parsed.unwrap_or(Command::Unsupported)
+}
+
+fn parse_query(body: &[u8]) -> Option<Command> {
+    if body.starts_with(b"Supported") {
+        return Some(Command::Supported)
+    }
+    if let Some(range) = strip_prefix(body, b"Xfer:features:read:target.xml:") {
+        let (offset, len) = split_once(range, b',')?;
+        return Some(Command::Features(parse_hex(offset)?, parse_hex(len)?))
+    }
+    match body {
+        b"Attached" => Some(Command::Reply("1")),
+        b"C" => Some(Command::Reply("QC1")),
+        b"fThreadInfo" => Some(Command::Reply("m1")),
+        b"sThreadInfo" => Some(Command::Reply("l")),
+        b"Symbol::" => Some(Command::Reply("OK")),
+        _ => None,
+    }
+}
+
+// vCont;动作[:线程];动作...，只有一个线程，按第一个动作执行
+fn parse_v(body: &[u8]) -> Option<Command> {
+    if body == b"Cont?" {
+        return Some(Command::Reply("vCont;c;C;s;S"))
+    }
+    let actions = strip_prefix(body, b"Cont;")?;
+    match actions.first() {
+        Some(b's') | Some(b'S') => Some(Command::Step(None)),
+        Some(b'c') | Some(b'C') => Some(Command::Continue(None)),
+        _ => None,
+    }
+}
+
+fn parse_breakpoint(body: &[u8]) -> Option<(BreakpointType, usize, usize)> {
+    let mut fields = body.split(|&ch| ch == b',');
+    let ty = match fields.next()? {
+        b"0" => BreakpointType::Software,
+        b"1" => BreakpointType::Hardware,
+        b"2" => BreakpointType::Write,
+        b"3" => BreakpointType::Read,
+        b"4" => BreakpointType::Access,
+        _ => return None,
+    };
+    let address = parse_hex(fields.next()?)?;
+    // 长度后面可能还有条件表达式，忽略
+    let kind = fields.next()?;
+    let kind = parse_hex(split_once(kind, b';').map(|(kind, _)| kind).unwrap_or(kind))?;
+    Some((ty, address, kind))
+}
+
+/// 停止原因；`watch`为触发的观察点种类（watch、rwatch或awatch）和地址
+pub fn stop_reply(signal: u8, watch: Option<(&str, usize)>) -> String {
+    match watch {
+        Some((kind, address)) => format!("T{:02x}{}:{:x};", signal, kind, address),
+        None => format!("T{:02x}", signal),
+    }
+}
+
+/// 异常对应的信号
+pub fn fault_signal(mcause: usize) -> u8 {
+    match mcause {
+        // 地址不对齐
+        0 | 4 | 6 => SIGBUS,
+        2 => SIGILL,
+        // 访问错误和页错误
+        1 | 5 | 7 | 12 | 13 | 15 => SIGSEGV,
+        _ => SIGTRAP,
+    }
+}
+
+pub fn supported() -> String {
+    format!("PacketSize={:x};qXfer:features:read+;vContSupported+", PACKET_SIZE)
+}
+
+/// 目标描述的一段，前缀'm'表示后面还有内容，'l'表示已经结束
+pub fn features(offset: usize, len: usize) -> Vec<u8> {
+    let xml = TARGET_XML.as_bytes();
+    let start = core::cmp::min(offset, xml.len());
+    let end = core::cmp::min(start.saturating_add(len), xml.len());
+    let mut ans = Vec::with_capacity(end - start + 1);
+    ans.push(if end < xml.len() { b'm' } else { b'l' });
+    ans.extend_from_slice(&xml[start..end]);
+    ans
+}
+
+/// 寄存器的值按小端序编码
+pub fn encode_register(value: usize) -> String {
+    encode_hex(&value.to_le_bytes())
+}
+
+pub fn decode_register(text: &[u8]) -> Option<usize> {
+    let bytes = decode_hex(text)?;
+    if bytes.len() > core::mem::size_of::<usize>() {
+        return None
+    }
+    Some(bytes.iter().rev().fold(0, |value, &byte| (value << 8) | byte as usize))
+}
+
+pub fn encode_hex(bytes: &[u8]) -> String {
+    let mut ans = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        ans.push_str(&format!("{:02x}", byte));
+    }
+    ans
+}
+
+fn decode_hex(text: &[u8]) -> Option<Vec<u8>> {
+    if text.len() % 2 != 0 {
+        return None
+    }
+    text.chunks(2).map(|pair| Some((hex_value(pair[0])? << 4) | hex_value(pair[1])?)).collect()
+}
+
+fn parse_hex(text: &[u8]) -> Option<usize> {
+    if text.is_empty() || text.len() > core::mem::size_of::<usize>() * 2 {
+        return None
+    }
+    text.iter().try_fold(0, |value, &ch| Some((value << 4) | hex_value(ch)? as usize))
+}
+
+fn hex_value(ch: u8) -> Option<u8> {
+    match ch {
+        b'0'..=b'9' => Some(ch - b'0'),
+        b'a'..=b'f' => Some(ch - b'a' + 10),
+        b'A'..=b'F' => Some(ch - b'A' + 10),
+        _ => None,
+    }
+}
+
+// X包中的二进制数据，'}'之后的字节与0x20异或
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut ans = Vec::with_capacity(data.len());
+    let mut iter = data.iter();
+    while let Some(&ch) = iter.next() {
+        if ch == b'}' {
+            if let Some(&next) = iter.next() {
+                ans.push(next ^ 0x20);
+            }
+        } else {
+            ans.push(ch);
+        }
+    }
+    ans
+}
+
+fn split_once(text: &[u8], separator: u8) -> Option<(&[u8], &[u8])> {
+    let index = text.iter().position(|&ch| ch == separator)?;
+    Some((&text[..index], &text[index + 1..]))
+}
+
+fn strip_prefix<'a>(text: &'a [u8], prefix: &[u8]) -> Option<&'a [u8]> {
+    if text.starts_with(prefix) {
+        Some(&text[prefix.len()..])
+    } else {
+        None
+    }
+}
@@ -0,0 +1,138 @@
+// 多个硬件线程的全部停止（all-stop）调试
+//
+// 一个硬件线程进入调试器时，先取得调试器，再用M态软件中断让其它硬件线程停在M态，交出各自的上下文；
+// 调试器让被调试程序继续运行时，停下的硬件线程一起返回内核
+use crate::executor::KernelContext;
+use crate::ipi::{self, MAX_HARTS};
+use crate::{hsm, platform, pmu, watchpoint};
+use riscv::register::{mhartid, satp};
+use rustsbi::println;
+use spin::Mutex;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+#[derive(Clone, Copy)]
+struct Stopped {
+    // 停在M态的硬件线程在调试器继续运行前不会返回内核，调试器可以读写它的上下文
+    ctx: *mut KernelContext,
+    // 每个硬件线程有自己的页表，调试器翻译这个硬件线程的地址时使用
+    satp: usize,
+}
+
+// 上下文只在硬件线程停下时被调试器访问
+unsafe impl Send for Stopped {}
+
+struct Session {
+    // 正在使用调试器的硬件线程
+    owner: Option<usize>,
+    // 要求停下、还没有停下的硬件线程，每个硬件线程一位
+    requests: usize,
+    stopped: [Option<Stopped>; MAX_HARTS],
+}
+
+static SESSION: Mutex<Session> = Mutex::new(Session { owner: None, requests: 0, stopped: [None; MAX_HARTS] });
+// 停下的硬件线程返回内核时加一；停下的硬件线程只读这个值，不占用锁
+static GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+/// 进入调试器：等其它硬件线程用完调试器，然后让其它还在运行的硬件线程停下
+pub fn enter(ctx: &mut KernelContext) {
+    let hart_id = mhartid::read();
+    loop {
+        {
+            let mut session = SESSION.lock();
+            if session.owner.is_none() {
+                session.owner = Some(hart_id);
+                session.stopped[hart_id] = Some(Stopped { ctx, satp: satp::read().bits() });
+                break
+            }
+        }
+        // 使用调试器的硬件线程可能正在等这个硬件线程停下
+        poll(ctx);
+    }
+    pmu::pause();
+    let targets = hsm::interruptible_harts() & !stopped_harts();
+    if targets == 0 {
+        return
+    }
+    SESSION.lock().requests = targets;
+    for target in harts(targets) {
+        crate::CLINT.send_soft(target);
+    }
+    let deadline = crate::CLINT.mtime() + platform::get().timebase_frequency / 10;
+    while SESSION.lock().requests != 0 && crate::CLINT.mtime() < deadline {
+        // 其它硬件线程可能在等这个硬件线程完成远程栅栏，完成后才能停下
+        ipi::handle_soft_interrupt();
+    }
+    let missing = core::mem::replace(&mut SESSION.lock().requests, 0);
+    for target in harts(missing) {
+        println!("[DebugSBI] Hart {} did not stop, it keeps running", target);
+    }
+}
+
+/// 离开调试器；release为false时，其它停下的硬件线程继续停着，等这个硬件线程下一次进入调试器
+pub fn leave(release: bool) {
+    let hart_id = mhartid::read();
+    pmu::resume();
+    let mut session = SESSION.lock();
+    session.owner = None;
+    session.stopped[hart_id] = None;
+    if release {
+        session.requests = 0;
+        session.stopped = [None; MAX_HARTS];
+        GENERATION.fetch_add(1, Ordering::Release);
+    }
+}
+
+/// 调试器要求这个硬件线程停下时，停在M态直到调试器让所有硬件线程继续运行；每次返回内核前调用
+pub fn poll(ctx: &mut KernelContext) {
+    stop_if_requested(ctx);
+    // 调试器可能在其它硬件线程上修改了观察点
+    watchpoint::sync();
+}
+
+fn stop_if_requested(ctx: &mut KernelContext) {
+    let hart_id = mhartid::read();
+    let generation = {
+        let mut session = SESSION.lock();
+        if (session.requests >> hart_id) & 1 == 0 {
+            return
+        }
+        session.requests &= !(1 << hart_id);
+        session.stopped[hart_id] = Some(Stopped { ctx, satp: satp::read().bits() });
+        GENERATION.load(Ordering::Acquire)
+    };
+    pmu::pause();
+    while GENERATION.load(Ordering::Acquire) == generation {}
+    pmu::resume();
+    // 停下期间调试器插入或删除了断点，也没有处理远程栅栏请求
+    unsafe { asm!("fence.i", "sfence.vma") };
+}
+
+/// 是否有硬件线程正在使用调试器
+pub fn debugger_active() -> bool {
+    SESSION.lock().owner.is_some()
+}
+
+/// 停在调试器中的硬件线程，包括正在使用调试器的硬件线程，每个硬件线程一位
+pub fn stopped_harts() -> usize {
+    let session = SESSION.lock();
+    (0..MAX_HARTS).filter(|&i| session.stopped[i].is_some())
+        .fold(0, |mask, i| mask | (1 << i))
+}
+
+/// 停下的硬件线程的上下文；调用者保证只在调试器中使用
+pub fn context(hart_id: usize) -> Option<*mut KernelContext> {
+    let session = SESSION.lock();
+    session.stopped.get(hart_id).and_then(|stopped| stopped.map(|stopped| stopped.ctx))
+}
+
+/// 停下的硬件线程进入M态时的satp，按上下文查找
+pub fn satp_of(ctx: &KernelContext) -> Option<usize> {
+    let session = SESSION.lock();
+    session.stopped.iter().flatten()
+        .find(|stopped| stopped.ctx as *const KernelContext == ctx as *const KernelContext)
+        .map(|stopped| stopped.satp)
+}
+
+fn harts(mask: usize) -> impl Iterator<Item = usize> {
+    (0..MAX_HARTS).filter(move |i| (mask >> i) & 1 != 0)
+}
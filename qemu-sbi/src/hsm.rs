@@ -0,0 +1,183 @@
+// SBI的硬件线程状态管理（HSM）扩展
+//
+// 只有启动硬件线程直接进入内核，其它硬件线程在M态用wfi等待，内核调用hart_start后才进入内核。
+// 硬件线程的状态按SBI规范中的状态机变化。停止和非保持挂起不能从SBI调用返回，
+// 由rust_main在调用结束后检查take_pending，换成新的上下文再进入内核
+use crate::error::{self, SBI_ERR_ALREADY_AVAILABLE, SBI_ERR_FAILED, SBI_ERR_INVALID_ADDRESS, SBI_ERR_INVALID_PARAM, SBI_ERR_NOT_SUPPORTED};
+use crate::ipi::MAX_HARTS;
+use crate::platform;
+use core::sync::atomic::Ordering;
+use riscv::register::{mhartid, mie, mip};
+use rustsbi::SbiRet;
+use spin::Mutex;
+
+// 挂起类型
+const SUSPEND_RETENTIVE: u32 = 0x0000_0000;
+const SUSPEND_NON_RETENTIVE: u32 = 0x8000_0000;
+
+/// SBI规范中的硬件线程状态，值为hart_get_status的返回值
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    Started = 0,
+    Stopped = 1,
+    StartPending = 2,
+    StopPending = 3,
+    Suspended = 4,
+    SuspendPending = 5,
+    ResumePending = 6,
+}
+
+#[derive(Clone, Copy)]
+struct Hart {
+    status: Status,
+    // 下次进入内核的地址和a1参数
+    entry: Option<(usize, usize)>,
+}
+
+static HARTS: Mutex<[Hart; MAX_HARTS]> = Mutex::new([Hart { status: Status::Stopped, entry: None }; MAX_HARTS]);
+
+pub struct Hsm;
+
+impl rustsbi::Hsm for Hsm {
+    fn hart_start(&self, hartid: usize, start_addr: usize, opaque: usize) -> SbiRet {
+        if !exists(hartid) {
+            return error::error(SBI_ERR_INVALID_PARAM)
+        }
+        if !valid_entry(start_addr) {
+            return error::error(SBI_ERR_INVALID_ADDRESS)
+        }
+        {
+            let mut harts = HARTS.lock();
+            if harts[hartid].status != Status::Stopped {
+                return error::error(SBI_ERR_ALREADY_AVAILABLE)
+            }
+            harts[hartid] = Hart { status: Status::StartPending, entry: Some((start_addr, opaque)) };
+        }
+        crate::CLINT.send_soft(hartid);
+        error::ok(0)
+    }
+
+    // 只能停止发起调用的硬件线程，参数不可信；只有已启动的硬件线程可以停止
+    fn hart_stop(&self, _hartid: usize) -> SbiRet {
+        let hart_id = mhartid::read();
+        let mut harts = HARTS.lock();
+        match harts.get_mut(hart_id) {
+            Some(hart) if hart.status == Status::Started => {
+                hart.status = Status::StopPending;
+                error::ok(0)
+            }
+            _ => error::error(SBI_ERR_FAILED),
+        }
+    }
+
+    fn hart_get_status(&self, hartid: usize) -> SbiRet {
+        if !exists(hartid) {
+            return error::error(SBI_ERR_INVALID_PARAM)
+        }
+        error::ok(status(hartid) as usize)
+    }
+
+    fn hart_suspend(&self, suspend_type: u32, resume_addr: usize, opaque: usize) -> SbiRet {
+        let hart_id = mhartid::read();
+        match suspend_type {
+            // 保持挂起：等到有中断，然后从SBI调用返回
+            SUSPEND_RETENTIVE => {
+                set_status(hart_id, Status::Suspended);
+                wait_for_interrupt();
+                set_status(hart_id, Status::Started);
+                error::ok(0)
+            }
+            SUSPEND_NON_RETENTIVE if !valid_entry(resume_addr) => error::error(SBI_ERR_INVALID_ADDRESS),
+            SUSPEND_NON_RETENTIVE => {
+                HARTS.lock()[hart_id] = Hart { status: Status::SuspendPending, entry: Some((resume_addr, opaque)) };
+                error::ok(0)
+            }
+            // 平台自定义的挂起类型
+            0x1000_0000..=0x7fff_ffff | 0x9000_0000..=0xffff_ffff => error::error(SBI_ERR_NOT_SUPPORTED),
+            _ => error::error(SBI_ERR_INVALID_PARAM),
+        }
+    }
+}
+
+// 进入过调试大师的硬件线程；设备树中列出了硬件线程时，还要在设备树中
+fn exists(hart_id: usize) -> bool {
+    let listed = platform::get().harts;
+    hart_id < MAX_HARTS && (crate::ONLINE_HARTS.load(Ordering::Relaxed) >> hart_id) & 1 != 0
+        && (listed == 0 || (listed >> hart_id) & 1 != 0)
+}
+
+// 内核的入口要在内存中，不能在调试大师自己的内存中，并且按2字节对齐
+fn valid_entry(addr: usize) -> bool {
+    let (memory_base, memory_size) = platform::get().memory;
+    let (firmware_base, firmware_size) = crate::firmware_region();
+    addr % 2 == 0 && addr.wrapping_sub(memory_base) < memory_size
+        && addr.wrapping_sub(firmware_base) >= firmware_size
+}
+
+/// 硬件线程的状态；不存在的硬件线程按停止处理
+pub fn status(hart_id: usize) -> Status {
+    HARTS.lock().get(hart_id).map_or(Status::Stopped, |hart| hart.status)
+}
+
+fn set_status(hart_id: usize, status: Status) {
+    if let Some(hart) = HARTS.lock().get_mut(hart_id) {
+        hart.status = status;
+    }
+}
+
+/// 可以接收核间中断的硬件线程，每个硬件线程一位
+pub fn interruptible_harts() -> usize {
+    let harts = HARTS.lock();
+    (0..MAX_HARTS).filter(|&i| !matches!(harts[i].status, Status::Stopped | Status::StartPending))
+        .fold(0, |mask, i| mask | (1 << i))
+}
+
+/// 启动硬件线程直接进入内核
+pub fn boot(hart_id: usize) {
+    set_status(hart_id, Status::Started);
+}
+
+/// 在M态等待内核调用hart_start，返回进入内核的地址和a1参数
+pub fn park(hart_id: usize) -> (usize, usize) {
+    loop {
+        {
+            let mut harts = HARTS.lock();
+            if harts[hart_id].status == Status::StartPending {
+                harts[hart_id].status = Status::Started;
+                if let Some(entry) = harts[hart_id].entry.take() {
+                    drop(harts);
+                    // 停止期间没有处理远程栅栏请求
+                    unsafe { asm!("fence.i", "sfence.vma") };
+                    return entry
+                }
+            }
+        }
+        unsafe { asm!("wfi") };
+        crate::CLINT.clear_soft(hart_id);
+    }
+}
+
+/// SBI调用结束后，检查这个硬件线程是否要停止或非保持挂起；返回再次进入内核的地址和a1参数
+pub fn take_pending(hart_id: usize) -> Option<(usize, usize)> {
+    match status(hart_id) {
+        Status::StopPending => {
+            set_status(hart_id, Status::Stopped);
+            Some(park(hart_id))
+        }
+        Status::SuspendPending => {
+            set_status(hart_id, Status::Suspended);
+            wait_for_interrupt();
+            let mut harts = HARTS.lock();
+            harts[hart_id].status = Status::Started;
+            harts[hart_id].entry.take()
+        }
+        _ => None,
+    }
+}
+
+// 等到有打开的中断待处理；M态中断是关闭的，中断在返回内核后才处理
+fn wait_for_interrupt() {
+    while mip::read().bits() & mie::read().bits() == 0 {
+        unsafe { asm!("wfi") };
+    }
+}
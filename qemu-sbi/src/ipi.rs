@@ -0,0 +1,162 @@
+// SBI的核间中断（IPI）和远程栅栏（RFENCE）扩展
+//
+// 每个硬件线程有一个邮箱。发送方把请求放进目标硬件线程的邮箱，再通过CLINT的msip触发M态软件中断；
+// 目标硬件线程在M态处理邮箱中的请求，然后才返回S态。远程栅栏要等目标硬件线程执行完成后才返回
+use crate::error::{self, SBI_ERR_INVALID_PARAM};
+use crate::hsm;
+use crate::pmu::{self, Event};
+use crate::reset;
+use riscv::register::{mhartid, mip};
+use rustsbi::{HartMask, SbiRet};
+use spin::Mutex;
+use core::sync::atomic::Ordering;
+
+pub const MAX_HARTS: usize = 8;
+
+// 超过这么多页时，直接刷新整个地址空间
+const SFENCE_PAGE_LIMIT: usize = 64;
+const PAGE_SIZE: usize = 0x1000;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Sfence {
+    start: usize,
+    size: usize,
+    // None表示所有地址空间
+    asid: Option<usize>,
+}
+
+// 刷新所有地址空间的所有地址
+const SFENCE_ALL: Sfence = Sfence { start: 0, size: usize::MAX, asid: None };
+
+#[derive(Clone, Copy)]
+struct Mailbox {
+    // 内核发送的核间中断，转发为S态软件中断
+    soft: bool,
+    fence_i: bool,
+    sfence: Option<Sfence>,
+    // 其它硬件线程正在复位系统
+    reset: bool,
+}
+
+const EMPTY: Mailbox = Mailbox { soft: false, fence_i: false, sfence: None, reset: false };
+
+static MAILBOXES: Mutex<[Mailbox; MAX_HARTS]> = Mutex::new([EMPTY; MAX_HARTS]);
+
+pub struct Ipi;
+
+impl rustsbi::Ipi for Ipi {
+    fn max_hart_id(&self) -> usize {
+        MAX_HARTS - 1
+    }
+
+    fn send_ipi_many(&self, hart_mask: HartMask) -> SbiRet {
+        send(&hart_mask, |mailbox| mailbox.soft = true, false)
+    }
+}
+
+pub struct Rfence;
+
+impl rustsbi::Rfence for Rfence {
+    fn remote_fence_i(&self, hart_mask: HartMask) -> SbiRet {
+        send(&hart_mask, |mailbox| mailbox.fence_i = true, true)
+    }
+
+    fn remote_sfence_vma(&self, hart_mask: HartMask, start_addr: usize, size: usize) -> SbiRet {
+        let request = Sfence { start: start_addr, size, asid: None };
+        send(&hart_mask, |mailbox| add_sfence(mailbox, request), true)
+    }
+
+    fn remote_sfence_vma_asid(&self, hart_mask: HartMask, start_addr: usize, size: usize, asid: usize) -> SbiRet {
+        let request = Sfence { start: start_addr, size, asid: Some(asid) };
+        send(&hart_mask, |mailbox| add_sfence(mailbox, request), true)
+    }
+}
+
+// 多个刷新请求合并为刷新全部
+fn add_sfence(mailbox: &mut Mailbox, request: Sfence) {
+    mailbox.sfence = match mailbox.sfence {
+        Some(pending) if pending != request => Some(SFENCE_ALL),
+        _ => Some(request),
+    };
+}
+
+// 把请求放进hart_mask中每个硬件线程的邮箱；wait为true时等待它们处理完成
+fn send<F: Fn(&mut Mailbox)>(hart_mask: &HartMask, request: F, wait: bool) -> SbiRet {
+    let online = crate::ONLINE_HARTS.load(Ordering::Relaxed);
+    let targets: usize = (0..MAX_HARTS).filter(|&hart_id| hart_mask.has_bit(hart_id))
+        .fold(0, |targets, hart_id| targets | (1 << hart_id));
+    if targets & !online != 0 {
+        return error::error(SBI_ERR_INVALID_PARAM)
+    }
+    // 停止的硬件线程不接收请求，启动时会执行fence.i和sfence.vma
+    let targets = targets & hsm::interruptible_harts();
+    post(targets, request);
+    if wait {
+        // 等待时M态中断是关闭的，先处理自己的邮箱，避免两个硬件线程互相等待
+        while (0..MAX_HARTS).any(|i| (targets >> i) & 1 != 0 && busy(i)) {
+            handle_soft_interrupt();
+        }
+    }
+    error::ok(0)
+}
+
+/// 让targets中的硬件线程停在M态，等待复位
+pub fn request_reset(targets: usize) {
+    post(targets, |mailbox| mailbox.reset = true);
+}
+
+fn post<F: Fn(&mut Mailbox)>(targets: usize, request: F) {
+    let mut mailboxes = MAILBOXES.lock();
+    for hart_id in (0..MAX_HARTS).filter(|i| (targets >> i) & 1 != 0) {
+        request(&mut mailboxes[hart_id]);
+        crate::CLINT.send_soft(hart_id);
+    }
+}
+
+// 邮箱中还有没完成的栅栏请求
+fn busy(hart_id: usize) -> bool {
+    let mailbox = MAILBOXES.lock()[hart_id];
+    mailbox.fence_i || mailbox.sfence.is_some()
+}
+
+/// 处理这个硬件线程的M态软件中断，执行邮箱中的请求
+pub fn handle_soft_interrupt() {
+    let hart_id = mhartid::read();
+    // 先清除msip，之后放入邮箱的请求会再次触发中断
+    crate::CLINT.clear_soft(hart_id);
+    let mailbox = core::mem::replace(&mut MAILBOXES.lock()[hart_id], EMPTY);
+    if mailbox.reset {
+        reset::quiesce();
+    }
+    if mailbox.fence_i {
+        unsafe { asm!("fence.i") };
+        pmu::record(Event::FenceIReceived);
+    }
+    if let Some(request) = mailbox.sfence {
+        sfence_vma(request);
+        pmu::record(if request.asid.is_some() { Event::SfenceVmaAsidReceived } else { Event::SfenceVmaReceived });
+    }
+    if mailbox.soft {
+        unsafe { mip::set_ssoft() };
+        pmu::record(Event::IpiReceived);
+    }
+}
+
+fn sfence_vma(request: Sfence) {
+    // [start, start + size)涉及的页数，start不一定按页对齐
+    let base = request.start & !(PAGE_SIZE - 1);
+    let pages = (request.start % PAGE_SIZE).saturating_add(request.size).saturating_add(PAGE_SIZE - 1) / PAGE_SIZE;
+    let all = (request.start == 0 && request.size == 0) || request.size == usize::MAX || pages > SFENCE_PAGE_LIMIT;
+    unsafe {
+        match (all, request.asid) {
+            (true, None) => asm!("sfence.vma"),
+            (true, Some(asid)) => asm!("sfence.vma x0, {}", in(reg) asid),
+            (false, None) => for page in 0..pages {
+                asm!("sfence.vma {}", in(reg) base.wrapping_add(page * PAGE_SIZE));
+            },
+            (false, Some(asid)) => for page in 0..pages {
+                asm!("sfence.vma {}, {}", in(reg) base.wrapping_add(page * PAGE_SIZE), in(reg) asid);
+            },
+        }
+    }
+}
@@ -7,69 +7,304 @@
 
 extern crate alloc;
 
+// 寄存器编号只能写在指令中时，对范围内的每个编号展开一个match分支，分支中$n为类型为$ty的编号常量。
+// 浮点寄存器使用0..32，mhpmcounter和mhpmevent使用3..32
+macro_rules! register_match {
+    (@ $index: expr, $n: ident: $ty: ty => $body: expr; $($i: literal)*) => {
+        match $index {
+            $($i => { const $n: $ty = $i; $body })*
+            _ => unreachable!()
+        }
+    };
+    (0..32, $index: expr, $n: ident: $ty: ty => $body: expr) => {
+        register_match!(@ $index, $n: $ty => $body;
+            0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16 17 18 19 20 21 22 23 24 25 26 27 28 29 30 31)
+    };
+    (3..32, $index: expr, $n: ident: $ty: ty => $body: expr) => {
+        register_match!(@ $index, $n: $ty => $body;
+            3 4 5 6 7 8 9 10 11 12 13 14 15 16 17 18 19 20 21 22 23 24 25 26 27 28 29 30 31)
+    };
+}
+
 mod executor;
 mod reset;
 mod uart;
 mod debug;
+mod probe;
+mod breakpoint;
+mod trigger;
+mod step;
+mod watchpoint;
+mod plic;
+mod gdbstub;
+mod delegation;
+mod clint;
+mod emulate;
+mod ipi;
+mod hsm;
+mod error;
+mod halt;
+mod console;
+mod tiaoshi;
+mod pmu;
+mod platform;
 
 use core::pin::Pin;
 use core::ops::{Generator, GeneratorState};
 use core::panic::PanicInfo;
 use executor::{Runtime, MachineTrap};
+use tiaoshi_dashi_decode::{disasm, fdt, paging};
 use linked_list_allocator::LockedHeap;
 use rustsbi::println;
 use alloc::alloc::Layout;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 const STACK_SIZE: usize = 0x10000 * 8;
+const BOOT_HART: usize = 0;
+// 内核放在内存开头之后2MiB的位置，前面是调试大师自己
+const KERNEL_OFFSET: usize = 0x20_0000;
 const HEAP_SIZE: usize = 0x10000;
+const PAGE_SIZE: usize = 0x1000;
 
 static mut MACHINE_HEAP: [u8; HEAP_SIZE] = [0; HEAP_SIZE];
 #[link_section = ".bss.stack"]
 static mut MACHINE_STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
 #[global_allocator]
 static ALLOCATOR: LockedHeap = LockedHeap::empty();
+// 地址由启动硬件线程根据设备树设置
+static PLIC: plic::Plic = plic::Plic::new(0);
+static CLINT: clint::Clint = clint::Clint::new(0);
+// 存在的硬件线程，即进入过调试大师的硬件线程，每个硬件线程一位
+static ONLINE_HARTS: AtomicUsize = AtomicUsize::new(0);
+// 启动硬件线程已经解析了设备树，其它硬件线程可以使用CLINT了
+static PLATFORM_READY: AtomicBool = AtomicBool::new(false);
+// 已经警告过硬件线程编号太大
+static UNSUPPORTED_WARNED: AtomicBool = AtomicBool::new(false);
 
 
 fn rust_main(mhartid: usize, opaque: usize) -> ! { 
-    if mhartid == 0 {
-        first_hart_init();
+    // 每个硬件线程的表只有MAX_HARTS项，编号更大的硬件线程不进入内核
+    if mhartid >= ipi::MAX_HARTS {
+        unsupported_hart(mhartid);
+    }
+    ONLINE_HARTS.fetch_or(1 << mhartid, Ordering::Relaxed);
+    if mhartid == BOOT_HART {
+        first_hart_init(opaque);
+        PLATFORM_READY.store(true, Ordering::Release);
+    } else {
+        while !PLATFORM_READY.load(Ordering::Acquire) {
+            core::hint::spin_loop();
+        }
     }
-    let addr = 0x8020_0000;
     executor::init();
+    // 委托是每个硬件线程自己的设置
+    delegation::init();
+    // 接收其它硬件线程的核间中断和远程栅栏请求，停止的硬件线程也用它唤醒
+    unsafe { riscv::register::mie::set_msoft() };
+    // 只有启动硬件线程直接进入内核，其它硬件线程等待内核调用hart_start
+    let (addr, opaque) = if mhartid == BOOT_HART {
+        hsm::boot(mhartid);
+        (platform::get().memory.0 + KERNEL_OFFSET, kernel_fdt(opaque))
+    } else {
+        hsm::park(mhartid)
+    };
     let mut rt = Runtime::new(mhartid, opaque, addr);
     loop {
+        // 其它硬件线程进入了调试器时，先停在这里
+        halt::poll(rt.context_mut());
         match Pin::new(&mut rt).resume(()) {
             GeneratorState::Yielded(MachineTrap::SbiCall()) => {
                 let ctx = rt.context_mut();
                 let (extension, function, param) = (ctx.a7, ctx.a6, 
                     [ctx.a0, ctx.a1, ctx.a2, ctx.a3, ctx.a4]);
-                let ans = rustsbi::ecall(extension, function, param);
+                // 内核请求复位前先停在调试器中，继续运行后才复位
+                if let Some((reset_type, reset_reason)) = reset::requested(extension, function, param) {
+                    if reset::stops_in_debugger() {
+                        debug::on_reset(ctx, reset_type, reset_reason);
+                    }
+                }
+                pmu::record_ecall(extension, function);
+                // 调试大师自己实现的扩展先处理，其它交给RustSBI
+                let ans = tiaoshi::ecall(ctx, extension, function, param)
+                    .or_else(|| console::ecall(extension, function, param))
+                    .or_else(|| pmu::ecall(extension, function, param))
+                    .unwrap_or_else(|| rustsbi::ecall(extension, function, param));
                 ctx.a0 = ans.error;
                 ctx.a1 = ans.value;
                 ctx.mepc = ctx.mepc.wrapping_add(4);
+                // 内核读取串口输入时可能读到了Ctrl+C
+                if uart::take_ctrl_c() {
+                    debug::on_interrupt(ctx);
+                }
+                // 内核通过调试大师扩展请求停下
+                if let Some((message, len)) = tiaoshi::take_stop(mhartid) {
+                    debug::on_kernel_stop(ctx, message, len);
+                }
+                // 内核停止或非保持挂起了这个硬件线程，再次进入内核时使用新的上下文
+                if let Some((addr, opaque)) = hsm::take_pending(mhartid) {
+                    rt = Runtime::new(mhartid, opaque, addr);
+                }
             }
-            GeneratorState::Yielded(MachineTrap::Breakpoint()) => { 
+            GeneratorState::Yielded(MachineTrap::Breakpoint(mtval)) => { 
                 let ctx = rt.context_mut();
-                
-                debug::on_breakpoint(ctx);
-                
-                ctx.mepc = ctx.mepc.wrapping_add(4);
+                // 调试器会根据断点的来源调整mepc；观察点触发时，mtval可能是访问的地址
+                debug::on_breakpoint(ctx, mtval);
+            }
+            GeneratorState::Yielded(MachineTrap::ExternalInterrupt()) => {
+                let ctx = rt.context_mut();
+                let platform = platform::get();
+                // 只有找到了PLIC才会打开外部中断
+                let context = platform.plic.map_or(0, |(_, context)| context);
+                let irq = PLIC.claim(context);
+                let request = if Some(irq) == platform.uart.irq { uart::receive_interrupt() } else { None };
+                if irq != 0 {
+                    PLIC.complete(context, irq);
+                }
+                match request {
+                    Some(uart::Request::Interrupt) => debug::on_interrupt(ctx),
+                    Some(uart::Request::GdbAttach) => debug::on_gdb_attach(ctx),
+                    None => {}
+                }
+            }
+            GeneratorState::Yielded(trap) if trap.is_kernel_exception() => {
+                // 先尝试模拟，模拟时发生的异常同样转交给内核
+                let forward = match trap {
+                    MachineTrap::IllegalInstruction(mtval) if emulate::illegal_instruction(rt.context_mut(), mtval) => None,
+                    MachineTrap::LoadMisaligned(mtval) | MachineTrap::StoreMisaligned(mtval) =>
+                        emulate::misaligned(rt.context_mut(), trap.mcause(), mtval),
+                    _ => Some((trap.mcause(), trap.mtval())),
+                };
+                let (mcause, mtval) = match forward {
+                    Some(forward) => forward,
+                    None => continue,
+                };
+                // 内核访问了调试大师自己的内存，不转交给内核
+                if firmware_access(mcause, mtval) {
+                    debug::on_fault(rt.context_mut(), mcause, mtval);
+                    continue
+                }
+                match executor::trap_vector(mcause) {
+                    // 像委托给S态一样转交给内核；在调试器中取消了委托的异常先停下
+                    Some(vector) => {
+                        let deliver = !delegation::stops_on(mcause)
+                            || debug::on_deliver(rt.context_mut(), mcause, mtval, vector);
+                        // 在调试器中可能清除了stvec，这时无法转交
+                        if deliver && !rt.redirect(mcause, mtval) {
+                            debug::on_fault(rt.context_mut(), mcause, mtval);
+                        }
+                    }
+                    // 内核还没有设置陷入处理函数
+                    None => debug::on_fault(rt.context_mut(), mcause, mtval),
+                }
+            }
+            GeneratorState::Yielded(MachineTrap::MachineTimer()) => {
+                // 转发给S态：关闭M态定时器中断，设置S态定时器中断待处理，内核下次调用set_timer时清除
+                unsafe {
+                    riscv::register::mie::clear_mtimer();
+                    riscv::register::mip::set_stimer();
+                }
+            }
+            GeneratorState::Yielded(MachineTrap::MachineSoft()) => {
+                // 执行其它硬件线程发来的请求，核间中断转发为S态软件中断
+                ipi::handle_soft_interrupt();
+            }
+            GeneratorState::Yielded(trap) => {
+                // 没有委托的中断和未定义的原因，停在调试器中检查，不能直接关机
+                let ctx = rt.context_mut();
+                debug::on_fault(ctx, trap.mcause(), trap.mtval());
             }
-            GeneratorState::Yielded(_trap) => todo!(),
             GeneratorState::Complete(()) => shutdown(),
         }
     }
 }
 
-fn first_hart_init() {
+// 等串口可以使用后只警告一次，然后关闭所有M态中断，一直等待
+fn unsupported_hart(hart_id: usize) -> ! {
+    while !PLATFORM_READY.load(Ordering::Acquire) {
+        core::hint::spin_loop();
+    }
+    if !UNSUPPORTED_WARNED.swap(true, Ordering::Relaxed) {
+        println!("[DebugSBI] Hart {} and other harts numbered {} or above are not supported, they stay stopped",
+            hart_id, ipi::MAX_HARTS);
+    }
+    unsafe { asm!("csrw mie, zero") };
+    loop {
+        unsafe { asm!("wfi") };
+    }
+}
+
+fn first_hart_init(opaque: usize) {
     // todo: clean bss memory using r0
     // todo: i18n
     init_alloc();
-    init_println();
+    // 解析设备树之后才知道串口在哪里，结果在打开串口之后输出
+    let discovered = platform::init(opaque, BOOT_HART);
+    let platform = platform::get();
+    CLINT.set_base(platform.clint);
+    if let Some((base, _)) = platform.plic {
+        PLIC.set_base(base);
+    }
+    init_println(&platform.uart);
+    match discovered {
+        Ok(missing) if !missing.is_empty() => println!("[DebugSBI] Not found in device tree: {}", missing.join(", ")),
+        Ok(_) => {}
+        Err(error) => println!("[DebugSBI] No device tree at {:#x} ({:?}), using QEMU virt defaults", opaque, error),
+    }
+    print_platform(&platform);
     init_reset();
+    init_timer(platform.clint);
+    init_ipi();
+    init_hsm();
+    init_ctrl_c(&platform);
     println!("RustSBI version: {}", rustsbi::VERSION);
 }
 
+// 其它硬件线程进入调试大师后，修改设备树交给内核；没有进入的硬件线程在设备树中禁用
+fn kernel_fdt(opaque: usize) -> usize {
+    let platform = platform::get();
+    let deadline = CLINT.mtime() + platform.timebase_frequency / 10;
+    while ONLINE_HARTS.load(Ordering::Relaxed) & platform.harts != platform.harts && CLINT.mtime() < deadline {
+        core::hint::spin_loop();
+    }
+    let started = ONLINE_HARTS.load(Ordering::Relaxed);
+    for hart_id in (0..ipi::MAX_HARTS).filter(|i| ((platform.harts & !started) >> i) & 1 != 0) {
+        println!("[DebugSBI] Hart {} did not start, it is disabled in the device tree", hart_id);
+    }
+    let firmware = firmware_region();
+    let error = match platform::patch(opaque, firmware, started) {
+        Ok(fdt) => return fdt,
+        Err(error) => error,
+    };
+    // 至少要让内核知道调试大师占用的内存，否则内核可能覆盖调试大师
+    if let Ok(fdt) = platform::reserve_only(opaque, firmware) {
+        println!("[DebugSBI] Device tree cannot be patched ({:?}), only /memreserve/ for the firmware is added", error);
+        return fdt
+    }
+    let (base, size) = firmware;
+    println!("[DebugSBI] Device tree is passed to the kernel unchanged ({:?})", error);
+    println!("[DebugSBI] WARNING: firmware memory {:#x}..{:#x} is NOT reserved, the kernel must not use it", base, base + size);
+    opaque
+}
+
+// 调试大师占用的内存，包括代码、数据和bss段中的MACHINE_STACK、MACHINE_HEAP，按页对齐
+fn firmware_region() -> (usize, usize) {
+    extern "C" {
+        static _stext: u8;
+        static _ebss: u8;
+    }
+    let (image_start, image_end) = unsafe { (&_stext as *const u8 as usize, &_ebss as *const u8 as usize) };
+    let (stack, heap) = unsafe { (MACHINE_STACK.as_ptr() as usize, MACHINE_HEAP.as_ptr() as usize) };
+    let start = image_start.min(stack).min(heap) & !(PAGE_SIZE - 1);
+    let end = image_end.max(stack + STACK_SIZE).max(heap + HEAP_SIZE);
+    (start, (end - start + PAGE_SIZE - 1) & !(PAGE_SIZE - 1))
+}
+
+// 访问错误的地址在调试大师占用的内存中
+fn firmware_access(mcause: usize, mtval: usize) -> bool {
+    let (base, size) = firmware_region();
+    matches!(mcause, 1 | 5 | 7) && mtval.wrapping_sub(base) < size
+}
+
 fn init_alloc() {
     unsafe {
         let heap_bottom = &mut MACHINE_HEAP as *mut _ as usize;
@@ -77,22 +312,54 @@ fn init_alloc() {
     }
 }
 
-fn init_println() {
-    let serial = uart::Ns16550a::new(0x10000000, 0, 11_059_200, 115200);
+fn init_println(config: &platform::Uart) {
+    let mut serial = uart::Ns16550a::new(config.base, config.reg_shift, config.clock_frequency, 115200);
+    serial.enable_receive_interrupt();
     rustsbi::legacy_stdio::init_legacy_stdio_embedded_hal(serial);
 }
 
+fn print_platform(platform: &platform::Platform) {
+    let (memory_base, memory_size) = platform.memory;
+    println!("[DebugSBI] Platform: uart {:#x}, clint {:#x}, memory {:#x}..{:#x}, {} harts, timebase {} Hz",
+        platform.uart.base, platform.clint, memory_base, memory_base + memory_size,
+        platform.harts.count_ones(), platform.timebase_frequency);
+}
+
+// 把串口的接收中断路由到启动硬件线程的M态，用于Ctrl+C
+fn init_ctrl_c(platform: &platform::Platform) {
+    let (context, irq) = match (platform.plic, platform.uart.irq) {
+        (Some((_, context)), Some(irq)) => (context, irq),
+        _ => {
+            println!("[DebugSBI] No PLIC or UART interrupt, Ctrl+C only works while the kernel reads the console");
+            return
+        }
+    };
+    PLIC.set_priority(irq, 1);
+    PLIC.enable(context, irq);
+    PLIC.set_threshold(context, 0);
+    // 内核运行在S态或U态时，M态中断总是打开的，不需要设置mstatus.MIE
+    unsafe { riscv::register::mie::set_mext() };
+}
+
 fn init_reset() {
     rustsbi::init_reset(reset::Reset);
 }
 
+fn init_timer(base: usize) {
+    rustsbi::init_timer(clint::Clint::new(base));
+}
+
+fn init_hsm() {
+    rustsbi::init_hsm(hsm::Hsm);
+}
+
+fn init_ipi() {
+    rustsbi::init_ipi(ipi::Ipi);
+    rustsbi::init_rfence(ipi::Rfence);
+}
+
 fn shutdown() -> ! {
-    use rustsbi::Reset;
-    reset::Reset.system_reset(
-        rustsbi::reset::RESET_TYPE_SHUTDOWN,
-        rustsbi::reset::RESET_REASON_NO_REASON,
-    );
-    loop {}
+    reset::reset(rustsbi::reset::RESET_TYPE_SHUTDOWN, rustsbi::reset::RESET_REASON_NO_REASON)
 }
 
 #[cfg_attr(not(test), panic_handler)]
@@ -102,24 +369,14 @@ fn panic(info: &PanicInfo) -> ! {
     // 输出的信息大概是“[rustsbi-panic] hart 0 panicked at ...”
     println!("[rustsbi-panic] hart {} {}", hart_id, info);
     println!("[rustsbi-panic] system shutdown scheduled due to SBI panic");
-    use rustsbi::Reset;
-    reset::Reset.system_reset(
-        rustsbi::reset::RESET_TYPE_SHUTDOWN,
-        rustsbi::reset::RESET_REASON_SYSTEM_FAILURE
-    );
-    loop { }
+    reset::firmware_failure()
 }
 
 #[cfg_attr(not(test), alloc_error_handler)]
 #[allow(unused)]
 fn alloc_error(layout: Layout) -> ! {
     println!("[rustsbi] out of memory for layout {:?}", layout);
-    use rustsbi::Reset;
-    reset::Reset.system_reset(
-        rustsbi::reset::RESET_TYPE_SHUTDOWN,
-        rustsbi::reset::RESET_REASON_SYSTEM_FAILURE
-    );
-    loop {}
+    reset::firmware_failure()
 }
 
 #[naked]
@@ -0,0 +1,366 @@
+// 从设备树发现平台的设备，修改交给内核的设备树
+//
+// 启动时a1寄存器（opaque）是设备树的地址。启动硬件线程解析设备树，找到串口、CLINT、PLIC、
+// 复位设备、内存和硬件线程，驱动程序都使用这里的配置。
+// 没有设备树，或者设备树中找不到串口、CLINT等设备时，使用QEMU virt平台的默认值。
+//
+// 进入内核前，启动硬件线程把设备树复制到调试大师自己的内存中并修改，内核不需要知道调试大师占用了哪些内存
+use crate::fdt::{self, Fdt, Node, Writer};
+use crate::ipi::MAX_HARTS;
+use crate::{tiaoshi, trigger};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Uart {
+    pub base: usize,
+    // 寄存器的间隔是1 << reg_shift个字节
+    pub reg_shift: usize,
+    pub clock_frequency: u64,
+    // PLIC中的中断号
+    pub irq: Option<usize>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum ResetDevice {
+    // QEMU的测试设备，关机时可以设置QEMU的退出码
+    SifiveTest(usize),
+    // 向寄存器写入给定的值来关机或复位，地址和值来自syscon-poweroff和syscon-reboot节点
+    Syscon { poweroff: Option<(usize, u32)>, reboot: Option<(usize, u32)> },
+    None,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Platform {
+    pub uart: Uart,
+    pub clint: usize,
+    // PLIC的地址和启动硬件线程的M态上下文编号
+    pub plic: Option<(usize, usize)>,
+    pub reset: ResetDevice,
+    // 内存的起始地址和大小
+    pub memory: (usize, usize),
+    // 设备树中存在的硬件线程，每个硬件线程一位；为0时只知道进入过调试大师的硬件线程
+    pub harts: usize,
+    // mtime每秒增加的次数
+    pub timebase_frequency: u64,
+}
+
+// QEMU virt平台的设备，没有设备树时使用
+const QEMU_VIRT: Platform = Platform {
+    uart: Uart { base: 0x1000_0000, reg_shift: 0, clock_frequency: 11_059_200, irq: Some(10) },
+    clint: 0x0200_0000,
+    plic: Some((0x0c00_0000, 0)),
+    reset: ResetDevice::SifiveTest(0x10_0000),
+    memory: (0x8000_0000, 0x800_0000),
+    harts: 0,
+    timebase_frequency: 10_000_000,
+};
+
+static PLATFORM: Mutex<Platform> = Mutex::new(QEMU_VIRT);
+
+const UART_COMPATIBLE: &[&str] = &["ns16550a", "ns16550"];
+// 给内核的设备树的最大大小
+const KERNEL_FDT_SIZE: usize = 0x10000;
+// 修改后的设备树在调试大师的bss段中，对齐到8个字节
+static mut KERNEL_FDT: [u64; KERNEL_FDT_SIZE / 8] = [0; KERNEL_FDT_SIZE / 8];
+// 保留内存和/chosen下描述调试大师的节点的名称
+const NODE_NAME: &str = "tiaoshi-dashi";
+
+/// 当前平台的配置
+pub fn get() -> Platform {
+    *PLATFORM.lock()
+}
+
+/// 解析`opaque`指向的设备树；返回设备树中没有找到的设备
+pub fn init(opaque: usize, boot_hart: usize) -> Result<Vec<&'static str>, fdt::Error> {
+    let fdt = unsafe { Fdt::from_address(opaque) }?;
+    let (platform, missing) = discover(&fdt, boot_hart);
+    *PLATFORM.lock() = platform;
+    Ok(missing)
+}
+
+fn discover(fdt: &Fdt, boot_hart: usize) -> (Platform, Vec<&'static str>) {
+    let mut platform = QEMU_VIRT;
+    let mut missing = Vec::new();
+    match fdt.find_compatible(UART_COMPATIBLE).and_then(|node| node.reg().map(|reg| (node, reg))) {
+        Some((node, (base, _))) => platform.uart = Uart {
+            base,
+            reg_shift: node.property_u32("reg-shift").unwrap_or(0) as usize,
+            clock_frequency: node.property_u32("clock-frequency").map_or(QEMU_VIRT.uart.clock_frequency, |f| f as u64),
+            irq: node.property_u32("interrupts").map(|irq| irq as usize),
+        },
+        None => missing.push("uart"),
+    }
+    match fdt.find_compatible(&["riscv,clint0", "sifive,clint0"]).and_then(|node| node.reg()) {
+        Some((base, _)) => platform.clint = base,
+        None => missing.push("clint"),
+    }
+    // 找不到PLIC和复位设备时不使用默认地址，其它平台的这个地址上可能是别的设备
+    platform.plic = plic(fdt, boot_hart);
+    if platform.plic.is_none() {
+        missing.push("plic");
+    }
+    platform.reset = reset_device(fdt);
+    if let ResetDevice::None = platform.reset {
+        missing.push("reset");
+    }
+    match fdt.nodes().find(|node| node.property_str("device_type") == Some("memory")).and_then(|node| node.reg()) {
+        Some(memory) => platform.memory = memory,
+        None => missing.push("memory"),
+    }
+    platform.harts = cpus(fdt).filter(|cpu| cpu.is_enabled())
+        .filter_map(|cpu| cpu.reg()).map(|(hart_id, _)| hart_id)
+        .filter(|&hart_id| hart_id < MAX_HARTS)
+        .fold(0, |mask, hart_id| mask | (1 << hart_id));
+    if platform.harts == 0 {
+        missing.push("cpus");
+    }
+    // timebase-frequency一般在/cpus节点上，也可能在每个cpu节点上
+    let timebase = fdt.nodes().find(|node| node.depth == 1 && node.name == "cpus")
+        .and_then(|node| node.property_u32("timebase-frequency"))
+        .or_else(|| cpus(fdt).find_map(|cpu| cpu.property_u32("timebase-frequency")));
+    match timebase {
+        Some(frequency) => platform.timebase_frequency = frequency as u64,
+        None => missing.push("timebase-frequency"),
+    }
+    (platform, missing)
+}
+
+fn cpus<'a>(fdt: &Fdt<'a>) -> impl Iterator<Item = Node<'a>> {
+    fdt.nodes().filter(|node| node.property_str("device_type") == Some("cpu"))
+}
+
+// interrupts-extended由（中断控制器，中断号）组成，第几对就是第几个上下文。
+// 启动硬件线程的M态上下文是它的本地中断控制器和M态外部中断（11）这一对
+fn plic(fdt: &Fdt, boot_hart: usize) -> Option<(usize, usize)> {
+    const MACHINE_EXTERNAL: u32 = 11;
+    let node = fdt.find_compatible(&["riscv,plic0", "sifive,plic-1.0.0"])?;
+    let (base, _) = node.reg()?;
+    let cpu = cpus(fdt).find(|cpu| cpu.reg().map(|(hart_id, _)| hart_id) == Some(boot_hart))?;
+    // 本地中断控制器是cpu节点的子节点
+    let intc = fdt.nodes().skip_while(|node| node.name != cpu.name || node.depth != cpu.depth).skip(1)
+        .take_while(|node| node.depth > cpu.depth)
+        .find(|node| node.is_compatible("riscv,cpu-intc"))?
+        .phandle()?;
+    let pairs: Vec<u32> = fdt::cells(node.property("interrupts-extended")?).collect();
+    let context = pairs.chunks_exact(2).position(|pair| pair[0] == intc && pair[1] == MACHINE_EXTERNAL)?;
+    Some((base, context))
+}
+
+fn reset_device(fdt: &Fdt) -> ResetDevice {
+    if let Some((base, _)) = fdt.find_compatible(&["sifive,test1", "sifive,test0"]).and_then(|node| node.reg()) {
+        return ResetDevice::SifiveTest(base)
+    }
+    let poweroff = fdt.find_compatible(&["syscon-poweroff"]).and_then(|node| syscon(fdt, node));
+    let reboot = fdt.find_compatible(&["syscon-reboot"]).and_then(|node| syscon(fdt, node));
+    if poweroff.is_none() && reboot.is_none() {
+        return ResetDevice::None
+    }
+    ResetDevice::Syscon { poweroff, reboot }
+}
+
+// regmap指向syscon节点，寄存器地址是它的reg加上offset
+fn syscon(fdt: &Fdt, node: Node) -> Option<(usize, u32)> {
+    let (base, _) = fdt.find_phandle(node.property_u32("regmap")?)?.reg()?;
+    let offset = node.property_u32("offset")? as usize;
+    Some((base + offset, node.property_u32("value")?))
+}
+
+/// 修改`opaque`指向的设备树，返回交给内核的设备树的地址
+///
+/// 保留调试大师占用的内存`firmware`，stdout-path指向调试大师使用的串口，
+/// 禁用`started`中没有的硬件线程，在/chosen下加上描述调试大师的节点
+pub fn patch(opaque: usize, firmware: (usize, usize), started: usize) -> Result<usize, fdt::Error> {
+    let fdt = unsafe { Fdt::from_address(opaque) }?;
+    let (address, buffer) = kernel_fdt_buffer();
+    let mut patch = Patch { writer: Writer::new(&fdt, buffer), firmware, uart: uart_path(&fdt), chosen: false, reserved_memory: false };
+    let mut open: Vec<Open> = Vec::new();
+    // 原来的设备树中已经有调试大师的节点时（例如复位后再次启动），跳过这个节点和它的子节点
+    let mut skip: Option<usize> = None;
+    for node in fdt.nodes() {
+        match skip {
+            Some(depth) if node.depth > depth => continue,
+            _ => skip = None,
+        }
+        while open.len() > node.depth {
+            patch.close(open.pop().unwrap());
+        }
+        let kind = match (open.last(), node.depth, node.name) {
+            (_, 0, _) => Open::Root(cells_of(&node)),
+            (_, 1, "chosen") => Open::Chosen,
+            (_, 1, "reserved-memory") => Open::ReservedMemory(cells_of(&node)),
+            (Some(Open::Chosen), _, name) | (Some(Open::ReservedMemory(_)), _, name) if name.starts_with(NODE_NAME) => {
+                skip = Some(node.depth);
+                continue
+            }
+            _ => Open::Other,
+        };
+        let disable = node.property_str("device_type") == Some("cpu") && node.is_enabled()
+            && node.reg().map_or(false, |(hart_id, _)| hart_id >= MAX_HARTS || (started >> hart_id) & 1 == 0);
+        let chosen = matches!(kind, Open::Chosen);
+        patch.writer.begin_node(node.name);
+        for property in node.properties() {
+            let replaced = match property.name {
+                "status" => disable,
+                "stdout-path" | "linux,stdout-path" => chosen && patch.uart.is_some(),
+                _ => false,
+            };
+            if !replaced {
+                patch.writer.property(property.name, property.value);
+            }
+        }
+        if disable {
+            patch.writer.property_str("status", "disabled");
+        }
+        if chosen {
+            patch.stdout_path();
+        }
+        open.push(kind);
+    }
+    while let Some(node) = open.pop() {
+        patch.close(node);
+    }
+    patch.writer.finish()?;
+    Ok(address)
+}
+
+/// `patch`失败时使用：原样复制设备树，只在保留内存表中加上调试大师占用的内存`firmware`
+pub fn reserve_only(opaque: usize, firmware: (usize, usize)) -> Result<usize, fdt::Error> {
+    let fdt = unsafe { Fdt::from_address(opaque) }?;
+    let (address, buffer) = kernel_fdt_buffer();
+    let mut writer = Writer::with_reservation(&fdt, buffer, Some(firmware));
+    writer.copy_structure();
+    writer.finish()?;
+    Ok(address)
+}
+
+// 修改后的设备树只在启动硬件线程进入内核前写出一次
+fn kernel_fdt_buffer() -> (usize, &'static mut [u8]) {
+    let address = unsafe { KERNEL_FDT.as_mut_ptr() as usize };
+    (address, unsafe { core::slice::from_raw_parts_mut(address as *mut u8, KERNEL_FDT_SIZE) })
+}
+
+// 正在写出的节点，关闭时可能要加上子节点
+#[derive(Clone, Copy)]
+enum Open {
+    // 根节点和/reserved-memory给子节点规定的#address-cells和#size-cells
+    Root((usize, usize)),
+    Chosen,
+    ReservedMemory((usize, usize)),
+    Other,
+}
+
+struct Patch<'a, 'b> {
+    writer: Writer<'a, 'b>,
+    firmware: (usize, usize),
+    uart: Option<String>,
+    // 原来的设备树中有这两个节点
+    chosen: bool,
+    reserved_memory: bool,
+}
+
+impl Patch<'_, '_> {
+    fn close(&mut self, node: Open) {
+        match node {
+            Open::Chosen => {
+                self.describe_debugger();
+                self.chosen = true;
+            }
+            Open::ReservedMemory(cells) => {
+                self.reserve_firmware(cells);
+                self.reserved_memory = true;
+            }
+            // 原来没有/chosen和/reserved-memory时加在根节点的最后
+            Open::Root(cells) => {
+                if !self.chosen {
+                    self.writer.begin_node("chosen");
+                    self.stdout_path();
+                    self.describe_debugger();
+                    self.writer.end_node();
+                }
+                if !self.reserved_memory {
+                    self.writer.begin_node("reserved-memory");
+                    self.writer.property_cells("#address-cells", &[cells.0 as u32]);
+                    self.writer.property_cells("#size-cells", &[cells.1 as u32]);
+                    self.writer.property("ranges", &[]);
+                    self.reserve_firmware(cells);
+                    self.writer.end_node();
+                }
+            }
+            Open::Other => {}
+        }
+        self.writer.end_node();
+    }
+
+    fn stdout_path(&mut self) {
+        if let Some(path) = &self.uart {
+            self.writer.property_str("stdout-path", path);
+        }
+    }
+
+    // 修改后的设备树也在这段内存中，内核需要读取它，所以不使用no-map
+    fn reserve_firmware(&mut self, (address_cells, size_cells): (usize, usize)) {
+        let (base, size) = self.firmware;
+        let mut reg = Vec::new();
+        push_cells(&mut reg, base, address_cells);
+        push_cells(&mut reg, size, size_cells);
+        self.writer.begin_node(&format!("{}@{:x}", NODE_NAME, base));
+        self.writer.property_cells("reg", &reg);
+        self.writer.end_node();
+    }
+
+    // 内核可以从/chosen/tiaoshi-dashi知道调试大师提供的功能
+    fn describe_debugger(&mut self) {
+        let triggers = trigger::count();
+        let platform = get();
+        let mut features = Vec::new();
+        for &(feature, available) in &[
+            ("breakpoints", true),
+            ("watchpoints", triggers > 0),
+            ("symbols", true),
+            ("gdb-rsp", true),
+            ("ctrl-c", platform.plic.is_some() && platform.uart.irq.is_some()),
+            ("console", true),
+            ("pmu", true),
+        ] {
+            if available {
+                features.extend_from_slice(feature.as_bytes());
+                features.push(0);
+            }
+        }
+        self.writer.begin_node(NODE_NAME);
+        self.writer.property_str("compatible", NODE_NAME);
+        self.writer.property_str("version", env!("CARGO_PKG_VERSION"));
+        self.writer.property_cells("sbi-extension", &[tiaoshi::EXTENSION_TIAOSHI as u32]);
+        self.writer.property_cells("triggers", &[triggers as u32]);
+        self.writer.property("features", &features);
+        self.writer.end_node();
+    }
+}
+
+fn cells_of(node: &Node) -> (usize, usize) {
+    (node.property_u32("#address-cells").unwrap_or(2) as usize, node.property_u32("#size-cells").unwrap_or(1) as usize)
+}
+
+// 用count个单元表示value，高位在前
+fn push_cells(cells: &mut Vec<u32>, value: usize, count: usize) {
+    for i in (0..count).rev() {
+        cells.push((value as u64).checked_shr(32 * i as u32).unwrap_or(0) as u32);
+    }
+}
+
+// 串口节点的完整路径，例如/soc/serial@10000000
+fn uart_path(fdt: &Fdt) -> Option<String> {
+    let uart = fdt.find_compatible(UART_COMPATIBLE)?;
+    let mut names = Vec::new();
+    for node in fdt.nodes() {
+        names.truncate(node.depth);
+        names.push(node.name);
+        if node == uart {
+            return Some(names.join("/"))
+        }
+    }
+    None
+}
@@ -0,0 +1,58 @@
+// 平台级中断控制器（PLIC）
+//
+// 调试器只使用串口的接收中断，把它路由到M态，用来实现Ctrl+C
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+pub struct Plic {
+    // 启动时从设备树得到地址
+    base: AtomicUsize,
+}
+
+mod offsets {
+    pub const PRIORITY: usize = 0x0;
+    pub const ENABLE: usize = 0x2000;
+    pub const ENABLE_STRIDE: usize = 0x80;
+    pub const THRESHOLD: usize = 0x20_0000;
+    pub const CLAIM: usize = 0x20_0004;
+    pub const CONTEXT_STRIDE: usize = 0x1000;
+}
+
+impl Plic {
+    pub const fn new(base: usize) -> Self {
+        Self { base: AtomicUsize::new(base) }
+    }
+
+    pub fn set_base(&self, base: usize) {
+        self.base.store(base, Ordering::Relaxed);
+    }
+
+    fn base(&self) -> usize {
+        self.base.load(Ordering::Relaxed)
+    }
+
+    pub fn set_priority(&self, irq: usize, priority: u32) {
+        unsafe { write_volatile((self.base() + offsets::PRIORITY + irq * 4) as *mut u32, priority) };
+    }
+
+    pub fn enable(&self, context: usize, irq: usize) {
+        let word = (self.base() + offsets::ENABLE + context * offsets::ENABLE_STRIDE + (irq / 32) * 4) as *mut u32;
+        unsafe { write_volatile(word, read_volatile(word) | (1 << (irq % 32))) };
+    }
+
+    pub fn set_threshold(&self, context: usize, threshold: u32) {
+        let address = self.base() + offsets::THRESHOLD + context * offsets::CONTEXT_STRIDE;
+        unsafe { write_volatile(address as *mut u32, threshold) };
+    }
+
+    /// 领取一个待处理的中断，没有中断时返回0
+    pub fn claim(&self, context: usize) -> usize {
+        let address = self.base() + offsets::CLAIM + context * offsets::CONTEXT_STRIDE;
+        unsafe { read_volatile(address as *const u32) as usize }
+    }
+
+    pub fn complete(&self, context: usize, irq: usize) {
+        let address = self.base() + offsets::CLAIM + context * offsets::CONTEXT_STRIDE;
+        unsafe { write_volatile(address as *mut u32, irq as u32) };
+    }
+}
@@ -0,0 +1,516 @@
+// SBI的性能监视（PMU）扩展和调试器的perf命令
+//
+// 计数器的编号依次为cycle、instret、硬件实现了的mhpmcounter，最后是调试大师自己的固件计数器。
+// 硬件计数器由内核直接读取对应的CSR；固件计数器统计调试大师处理的事件，由内核调用counter_fw_read读取。
+// 计数器是每个硬件线程自己的，SBI调用只作用在调用的硬件线程上
+use crate::error::{self, SBI_ERR_INVALID_PARAM, SBI_ERR_NOT_SUPPORTED, SBI_ERR_ALREADY_STARTED, SBI_ERR_ALREADY_STOPPED};
+use crate::ipi::MAX_HARTS;
+use crate::probe;
+use riscv::register::mhartid;
+use rustsbi::SbiRet;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+const EXTENSION_BASE: usize = 0x10;
+const FUNCTION_PROBE_EXTENSION: usize = 3;
+const EXTENSION_PMU: usize = 0x504D55;
+const FUNCTION_NUM_COUNTERS: usize = 0;
+const FUNCTION_COUNTER_GET_INFO: usize = 1;
+const FUNCTION_COUNTER_CONFIG_MATCHING: usize = 2;
+const FUNCTION_COUNTER_START: usize = 3;
+const FUNCTION_COUNTER_STOP: usize = 4;
+const FUNCTION_COUNTER_FW_READ: usize = 5;
+
+// counter_config_matching、counter_start和counter_stop的标志
+const CFG_FLAG_SKIP_MATCH: usize = 1 << 0;
+const CFG_FLAG_CLEAR_VALUE: usize = 1 << 1;
+const CFG_FLAG_AUTO_START: usize = 1 << 2;
+const START_FLAG_SET_INIT_VALUE: usize = 1 << 0;
+const STOP_FLAG_RESET: usize = 1 << 0;
+
+// 事件编号的第16到19位是类型，低16位是类型中的编号
+const EVENT_TYPE_HARDWARE: usize = 0;
+const EVENT_TYPE_RAW: usize = 2;
+const EVENT_TYPE_FIRMWARE: usize = 15;
+const HW_CPU_CYCLES: usize = 1;
+const HW_INSTRUCTIONS: usize = 2;
+
+// 统计固件事件的旧版和新版SBI调用
+const EXTENSION_LEGACY_SET_TIMER: usize = 0x00;
+const EXTENSION_LEGACY_SEND_IPI: usize = 0x04;
+const EXTENSION_LEGACY_REMOTE_FENCE_I: usize = 0x05;
+const EXTENSION_LEGACY_REMOTE_SFENCE_VMA: usize = 0x06;
+const EXTENSION_LEGACY_REMOTE_SFENCE_VMA_ASID: usize = 0x07;
+const EXTENSION_TIMER: usize = 0x5449_4D45;
+const EXTENSION_IPI: usize = 0x73_5049;
+const EXTENSION_RFENCE: usize = 0x5246_4E43;
+
+// cycle和instret之后是hpmcounter3到hpmcounter31
+const HPM_FIRST: usize = 3;
+const HPM_COUNT: usize = 29;
+const HARDWARE_COUNTERS: usize = 2 + HPM_COUNT;
+// 固件计数器的个数，每个计数器可以统计任意一种固件事件
+const FIRMWARE_COUNTERS: usize = 8;
+const MAX_COUNTERS: usize = HARDWARE_COUNTERS + FIRMWARE_COUNTERS;
+const EVENT_COUNT: usize = 13;
+
+/// 调试大师统计的固件事件，编号和SBI规范相同
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Event {
+    MisalignedLoad = 0,
+    MisalignedStore = 1,
+    IllegalInstruction = 4,
+    SetTimer = 5,
+    IpiSent = 6,
+    IpiReceived = 7,
+    FenceISent = 8,
+    FenceIReceived = 9,
+    SfenceVmaSent = 10,
+    SfenceVmaReceived = 11,
+    SfenceVmaAsidSent = 12,
+    SfenceVmaAsidReceived = 13,
+    // 规范把0xffff留给平台自定义的事件，调试大师用它统计处理过的SBI调用
+    SbiCall = 0xffff,
+}
+
+// 固件事件和perf命令中显示的名称
+const EVENTS: [(Event, &str); EVENT_COUNT] = [
+    (Event::SbiCall, "SBI calls"),
+    (Event::MisalignedLoad, "misaligned loads"),
+    (Event::MisalignedStore, "misaligned stores"),
+    (Event::IllegalInstruction, "illegal instructions"),
+    (Event::SetTimer, "set_timer"),
+    (Event::IpiSent, "IPIs sent"),
+    (Event::IpiReceived, "IPIs received"),
+    (Event::FenceISent, "fence.i sent"),
+    (Event::FenceIReceived, "fence.i received"),
+    (Event::SfenceVmaSent, "sfence.vma sent"),
+    (Event::SfenceVmaReceived, "sfence.vma received"),
+    (Event::SfenceVmaAsidSent, "sfence.vma asid sent"),
+    (Event::SfenceVmaAsidReceived, "sfence.vma asid received"),
+];
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Kind {
+    Cycle,
+    Instret,
+    // mhpmcounter的编号
+    Hpm(usize),
+    Firmware,
+}
+
+#[derive(Clone, Copy)]
+struct Counter {
+    // 配置的事件编号，None表示计数器空闲
+    event: Option<usize>,
+    started: bool,
+    // 固件计数器停止时的值，或者开始时的初始值
+    value: usize,
+    // 固件计数器开始时事件已经发生的次数
+    base: usize,
+}
+
+#[derive(Clone, Copy)]
+struct Hart {
+    counters: [Counter; MAX_COUNTERS],
+    // 每种固件事件发生的次数，顺序和EVENTS相同
+    events: [usize; EVENT_COUNT],
+    // 停在调试器中的时间里硬件计数器增加的值，perf命令的结果不包括这部分
+    hidden: [usize; HARDWARE_COUNTERS],
+    // 进入调试器时硬件计数器的值
+    paused: Option<[usize; HARDWARE_COUNTERS]>,
+    // 上一次perf命令时的pc和计数器的值
+    snapshot: Option<(usize, [usize; HARDWARE_COUNTERS + EVENT_COUNT])>,
+}
+
+const IDLE: Counter = Counter { event: None, started: false, value: 0, base: 0 };
+const EMPTY: Hart = Hart {
+    counters: [IDLE; MAX_COUNTERS],
+    events: [0; EVENT_COUNT],
+    hidden: [0; HARDWARE_COUNTERS],
+    paused: None,
+    snapshot: None,
+};
+
+static HARTS: Mutex<[Hart; MAX_HARTS]> = Mutex::new([EMPTY; MAX_HARTS]);
+// 硬件实现了的mhpmcounter，每个编号一位；None表示还没有探测过
+static HPM: Mutex<Option<usize>> = Mutex::new(None);
+
+/// 处理PMU扩展的SBI调用；其它调用返回None
+pub fn ecall(extension: usize, function: usize, param: [usize; 5]) -> Option<SbiRet> {
+    let ans = match (extension, function) {
+        // RustSBI不知道这个扩展，探测时由这里回答
+        (EXTENSION_BASE, FUNCTION_PROBE_EXTENSION) if param[0] == EXTENSION_PMU => error::ok(1),
+        (EXTENSION_PMU, FUNCTION_NUM_COUNTERS) => error::ok(counters().len()),
+        (EXTENSION_PMU, FUNCTION_COUNTER_GET_INFO) => match counters().get(param[0]) {
+            Some(&kind) => error::ok(counter_info(kind)),
+            None => error::error(SBI_ERR_INVALID_PARAM),
+        },
+        (EXTENSION_PMU, FUNCTION_COUNTER_CONFIG_MATCHING) =>
+            config_matching(param[0], param[1], param[2], param[3], param[4]),
+        (EXTENSION_PMU, FUNCTION_COUNTER_START) => counter_start(param[0], param[1], param[2], param[3]),
+        (EXTENSION_PMU, FUNCTION_COUNTER_STOP) => counter_stop(param[0], param[1], param[2]),
+        (EXTENSION_PMU, FUNCTION_COUNTER_FW_READ) => counter_fw_read(param[0]),
+        (EXTENSION_PMU, _) => error::error(SBI_ERR_NOT_SUPPORTED),
+        _ => return None,
+    };
+    Some(ans)
+}
+
+/// 这个硬件线程上发生了一次固件事件
+pub fn record(event: Event) {
+    if let Some(slot) = event_slot(event as usize) {
+        let mut harts = HARTS.lock();
+        let events = &mut harts[mhartid::read()].events;
+        events[slot] = events[slot].wrapping_add(1);
+    }
+}
+
+/// 统计内核的SBI调用；设置定时器、发送核间中断和远程栅栏同时记为对应的固件事件
+pub fn record_ecall(extension: usize, function: usize) {
+    record(Event::SbiCall);
+    let event = match (extension, function) {
+        (EXTENSION_TIMER, 0) | (EXTENSION_LEGACY_SET_TIMER, _) => Event::SetTimer,
+        (EXTENSION_IPI, 0) | (EXTENSION_LEGACY_SEND_IPI, _) => Event::IpiSent,
+        (EXTENSION_RFENCE, 0) | (EXTENSION_LEGACY_REMOTE_FENCE_I, _) => Event::FenceISent,
+        (EXTENSION_RFENCE, 1) | (EXTENSION_LEGACY_REMOTE_SFENCE_VMA, _) => Event::SfenceVmaSent,
+        (EXTENSION_RFENCE, 2) | (EXTENSION_LEGACY_REMOTE_SFENCE_VMA_ASID, _) => Event::SfenceVmaAsidSent,
+        _ => return,
+    };
+    record(event);
+}
+
+/// 进入调试器时记下硬件计数器的值，停在调试器中的时间不计入perf命令的结果
+pub fn pause() {
+    let values = hardware_values();
+    let mut harts = HARTS.lock();
+    let hart = &mut harts[mhartid::read()];
+    if hart.paused.is_none() {
+        hart.paused = Some(values);
+    }
+}
+
+/// 离开调试器，记下停在调试器中的时间里硬件计数器增加的值
+pub fn resume() {
+    let values = hardware_values();
+    let mut harts = HARTS.lock();
+    let hart = &mut harts[mhartid::read()];
+    if let Some(paused) = hart.paused.take() {
+        for i in 0..HARDWARE_COUNTERS {
+            hart.hidden[i] = hart.hidden[i].wrapping_add(values[i].wrapping_sub(paused[i]));
+        }
+    }
+}
+
+/// perf命令：这个硬件线程的计数器的值，和上一次perf命令以来增加的值。
+/// 返回上一次perf命令时的pc，以及每个计数器的名称、值和增量；硬件计数器不包括停在调试器中的时间
+pub fn perf(pc: usize) -> (Option<usize>, Vec<(String, usize, usize)>) {
+    let hpm = implemented_hpm();
+    let current = hardware_values();
+    let mut harts = HARTS.lock();
+    let hart = &mut harts[mhartid::read()];
+    let hardware = hart.paused.unwrap_or(current);
+    let mut values = [0; HARDWARE_COUNTERS + EVENT_COUNT];
+    for i in 0..HARDWARE_COUNTERS {
+        values[i] = hardware[i].wrapping_sub(hart.hidden[i]);
+    }
+    values[HARDWARE_COUNTERS..].copy_from_slice(&hart.events);
+    let (last_pc, previous) = match hart.snapshot {
+        Some((pc, previous)) => (Some(pc), previous),
+        None => (None, [0; HARDWARE_COUNTERS + EVENT_COUNT]),
+    };
+    hart.snapshot = Some((pc, values));
+    let mut ans = Vec::new();
+    for (i, (&value, &previous)) in values.iter().zip(previous.iter()).enumerate() {
+        let name = match i {
+            0 => String::from("cycle"),
+            1 => String::from("instret"),
+            i if i < HARDWARE_COUNTERS => {
+                let n = i - 2 + HPM_FIRST;
+                if (hpm >> n) & 1 == 0 {
+                    continue
+                }
+                format!("hpmcounter{}", n)
+            }
+            i => String::from(EVENTS[i - HARDWARE_COUNTERS].1),
+        };
+        ans.push((name, value, value.wrapping_sub(previous)));
+    }
+    (last_pc, ans)
+}
+
+// 这个硬件线程上的计数器，下标就是SBI调用中的计数器编号
+fn counters() -> Vec<Kind> {
+    let hpm = implemented_hpm();
+    let mut kinds = Vec::with_capacity(MAX_COUNTERS);
+    kinds.push(Kind::Cycle);
+    kinds.push(Kind::Instret);
+    kinds.extend((HPM_FIRST..HPM_FIRST + HPM_COUNT).filter(|n| (hpm >> n) & 1 != 0).map(Kind::Hpm));
+    kinds.extend((0..FIRMWARE_COUNTERS).map(|_| Kind::Firmware));
+    kinds
+}
+
+// 写入1后读回不为0的mhpmcounter是实现了的；没有实现的计数器可以恒为0。假设所有硬件线程都一样
+fn implemented_hpm() -> usize {
+    let mut hpm = HPM.lock();
+    if let Some(mask) = *hpm {
+        return mask
+    }
+    let mut mask = 0;
+    for n in HPM_FIRST..HPM_FIRST + HPM_COUNT {
+        if probe::write_mhpmcounter(n, 1).is_ok() {
+            if let Ok(value) = probe::read_mhpmcounter(n) {
+                if value != 0 {
+                    mask |= 1 << n;
+                }
+            }
+            let _ = probe::write_mhpmcounter(n, 0);
+        }
+    }
+    *hpm = Some(mask);
+    mask
+}
+
+// 规范中的counter_info：硬件计数器为CSR编号和位宽减一，固件计数器只有最高位的类型
+fn counter_info(kind: Kind) -> usize {
+    let xlen = core::mem::size_of::<usize>() * 8;
+    match kind {
+        Kind::Firmware => 1 << (xlen - 1),
+        kind => user_csr(kind) | ((xlen - 1) << 12),
+    }
+}
+
+// 内核读取硬件计数器的CSR编号，也决定了mcounteren和mcountinhibit中的位
+fn user_csr(kind: Kind) -> usize {
+    match kind {
+        Kind::Cycle => 0xc00,
+        Kind::Instret => 0xc02,
+        Kind::Hpm(n) => 0xc00 + n,
+        Kind::Firmware => unreachable!(),
+    }
+}
+
+// 硬件计数器在hidden和paused中的位置
+fn hardware_slot(kind: Kind) -> usize {
+    match kind {
+        Kind::Cycle => 0,
+        Kind::Instret => 1,
+        Kind::Hpm(n) => 2 + n - HPM_FIRST,
+        Kind::Firmware => unreachable!(),
+    }
+}
+
+fn hardware_values() -> [usize; HARDWARE_COUNTERS] {
+    let hpm = implemented_hpm();
+    let (cycle, instret): (usize, usize);
+    unsafe { asm!("csrr {}, mcycle", "csrr {}, minstret", out(reg) cycle, out(reg) instret) };
+    let mut values = [0; HARDWARE_COUNTERS];
+    values[hardware_slot(Kind::Cycle)] = cycle;
+    values[hardware_slot(Kind::Instret)] = instret;
+    for n in (HPM_FIRST..HPM_FIRST + HPM_COUNT).filter(|n| (hpm >> n) & 1 != 0) {
+        values[hardware_slot(Kind::Hpm(n))] = probe::read_mhpmcounter(n).unwrap_or(0);
+    }
+    values
+}
+
+// 较旧的QEMU不能写入mcycle和minstret，这时计数器保持原来的值
+fn write_hardware(kind: Kind, value: usize) {
+    let _ = match kind {
+        Kind::Cycle => probe::write_mcycle(value),
+        Kind::Instret => probe::write_minstret(value),
+        Kind::Hpm(n) => probe::write_mhpmcounter(n, value),
+        Kind::Firmware => unreachable!(),
+    };
+}
+
+// 硬件计数器开始或停止计数；没有mcountinhibit时硬件计数器总是在计数
+fn set_inhibit(kind: Kind, inhibit: bool) {
+    let bit = 1 << (user_csr(kind) - 0xc00);
+    if let Ok(value) = probe::read_mcountinhibit() {
+        let _ = probe::write_mcountinhibit(if inhibit { value | bit } else { value & !bit });
+    }
+    // 计数时允许内核直接读取这个计数器，停止后收回；调试大师平时不打开mcounteren中的任何位，
+    // time由emulate模块模拟，所以cycle和instret也一起收回
+    if inhibit {
+        unsafe { asm!("csrc mcounteren, {}", in(reg) bit) };
+    } else {
+        unsafe { asm!("csrs mcounteren, {}", in(reg) bit) };
+    }
+}
+
+// 固件事件在EVENTS中的位置
+fn event_slot(code: usize) -> Option<usize> {
+    EVENTS.iter().position(|&(event, _)| event as usize == code)
+}
+
+// 计数器能否统计这个事件
+fn can_count(kind: Kind, event: usize) -> bool {
+    let code = event & 0xffff;
+    match (event >> 16, kind) {
+        (EVENT_TYPE_HARDWARE, Kind::Cycle) => code == HW_CPU_CYCLES,
+        (EVENT_TYPE_HARDWARE, Kind::Instret) => code == HW_INSTRUCTIONS,
+        (EVENT_TYPE_RAW, Kind::Hpm(_)) => true,
+        (EVENT_TYPE_FIRMWARE, Kind::Firmware) => event_slot(code).is_some(),
+        _ => false,
+    }
+}
+
+// counter_idx_base和counter_idx_mask选中的计数器编号；有编号不存在时返回None
+fn selected(count: usize, base: usize, mask: usize) -> Option<Vec<usize>> {
+    let mut ans = Vec::new();
+    for i in (0..core::mem::size_of::<usize>() * 8).filter(|i| (mask >> i) & 1 != 0) {
+        match base.checked_add(i) {
+            Some(index) if index < count => ans.push(index),
+            _ => return None,
+        }
+    }
+    Some(ans)
+}
+
+fn config_matching(base: usize, mask: usize, flags: usize, event: usize, event_data: usize) -> SbiRet {
+    let kinds = counters();
+    let indexes = match selected(kinds.len(), base, mask) {
+        Some(indexes) if !indexes.is_empty() => indexes,
+        _ => return error::error(SBI_ERR_INVALID_PARAM),
+    };
+    let mut harts = HARTS.lock();
+    let hart = &mut harts[mhartid::read()];
+    let index = if flags & CFG_FLAG_SKIP_MATCH != 0 {
+        // 内核已经配置过这个计数器，直接使用
+        let index = indexes[0];
+        if hart.counters[index].event.is_none() {
+            return error::error(SBI_ERR_INVALID_PARAM)
+        }
+        index
+    } else {
+        let found = indexes.iter().copied()
+            .find(|&i| hart.counters[i].event.is_none() && can_count(kinds[i], event));
+        match found {
+            Some(index) => index,
+            None => return error::error(SBI_ERR_NOT_SUPPORTED),
+        }
+    };
+    let kind = kinds[index];
+    if flags & CFG_FLAG_SKIP_MATCH == 0 {
+        hart.counters[index] = Counter { event: Some(event), ..IDLE };
+        if let Kind::Hpm(n) = kind {
+            // 原始事件的编号由平台决定，直接写入mhpmevent
+            let _ = probe::write_mhpmevent(n, event_data);
+        }
+    }
+    if flags & CFG_FLAG_CLEAR_VALUE != 0 {
+        match kind {
+            Kind::Firmware => {
+                let counter = &mut hart.counters[index];
+                counter.value = 0;
+                counter.base = hart.events[event_slot(event & 0xffff).unwrap_or(0)];
+            }
+            kind => write_hardware(kind, 0),
+        }
+    }
+    if flags & CFG_FLAG_AUTO_START != 0 && !hart.counters[index].started {
+        start(hart, kind, index, None);
+    }
+    error::ok(index)
+}
+
+fn counter_start(base: usize, mask: usize, flags: usize, initial_value: usize) -> SbiRet {
+    let kinds = counters();
+    let indexes = match selected(kinds.len(), base, mask) {
+        Some(indexes) => indexes,
+        None => return error::error(SBI_ERR_INVALID_PARAM),
+    };
+    let mut harts = HARTS.lock();
+    let hart = &mut harts[mhartid::read()];
+    // 先检查所有计数器，不能开始时一个也不开始
+    for &i in &indexes {
+        if hart.counters[i].event.is_none() {
+            return error::error(SBI_ERR_INVALID_PARAM)
+        }
+        if hart.counters[i].started {
+            return error::error(SBI_ERR_ALREADY_STARTED)
+        }
+    }
+    let initial = if flags & START_FLAG_SET_INIT_VALUE != 0 { Some(initial_value) } else { None };
+    for &i in &indexes {
+        start(hart, kinds[i], i, initial);
+    }
+    error::ok(0)
+}
+
+fn counter_stop(base: usize, mask: usize, flags: usize) -> SbiRet {
+    let kinds = counters();
+    let indexes = match selected(kinds.len(), base, mask) {
+        Some(indexes) => indexes,
+        None => return error::error(SBI_ERR_INVALID_PARAM),
+    };
+    let mut harts = HARTS.lock();
+    let hart = &mut harts[mhartid::read()];
+    for &i in &indexes {
+        if hart.counters[i].event.is_none() {
+            return error::error(SBI_ERR_INVALID_PARAM)
+        }
+        if !hart.counters[i].started {
+            return error::error(SBI_ERR_ALREADY_STOPPED)
+        }
+    }
+    for &i in &indexes {
+        let kind = kinds[i];
+        match kind {
+            Kind::Firmware => hart.counters[i].value = firmware_value(hart, i),
+            kind => set_inhibit(kind, true),
+        }
+        hart.counters[i].started = false;
+        if flags & STOP_FLAG_RESET != 0 {
+            hart.counters[i] = IDLE;
+            if let Kind::Hpm(n) = kind {
+                let _ = probe::write_mhpmevent(n, 0);
+            }
+        }
+    }
+    error::ok(0)
+}
+
+fn counter_fw_read(index: usize) -> SbiRet {
+    if counters().get(index) != Some(&Kind::Firmware) {
+        return error::error(SBI_ERR_INVALID_PARAM)
+    }
+    let harts = HARTS.lock();
+    let hart = &harts[mhartid::read()];
+    if hart.counters[index].event.is_none() {
+        return error::error(SBI_ERR_INVALID_PARAM)
+    }
+    error::ok(firmware_value(hart, index))
+}
+
+fn start(hart: &mut Hart, kind: Kind, index: usize, initial: Option<usize>) {
+    match kind {
+        Kind::Firmware => {
+            let slot = hart.counters[index].event.and_then(|event| event_slot(event & 0xffff)).unwrap_or(0);
+            let counter = &mut hart.counters[index];
+            if let Some(value) = initial {
+                counter.value = value;
+            }
+            counter.base = hart.events[slot];
+        }
+        kind => {
+            if let Some(value) = initial {
+                write_hardware(kind, value);
+            }
+            set_inhibit(kind, false);
+        }
+    }
+    hart.counters[index].started = true;
+}
+
+// 固件计数器的值：运行时加上开始以来事件发生的次数
+fn firmware_value(hart: &Hart, index: usize) -> usize {
+    let counter = &hart.counters[index];
+    if !counter.started {
+        return counter.value
+    }
+    let slot = counter.event.and_then(|event| event_slot(event & 0xffff)).unwrap_or(0);
+    counter.value.wrapping_add(hart.events[slot].wrapping_sub(counter.base))
+}
@@ -0,0 +1,242 @@
+// 调试器使用的受保护的访存和CSR访问
+//
+// 访存前临时把mtvec换成probe_trap_entry。如果访存指令发生异常，陷入处理函数记下mcause，
+// 跳过这条指令后返回，调试器就可以报告错误，而不会进入给内核准备的陷入处理流程
+use crate::executor::{MSTATUS_MPP, MSTATUS_MPP_SUPERVISOR, MSTATUS_MXR, MSTATUS_SUM};
+
+// 没有发生异常时，t1寄存器保持这个值
+const NO_TRAP: usize = usize::MAX;
+
+macro_rules! guarded {
+    ($ins: expr, $($operands: tt)*) => {{
+        let cause: usize;
+        asm!(
+            // 陷入处理函数跳过4个字节，所以访存指令不能被压缩
+            ".option push",
+            ".option norvc",
+            $ins,
+            ".option pop",
+            $($operands)*,
+            inout("t1") NO_TRAP => cause,
+            out("t0") _,
+        );
+        cause
+    }};
+}
+
+/// 读取物理内存，返回零扩展后的值；发生异常时返回mcause
+pub fn load(paddr: usize, width: u8) -> Result<u128, usize> {
+    if width == 128 {
+        let low = load(paddr, 64)?;
+        let high = load(paddr.wrapping_add(8), 64)?;
+        return Ok(low | (high << 64))
+    }
+    let (cause, value) = with_probe_trap(|| unsafe {
+        let value: usize;
+        let cause = match width {
+            8 => guarded!("lbu {value}, 0({addr})", addr = in(reg) paddr, value = lateout(reg) value),
+            16 => guarded!("lhu {value}, 0({addr})", addr = in(reg) paddr, value = lateout(reg) value),
+            32 => guarded!("lwu {value}, 0({addr})", addr = in(reg) paddr, value = lateout(reg) value),
+            64 => guarded!("ld {value}, 0({addr})", addr = in(reg) paddr, value = lateout(reg) value),
+            _ => unreachable!()
+        };
+        (cause, value)
+    });
+    if cause == NO_TRAP {
+        Ok(value as u128)
+    } else {
+        Err(cause)
+    }
+}
+
+/// 写入物理内存；发生异常时返回mcause
+pub fn store(paddr: usize, width: u8, value: u128) -> Result<(), usize> {
+    if width == 128 {
+        store(paddr, 64, value)?;
+        return store(paddr.wrapping_add(8), 64, value >> 64)
+    }
+    let value = value as usize;
+    let cause = with_probe_trap(|| unsafe {
+        match width {
+            8 => guarded!("sb {value}, 0({addr})", addr = in(reg) paddr, value = in(reg) value),
+            16 => guarded!("sh {value}, 0({addr})", addr = in(reg) paddr, value = in(reg) value),
+            32 => guarded!("sw {value}, 0({addr})", addr = in(reg) paddr, value = in(reg) value),
+            64 => guarded!("sd {value}, 0({addr})", addr = in(reg) paddr, value = in(reg) value),
+            _ => unreachable!()
+        }
+    });
+    if cause == NO_TRAP {
+        Ok(())
+    } else {
+        Err(cause)
+    }
+}
+
+// 设置mstatus.MPRV后，读写按MPP表示的特权级翻译地址、检查权限，和被调试程序自己访问的效果相同。
+// 修改mstatus和访存必须在同一段汇编中完成，否则机器栈的访问也会被翻译
+const MSTATUS_MPRV: usize = 1 << 17;
+
+// 访存时的mstatus，特权级、SUM和MXR取自被调试程序
+fn mprv_mstatus(mstatus: usize) -> usize {
+    let current: usize;
+    unsafe { asm!("csrr {}, mstatus", out(reg) current) };
+    let mask = MSTATUS_MPP | MSTATUS_SUM | MSTATUS_MXR;
+    (current & !mask) | (mstatus & mask) | MSTATUS_MPRV
+}
+
+macro_rules! mprv {
+    ($ins: literal) => {
+        concat!("csrrw {old}, mstatus, {new}\n", $ins, "\ncsrw mstatus, {old}")
+    };
+}
+
+/// 按被调试程序的权限读取虚拟地址，`mstatus`为被调试程序的mstatus；发生异常时返回mcause
+pub fn load_virtual(address: usize, width: u8, mstatus: usize) -> Result<usize, usize> {
+    let new = mprv_mstatus(mstatus);
+    let (cause, value) = with_probe_trap(|| unsafe {
+        let value: usize;
+        let cause = match width {
+            8 => guarded!(mprv!("lbu {value}, 0({addr})"), addr = in(reg) address, value = lateout(reg) value, new = in(reg) new, old = out(reg) _),
+            16 => guarded!(mprv!("lhu {value}, 0({addr})"), addr = in(reg) address, value = lateout(reg) value, new = in(reg) new, old = out(reg) _),
+            32 => guarded!(mprv!("lwu {value}, 0({addr})"), addr = in(reg) address, value = lateout(reg) value, new = in(reg) new, old = out(reg) _),
+            64 => guarded!(mprv!("ld {value}, 0({addr})"), addr = in(reg) address, value = lateout(reg) value, new = in(reg) new, old = out(reg) _),
+            _ => unreachable!()
+        };
+        (cause, value)
+    });
+    if cause == NO_TRAP {
+        Ok(value)
+    } else {
+        Err(cause)
+    }
+}
+
+/// 按被调试程序的权限写入虚拟地址；发生异常时返回mcause
+pub fn store_virtual(address: usize, width: u8, value: usize, mstatus: usize) -> Result<(), usize> {
+    let new = mprv_mstatus(mstatus);
+    let cause = with_probe_trap(|| unsafe {
+        match width {
+            8 => guarded!(mprv!("sb {value}, 0({addr})"), addr = in(reg) address, value = in(reg) value, new = in(reg) new, old = out(reg) _),
+            16 => guarded!(mprv!("sh {value}, 0({addr})"), addr = in(reg) address, value = in(reg) value, new = in(reg) new, old = out(reg) _),
+            32 => guarded!(mprv!("sw {value}, 0({addr})"), addr = in(reg) address, value = in(reg) value, new = in(reg) new, old = out(reg) _),
+            64 => guarded!(mprv!("sd {value}, 0({addr})"), addr = in(reg) address, value = in(reg) value, new = in(reg) new, old = out(reg) _),
+            _ => unreachable!()
+        }
+    });
+    if cause == NO_TRAP {
+        Ok(())
+    } else {
+        Err(cause)
+    }
+}
+
+// S态访问物理地址时，MPP为S态，关闭地址翻译后只检查PMP
+
+/// 按S态的权限读取物理地址，检查PMP和地址是否存在；发生异常时返回mcause
+pub fn load_supervisor(paddr: usize, width: u8) -> Result<usize, usize> {
+    without_translation(|| load_virtual(paddr, width, MSTATUS_MPP_SUPERVISOR))
+}
+
+/// 按S态的权限写入物理地址；发生异常时返回mcause
+pub fn store_supervisor(paddr: usize, width: u8, value: usize) -> Result<(), usize> {
+    without_translation(|| store_virtual(paddr, width, value, MSTATUS_MPP_SUPERVISOR))
+}
+
+// satp只影响S态、U态和设置了MPRV的访存，M态自己的代码和栈不受影响；恢复成原来的值不需要刷新TLB
+fn without_translation<T, F: FnOnce() -> T>(f: F) -> T {
+    let satp: usize;
+    unsafe { asm!("csrrw {}, satp, zero", out(reg) satp) };
+    let ans = f();
+    unsafe { asm!("csrw satp, {}", in(reg) satp) };
+    ans
+}
+
+// 调试触发器的CSR不一定存在，访问时同样需要保护
+macro_rules! guarded_csr_read {
+    ($name: ident, $csrr: literal) => {
+        pub fn $name() -> Result<usize, usize> {
+            let (cause, value) = with_probe_trap(|| unsafe {
+                let value: usize;
+                let cause = guarded!($csrr, value = lateout(reg) value);
+                (cause, value)
+            });
+            if cause == NO_TRAP { Ok(value) } else { Err(cause) }
+        }
+    };
+}
+
+macro_rules! guarded_csr_write {
+    ($name: ident, $csrw: literal) => {
+        pub fn $name(value: usize) -> Result<(), usize> {
+            let cause = with_probe_trap(|| unsafe { guarded!($csrw, value = in(reg) value) });
+            if cause == NO_TRAP { Ok(()) } else { Err(cause) }
+        }
+    };
+}
+
+guarded_csr_read!(read_tselect, "csrr {value}, 0x7a0");
+guarded_csr_write!(write_tselect, "csrw 0x7a0, {value}");
+guarded_csr_read!(read_tdata1, "csrr {value}, 0x7a1");
+guarded_csr_write!(write_tdata1, "csrw 0x7a1, {value}");
+guarded_csr_read!(read_tdata2, "csrr {value}, 0x7a2");
+guarded_csr_write!(write_tdata2, "csrw 0x7a2, {value}");
+guarded_csr_read!(read_tinfo, "csrr {value}, 0x7a4");
+// 性能计数器的CSR也不一定存在
+guarded_csr_read!(read_mcountinhibit, "csrr {value}, 0x320");
+guarded_csr_write!(write_mcountinhibit, "csrw 0x320, {value}");
+// 有的平台上mcycle和minstret是只读的
+guarded_csr_write!(write_mcycle, "csrw 0xb00, {value}");
+guarded_csr_write!(write_minstret, "csrw 0xb02, {value}");
+
+/// 读取mhpmcounter3到mhpmcounter31；发生异常时返回mcause
+pub fn read_mhpmcounter(index: usize) -> Result<usize, usize> {
+    let (cause, value) = with_probe_trap(|| unsafe {
+        let value: usize;
+        let cause = register_match!(3..32, index, N: usize =>
+            guarded!("csrr {value}, {csr}", csr = const 0xb00 + N, value = lateout(reg) value));
+        (cause, value)
+    });
+    if cause == NO_TRAP { Ok(value) } else { Err(cause) }
+}
+
+/// 写入mhpmcounter3到mhpmcounter31；发生异常时返回mcause
+pub fn write_mhpmcounter(index: usize, value: usize) -> Result<(), usize> {
+    let cause = with_probe_trap(|| unsafe {
+        register_match!(3..32, index, N: usize => guarded!("csrw {csr}, {value}", csr = const 0xb00 + N, value = in(reg) value))
+    });
+    if cause == NO_TRAP { Ok(()) } else { Err(cause) }
+}
+
+/// 写入mhpmevent3到mhpmevent31，选择计数器统计的事件；发生异常时返回mcause
+pub fn write_mhpmevent(index: usize, value: usize) -> Result<(), usize> {
+    let cause = with_probe_trap(|| unsafe {
+        register_match!(3..32, index, N: usize => guarded!("csrw {csr}, {value}", csr = const 0x320 + N, value = in(reg) value))
+    });
+    if cause == NO_TRAP { Ok(()) } else { Err(cause) }
+}
+
+fn with_probe_trap<T, F: FnOnce() -> T>(f: F) -> T {
+    let mut entry = probe_trap_entry as usize;
+    if entry & 0x2 != 0 {
+        entry += 0x2; // 必须对齐到4个字节
+    }
+    let old_entry: usize;
+    unsafe { asm!("csrrw {}, mtvec, {}", lateout(reg) old_entry, in(reg) entry) };
+    let ans = f();
+    unsafe { asm!("csrw mtvec, {}", in(reg) old_entry) };
+    ans
+}
+
+#[naked]
+#[link_section = ".text"]
+unsafe extern "C" fn probe_trap_entry() -> ! {
+    asm!( // 从M态陷入，mstatus.MPP仍然是M态
+        ".p2align 2",
+        "csrr   t1, mcause
+        csrr    t0, mepc
+        addi    t0, t0, 4
+        csrw    mepc, t0
+        mret",
+        options(noreturn)
+    )
+}
@@ -1,29 +1,170 @@
-pub struct Reset;
+// 系统复位（SRST扩展），使用设备树中的测试设备，或者syscon-poweroff和syscon-reboot
+//
+// 复位前先让其它硬件线程停在M态。关机时QEMU的退出码说明关机的原因，
+// 测试脚本可以区分内核正常关机、内核报告的系统错误和调试大师自己的错误
+use crate::error::{self, SBI_ERR_INVALID_PARAM, SBI_ERR_NOT_SUPPORTED};
+use crate::{halt, hsm, ipi};
+use crate::platform::{self, ResetDevice};
+use rustsbi::reset::{RESET_TYPE_SHUTDOWN, RESET_TYPE_WARM_REBOOT, RESET_REASON_NO_REASON, RESET_REASON_SYSTEM_FAILURE};
+use riscv::register::mhartid;
+use rustsbi::println;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
+// 失败时高16位是QEMU的退出码
 const TEST_FAIL: u32 = 0x3333;
 const TEST_PASS: u32 = 0x5555;
 const TEST_RESET: u32 = 0x7777;
 
+/// 内核请求关机，没有说明原因
+pub const EXIT_SUCCESS: u32 = 0;
+/// 内核请求关机，原因是系统错误
+pub const EXIT_SYSTEM_FAILURE: u32 = 1;
+/// 调试大师自己panic或者内存不足
+pub const EXIT_FIRMWARE_FAILURE: u32 = 2;
+/// SBI实现或厂商自定义的关机原因
+pub const EXIT_OTHER_REASON: u32 = 3;
+
+const EXTENSION_SRST: usize = 0x5352_5354;
+const FUNCTION_SYSTEM_RESET: usize = 0;
+const EXTENSION_LEGACY_SHUTDOWN: usize = 0x08;
+// 复位类型从这里开始是厂商自定义的
+const RESET_TYPE_VENDOR: usize = 0xF000_0000;
+// 复位原因从这里开始是SBI实现和厂商自定义的
+const RESET_REASON_SBI: usize = 0xE000_0000;
+
+// 内核请求复位时先停在调试器中
+static STOP_IN_DEBUGGER: AtomicBool = AtomicBool::new(false);
+// 已经有硬件线程在复位系统
+static RESETTING: AtomicBool = AtomicBool::new(false);
+// 已经停在M态等待复位的硬件线程，每个硬件线程一位
+static QUIESCED: AtomicUsize = AtomicUsize::new(0);
+
+pub struct Reset;
+
 impl rustsbi::Reset for Reset {
     fn system_reset(&self, reset_type: usize, reset_reason: usize) -> rustsbi::SbiRet {
-        // todo: only exit after all harts finished
-        // loop {}
-        const VIRT_TEST: *mut u32 = 0x10_0000 as *mut u32;
-        // Fail = 0x3333,
-        // Pass = 0x5555,
-        // Reset = 0x7777,
-        let mut value = match reset_type {
-            rustsbi::reset::RESET_TYPE_SHUTDOWN => TEST_PASS,
-            rustsbi::reset::RESET_TYPE_COLD_REBOOT => TEST_RESET,
-            rustsbi::reset::RESET_TYPE_WARM_REBOOT => TEST_RESET,
-            _ => TEST_FAIL,
-        };
-        if reset_reason == rustsbi::reset::RESET_REASON_SYSTEM_FAILURE {
-            value = TEST_FAIL;
-        };
-        unsafe {
-            core::ptr::write_volatile(VIRT_TEST, value);
+        if reset_type >= RESET_TYPE_VENDOR && reset_type <= u32::MAX as usize {
+            return error::error(SBI_ERR_NOT_SUPPORTED)
         }
-        unreachable!()
+        let valid_reason = reset_reason <= RESET_REASON_SYSTEM_FAILURE
+            || (reset_reason >= RESET_REASON_SBI && reset_reason <= u32::MAX as usize);
+        if reset_type > RESET_TYPE_WARM_REBOOT || !valid_reason {
+            return error::error(SBI_ERR_INVALID_PARAM)
+        }
+        if !supported(reset_type) {
+            println!("[DebugSBI] No reset device");
+            return error::error(SBI_ERR_NOT_SUPPORTED)
+        }
+        reset(reset_type, reset_reason)
+    }
+}
+
+/// 这次SBI调用是否请求复位，返回复位类型和原因；参数不合法时仍然交给SBI调用返回错误
+pub fn requested(extension: usize, function: usize, param: [usize; 5]) -> Option<(usize, usize)> {
+    match (extension, function) {
+        (EXTENSION_SRST, FUNCTION_SYSTEM_RESET) => Some((param[0], param[1])),
+        (EXTENSION_LEGACY_SHUTDOWN, _) => Some((RESET_TYPE_SHUTDOWN, RESET_REASON_NO_REASON)),
+        _ => None,
+    }
+}
+
+/// 内核请求复位时是否先停在调试器中
+pub fn stops_in_debugger() -> bool {
+    STOP_IN_DEBUGGER.load(Ordering::Relaxed)
+}
+
+pub fn set_stop_in_debugger(stop: bool) {
+    STOP_IN_DEBUGGER.store(stop, Ordering::Relaxed);
+}
+
+/// 让其它硬件线程停在M态，然后复位或关机
+pub fn reset(reset_type: usize, reset_reason: usize) -> ! {
+    // 两个硬件线程同时复位时，后来的硬件线程等待先来的硬件线程复位
+    if RESETTING.swap(true, Ordering::AcqRel) {
+        quiesce()
+    }
+    quiesce_others();
+    if reset_type == RESET_TYPE_SHUTDOWN {
+        shutdown(match reset_reason {
+            RESET_REASON_NO_REASON => EXIT_SUCCESS,
+            RESET_REASON_SYSTEM_FAILURE => EXIT_SYSTEM_FAILURE,
+            _ => EXIT_OTHER_REASON,
+        })
+    } else {
+        reboot()
+    }
+}
+
+/// 调试大师自己出错时直接退出QEMU；其它硬件线程可能持有锁，不等待它们
+pub fn firmware_failure() -> ! {
+    shutdown(EXIT_FIRMWARE_FAILURE)
+}
+
+/// 其它硬件线程正在复位系统，停在M态等待；处理M态软件中断时调用
+pub fn quiesce() -> ! {
+    QUIESCED.fetch_or(1 << mhartid::read(), Ordering::AcqRel);
+    loop {
+        unsafe { asm!("wfi") };
+    }
+}
+
+fn quiesce_others() {
+    // 停在调试器中和用HSM停止的硬件线程已经在M态等待了，不会再进入内核
+    let targets = hsm::interruptible_harts() & !halt::stopped_harts() & !(1 << mhartid::read());
+    ipi::request_reset(targets);
+    let deadline = crate::CLINT.mtime() + platform::get().timebase_frequency / 10;
+    while QUIESCED.load(Ordering::Acquire) & targets != targets && crate::CLINT.mtime() < deadline {
+        // 其它硬件线程可能在等这个硬件线程完成远程栅栏
+        ipi::handle_soft_interrupt();
+    }
+}
+
+// 退出码为0时QEMU对FAIL也返回0，所以成功时使用PASS
+fn exit_value(code: u32) -> u32 {
+    if code == EXIT_SUCCESS { TEST_PASS } else { (code << 16) | TEST_FAIL }
+}
+
+/// 平台能否执行这种复位
+pub fn supported(reset_type: usize) -> bool {
+    match platform::get().reset {
+        ResetDevice::SifiveTest(_) => true,
+        ResetDevice::Syscon { poweroff, reboot } =>
+            if reset_type == RESET_TYPE_SHUTDOWN { poweroff.is_some() } else { reboot.is_some() },
+        ResetDevice::None => false,
+    }
+}
+
+// 只有测试设备能设置QEMU的退出码，syscon-poweroff不区分关机的原因
+fn shutdown(code: u32) -> ! {
+    match platform::get().reset {
+        ResetDevice::SifiveTest(base) => write(base, exit_value(code)),
+        ResetDevice::Syscon { poweroff: Some((address, value)), .. } => write(address, value),
+        _ => no_device(),
+    }
+}
+
+fn reboot() -> ! {
+    match platform::get().reset {
+        ResetDevice::SifiveTest(base) => write(base, TEST_RESET),
+        ResetDevice::Syscon { reboot: Some((address, value)), .. } => write(address, value),
+        _ => no_device(),
+    }
+}
+
+fn write(address: usize, value: u32) -> ! {
+    unsafe { core::ptr::write_volatile(address as *mut u32, value) };
+    wait()
+}
+
+// 调试大师自己要求复位时不能返回，只能停在这里
+fn no_device() -> ! {
+    println!("[DebugSBI] No reset device");
+    wait()
+}
+
+// 复位需要一点时间，在这之前不能返回
+fn wait() -> ! {
+    loop {
+        unsafe { asm!("wfi") };
     }
 }
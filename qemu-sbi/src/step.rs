@@ -0,0 +1,17 @@
+// 单步执行需要的指令分析，解码在tiaoshi-dashi-decode中，这里读取被调试程序的寄存器
+use crate::executor::KernelContext;
+use alloc::vec::Vec;
+use riscv::register::sepc;
+use tiaoshi_dashi_decode::step;
+
+pub use tiaoshi_dashi_decode::step::{is_call, MemoryAccess};
+
+/// 指令执行后可能到达的所有地址，不包括陷入的情况
+pub fn successors(ins: u32, pc: usize, ctx: &KernelContext) -> Vec<usize> {
+    step::successors(ins, pc, |index| ctx.x(index), sepc::read())
+}
+
+/// 解码访存指令，计算访问的地址；不是访存指令时返回None
+pub fn memory_access(ins: u32, ctx: &KernelContext) -> Option<MemoryAccess> {
+    step::memory_access(ins, |index| ctx.x(index))
+}
@@ -0,0 +1,170 @@
+// 调试大师的厂商扩展，内核通过它和调试器合作
+//
+// 内核可以登记符号表、在自己身上设置断点和观察点、带着消息停在调试器中，以及公布任务列表。
+// 参数中的地址都是内核当前地址空间中的虚拟地址，按内核自己的权限访问
+use crate::executor::KernelContext;
+use crate::error::{self, SBI_ERR_FAILED, SBI_ERR_INVALID_ADDRESS, SBI_ERR_INVALID_PARAM, SBI_ERR_NOT_SUPPORTED};
+use crate::ipi::MAX_HARTS;
+use crate::watchpoint::{self, WatchKind, WatchError};
+use crate::{breakpoint, debug, probe};
+use riscv::register::mhartid;
+use rustsbi::SbiRet;
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+const EXTENSION_BASE: usize = 0x10;
+const FUNCTION_PROBE_EXTENSION: usize = 3;
+/// 厂商扩展的编号在0x09000000到0x09FFFFFF之间；后三个字节是“TSD”
+pub const EXTENSION_TIAOSHI: usize = 0x0954_5344;
+const FUNCTION_REGISTER_SYMBOLS: usize = 0;
+const FUNCTION_SET_BREAKPOINT: usize = 1;
+const FUNCTION_SET_WATCHPOINT: usize = 2;
+const FUNCTION_CLEAR: usize = 3;
+const FUNCTION_STOP: usize = 4;
+const FUNCTION_SET_TASK_LIST: usize = 5;
+
+// 符号表最多这么多字节；M态的堆很小，只保存原始文本和每个符号的位置
+const SYMBOLS_LIMIT: usize = 16 * 1024;
+// 符号表没有给出符号的大小时，离符号超过这么多字节的地址不属于这个符号
+const SYMBOL_OFFSET_LIMIT: usize = 0x1_0000;
+// 停下时的消息最多打印这么多字节
+pub const MESSAGE_LIMIT: usize = 256;
+
+// 内核登记的符号表，按地址排序
+struct SymbolTable {
+    text: String,
+    // 地址，大小（0表示不知道），名称在text中的起止位置
+    symbols: Vec<(usize, usize, usize, usize)>,
+}
+
+static SYMBOLS: Mutex<Option<SymbolTable>> = Mutex::new(None);
+// 内核公布的任务列表的虚拟地址，见MANUAL
+static TASK_LIST: Mutex<Option<usize>> = Mutex::new(None);
+// SBI调用返回后才停在调试器中，这样pc指向ecall的下一条指令；保存消息的地址和长度
+static PENDING_STOPS: Mutex<[Option<(usize, usize)>; MAX_HARTS]> = Mutex::new([None; MAX_HARTS]);
+
+/// 处理调试大师扩展的SBI调用；其它调用返回None
+pub fn ecall(ctx: &KernelContext, extension: usize, function: usize, param: [usize; 5]) -> Option<SbiRet> {
+    let ans = match (extension, function) {
+        // RustSBI不知道这个扩展，探测时由这里回答
+        (EXTENSION_BASE, FUNCTION_PROBE_EXTENSION) if param[0] == EXTENSION_TIAOSHI => error::ok(1),
+        (EXTENSION_TIAOSHI, FUNCTION_REGISTER_SYMBOLS) => register_symbols(ctx, param[0], param[1]),
+        (EXTENSION_TIAOSHI, FUNCTION_SET_BREAKPOINT) => match debug::add_breakpoint(ctx, param[0]) {
+            Some(number) => error::ok(number),
+            None => error::error(SBI_ERR_INVALID_ADDRESS),
+        },
+        (EXTENSION_TIAOSHI, FUNCTION_SET_WATCHPOINT) => set_watchpoint(param[0], param[1], param[2]),
+        (EXTENSION_TIAOSHI, FUNCTION_CLEAR) => {
+            if breakpoint::delete(param[0]) || watchpoint::delete(param[0]) {
+                error::ok(0)
+            } else {
+                error::error(SBI_ERR_INVALID_PARAM)
+            }
+        }
+        (EXTENSION_TIAOSHI, FUNCTION_STOP) => {
+            PENDING_STOPS.lock()[mhartid::read()] = Some((param[0], core::cmp::min(param[1], MESSAGE_LIMIT)));
+            error::ok(0)
+        }
+        (EXTENSION_TIAOSHI, FUNCTION_SET_TASK_LIST) => {
+            *TASK_LIST.lock() = if param[0] == 0 { None } else { Some(param[0]) };
+            error::ok(0)
+        }
+        (EXTENSION_TIAOSHI, _) => error::error(SBI_ERR_NOT_SUPPORTED),
+        _ => return None,
+    };
+    Some(ans)
+}
+
+/// 内核请求停在调试器中，返回消息的地址和长度
+pub fn take_stop(hart_id: usize) -> Option<(usize, usize)> {
+    PENDING_STOPS.lock()[hart_id].take()
+}
+
+/// 内核公布的任务列表
+pub fn task_list() -> Option<usize> {
+    *TASK_LIST.lock()
+}
+
+/// 查找地址所在的符号，返回符号名称和偏移；地址在符号之后的空隙中时返回None
+pub fn lookup(address: usize) -> Option<(String, usize)> {
+    let table = SYMBOLS.lock();
+    let table = table.as_ref()?;
+    let index = match table.symbols.binary_search_by_key(&address, |&(symbol, _, _, _)| symbol) {
+        Ok(index) => index,
+        Err(0) => return None,
+        Err(index) => index - 1,
+    };
+    let (symbol, size, start, end) = table.symbols[index];
+    let offset = address - symbol;
+    let limit = if size != 0 { size } else { SYMBOL_OFFSET_LIMIT };
+    if offset >= limit {
+        return None
+    }
+    Some((String::from(&table.text[start..end]), offset))
+}
+
+// 符号表是文本，每行一个符号，格式和nm的输出相同：十六进制地址、可选的类型字母、名称。
+// nm -S的输出在地址之后还有十六进制的大小。不能识别的行被忽略。
+// 长度为0时清除符号表，返回登记的符号个数
+fn register_symbols(ctx: &KernelContext, address: usize, len: usize) -> SbiRet {
+    if len == 0 {
+        *SYMBOLS.lock() = None;
+        return error::ok(0)
+    }
+    if len > SYMBOLS_LIMIT {
+        return error::error(SBI_ERR_INVALID_PARAM)
+    }
+    let mut bytes = Vec::with_capacity(len);
+    for offset in 0..len {
+        match probe::load_virtual(address.wrapping_add(offset), 8, ctx.mstatus) {
+            Ok(byte) => bytes.push(byte as u8),
+            Err(_) => return error::error(SBI_ERR_INVALID_ADDRESS),
+        }
+    }
+    let text = match String::from_utf8(bytes) {
+        Ok(text) => text,
+        Err(_) => return error::error(SBI_ERR_INVALID_PARAM),
+    };
+    let mut symbols = Vec::new();
+    let mut start = 0;
+    for line in text.split('\n') {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let hex = |word: &str| usize::from_str_radix(word.trim_start_matches("0x"), 16).ok();
+        let symbol = words.first().and_then(|&word| hex(word));
+        // 地址、大小、类型和名称
+        let size = if words.len() == 4 { hex(words[1]).unwrap_or(0) } else { 0 };
+        if let (Some(symbol), Some(name), true) = (symbol, words.last(), words.len() >= 2) {
+            // name是line的一部分，算出它在text中的位置
+            let name_start = start + (name.as_ptr() as usize - line.as_ptr() as usize);
+            symbols.push((symbol, size, name_start, name_start + name.len()));
+        }
+        start += line.len() + 1;
+    }
+    symbols.sort_by_key(|&(symbol, _, _, _)| symbol);
+    let count = symbols.len();
+    *SYMBOLS.lock() = Some(SymbolTable { text, symbols });
+    error::ok(count)
+}
+
+// 种类：0为写，1为读，2为读写；其它硬件线程下次返回内核前写入这个观察点
+fn set_watchpoint(address: usize, len: usize, kind: usize) -> SbiRet {
+    let kind = match kind {
+        0 => WatchKind::Write,
+        1 => WatchKind::Read,
+        2 => WatchKind::Access,
+        _ => return error::error(SBI_ERR_INVALID_PARAM),
+    };
+    if !len.is_power_of_two() || address % len != 0 {
+        return error::error(SBI_ERR_INVALID_PARAM)
+    }
+    if let Some(number) = watchpoint::number_of(address, len, kind) {
+        return error::ok(number)
+    }
+    match watchpoint::add(address, len, kind) {
+        Ok(number) => error::ok(number),
+        Err(WatchError::NoTriggers) => error::error(SBI_ERR_NOT_SUPPORTED),
+        Err(WatchError::Busy(_)) => error::error(SBI_ERR_FAILED),
+        Err(WatchError::Unsupported) => error::error(SBI_ERR_INVALID_PARAM),
+    }
+}
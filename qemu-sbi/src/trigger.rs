@@ -0,0 +1,169 @@
+// RISC-V调试规范中的触发器模块（Sdtrig）
+//
+// 触发器的动作都设置为断点异常。触发时被调试程序陷入M态，和执行ebreak一样进入调试器
+use crate::probe;
+use spin::Mutex;
+
+const MAX_TRIGGERS: usize = 32;
+
+pub const TYPE_MCONTROL: usize = 2;
+pub const TYPE_ICOUNT: usize = 3;
+pub const TYPE_MCONTROL6: usize = 6;
+
+// RV64下tdata1的类型字段
+const TDATA1_TYPE_SHIFT: usize = 60;
+
+// icount触发器的字段
+const ICOUNT_COUNT_SHIFT: usize = 10;
+const ICOUNT_S: usize = 1 << 7;
+const ICOUNT_U: usize = 1 << 6;
+
+// mcontrol和mcontrol6共有的字段，action为0，即断点异常
+const MCONTROL_MATCH_SHIFT: usize = 7;
+const MCONTROL_MATCH_MASK: usize = 0xf << MCONTROL_MATCH_SHIFT;
+const MCONTROL_S: usize = 1 << 4;
+const MCONTROL_U: usize = 1 << 3;
+const MCONTROL_STORE: usize = 1 << 1;
+const MCONTROL_LOAD: usize = 1 << 0;
+const MATCH_EQUAL: usize = 0;
+const MATCH_NAPOT: usize = 1;
+// 触发器命中后由硬件置位，不一定实现
+const MCONTROL_HIT: usize = 1 << 20;
+const MCONTROL6_HIT0: usize = 1 << 22;
+
+struct Triggers {
+    // None表示还没有探测过
+    count: Option<usize>,
+    used: [bool; MAX_TRIGGERS],
+}
+
+static TRIGGERS: Mutex<Triggers> = Mutex::new(Triggers { count: None, used: [false; MAX_TRIGGERS] });
+
+/// 当前硬件线程上触发器的个数，第一次调用时探测
+pub fn count() -> usize {
+    let mut triggers = TRIGGERS.lock();
+    probed_count(&mut triggers)
+}
+
+fn probed_count(triggers: &mut Triggers) -> usize {
+    if let Some(count) = triggers.count {
+        return count
+    }
+    let mut count = 0;
+    while count < MAX_TRIGGERS {
+        if probe::write_tselect(count).is_err() {
+            break
+        }
+        match probe::read_tselect() {
+            Ok(index) if index == count => {}
+            _ => break
+        }
+        // tinfo为1说明这个编号上没有触发器
+        if let Ok(1) = probe::read_tinfo() {
+            break
+        }
+        count += 1;
+    }
+    triggers.count = Some(count);
+    count
+}
+
+// 触发器是否支持给定的类型
+fn supports(index: usize, ty: usize) -> bool {
+    if probe::write_tselect(index).is_err() {
+        return false
+    }
+    match probe::read_tinfo() {
+        Ok(info) => (info >> ty) & 1 != 0,
+        // 没有tinfo时，写入类型后读回检查
+        Err(_) => {
+            if probe::write_tdata1(ty << TDATA1_TYPE_SHIFT).is_err() {
+                return false
+            }
+            let ans = matches!(probe::read_tdata1(), Ok(tdata1) if tdata1 >> TDATA1_TYPE_SHIFT == ty);
+            let _ = probe::write_tdata1(0);
+            ans
+        }
+    }
+}
+
+/// 分配一个空闲的、支持给定类型的触发器，返回触发器编号和类型
+pub fn allocate(types: &[usize]) -> Option<(usize, usize)> {
+    let mut triggers = TRIGGERS.lock();
+    let count = probed_count(&mut triggers);
+    for index in 0..count {
+        if triggers.used[index] {
+            continue
+        }
+        if let Some(&ty) = types.iter().find(|&&ty| supports(index, ty)) {
+            triggers.used[index] = true;
+            return Some((index, ty))
+        }
+    }
+    None
+}
+
+/// 写入触发器的配置，写入失败或硬件不支持这样的配置时返回false
+pub fn program(index: usize, tdata1: usize, tdata2: usize) -> bool {
+    // 先关闭触发器，避免配置到一半时触发
+    let written = probe::write_tselect(index).is_ok()
+        && probe::write_tdata1(0).is_ok()
+        && probe::write_tdata2(tdata2).is_ok()
+        && probe::write_tdata1(tdata1).is_ok();
+    // 只读字段（如mcontrol的maskmax）可能不为零，只检查写入的位和匹配方式
+    written && matches!(probe::read_tdata1(), Ok(value) if value & (tdata1 | MCONTROL_MATCH_MASK) == tdata1)
+        && probe::read_tdata2() == Ok(tdata2)
+}
+
+/// 关闭并释放触发器
+pub fn free(index: usize) {
+    let mut triggers = TRIGGERS.lock();
+    if probe::write_tselect(index).is_ok() {
+        let _ = probe::write_tdata1(0);
+    }
+    triggers.used[index] = false;
+}
+
+/// 设置icount触发器，让S态或U态执行一条指令后进入调试器；不支持时返回None
+pub fn arm_icount() -> Option<usize> {
+    let (index, _) = allocate(&[TYPE_ICOUNT])?;
+    let tdata1 = (TYPE_ICOUNT << TDATA1_TYPE_SHIFT) | (1 << ICOUNT_COUNT_SHIFT) | ICOUNT_S | ICOUNT_U;
+    if program(index, tdata1, 0) {
+        Some(index)
+    } else {
+        free(index);
+        None
+    }
+}
+
+/// 地址匹配触发器的tdata1和tdata2，在S态或U态访问[address, address + len)时触发
+///
+/// `len`必须是2的幂，`address`必须对齐到`len`
+pub fn address_match(ty: usize, address: usize, len: usize, load: bool, store: bool) -> (usize, usize) {
+    let mut tdata1 = (ty << TDATA1_TYPE_SHIFT) | MCONTROL_S | MCONTROL_U;
+    if load {
+        tdata1 |= MCONTROL_LOAD;
+    }
+    if store {
+        tdata1 |= MCONTROL_STORE;
+    }
+    if len == 1 {
+        (tdata1 | (MATCH_EQUAL << MCONTROL_MATCH_SHIFT), address)
+    } else {
+        // NAPOT：低位连续的1表示范围大小，len为2^M时有M-1个1
+        (tdata1 | (MATCH_NAPOT << MCONTROL_MATCH_SHIFT), address | ((len >> 1) - 1))
+    }
+}
+
+/// 触发器的hit位是否置位
+pub fn hit(index: usize, ty: usize) -> bool {
+    let mask = if ty == TYPE_MCONTROL6 { MCONTROL6_HIT0 } else { MCONTROL_HIT };
+    probe::write_tselect(index).is_ok() && matches!(probe::read_tdata1(), Ok(tdata1) if tdata1 & mask != 0)
+}
+
+/// 关闭触发器，但不释放
+pub fn disable(index: usize) {
+    if probe::write_tselect(index).is_ok() {
+        let _ = probe::write_tdata1(0);
+    }
+}
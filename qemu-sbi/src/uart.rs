@@ -1,6 +1,58 @@
 use core::convert::Infallible;
 use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use embedded_hal::serial::{Read, Write};
+use spin::Mutex;
+
+// 串口输入Ctrl+C时停止内核，进入调试器
+const CTRL_C: u8 = 0x03;
+const RECEIVE_BUFFER_SIZE: usize = 256;
+// GDB连接时发送的第一个包的开头。内核运行时在串口上收到它，就停止内核，交给GDB处理
+pub const GDB_ATTACH: &[u8] = b"$qSupported";
+
+/// 接收中断中收到的调试请求
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Request {
+    // Ctrl+C
+    Interrupt,
+    // GDB的qSupported包，包的开头已经被读走
+    GdbAttach,
+}
+
+// 接收中断中读出、还没有交给内核的字节
+struct ReceiveBuffer {
+    data: [u8; RECEIVE_BUFFER_SIZE],
+    head: usize,
+    len: usize,
+}
+
+impl ReceiveBuffer {
+    fn push(&mut self, word: u8) {
+        if self.len == RECEIVE_BUFFER_SIZE {
+            return // 缓冲区满了，丢弃新的输入
+        }
+        self.data[(self.head + self.len) % RECEIVE_BUFFER_SIZE] = word;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None
+        }
+        let word = self.data[self.head];
+        self.head = (self.head + 1) % RECEIVE_BUFFER_SIZE;
+        self.len -= 1;
+        Some(word)
+    }
+}
+
+static RECEIVED: Mutex<ReceiveBuffer> = Mutex::new(ReceiveBuffer { data: [0; RECEIVE_BUFFER_SIZE], head: 0, len: 0 });
+static CTRL_C_PENDING: AtomicBool = AtomicBool::new(false);
+// 和GDB_ATTACH开头相同的字节先不交给内核，这里记录已经匹配的长度
+static ATTACH_MATCHED: AtomicUsize = AtomicUsize::new(0);
+// 接收中断处理函数使用的串口地址，打开接收中断时设置
+static INTERRUPT_BASE: AtomicUsize = AtomicUsize::new(0);
+static INTERRUPT_SHIFT: AtomicUsize = AtomicUsize::new(0);
 
 pub struct Ns16550a {
     base: usize,
@@ -26,11 +78,96 @@ impl Ns16550a {
             write_volatile((base + (offsets::IER << shift)) as *mut u8, 0);
             write_volatile((base + (offsets::FCR << shift)) as *mut u8, 0x7); // FIFO enable + FIFO reset
 
-            // No interrupt for now
+            // 接收中断由enable_receive_interrupt打开
         }
         // init finished
         Self { base, shift }
     }
+
+    /// 打开接收中断，中断到来时应当调用`receive_interrupt`
+    pub fn enable_receive_interrupt(&mut self) {
+        INTERRUPT_BASE.store(self.base, Ordering::Relaxed);
+        INTERRUPT_SHIFT.store(self.shift, Ordering::Relaxed);
+        unsafe {
+            // OUT2连接着16550的中断输出
+            write_volatile((self.base + (offsets::MCR << self.shift)) as *mut u8, masks::OUT2);
+            write_volatile((self.base + (offsets::IER << self.shift)) as *mut u8, masks::ERBFI);
+        }
+    }
+
+    // 从硬件读一个字节，Ctrl+C不交给内核，只记下来
+    fn read_hardware(&self) -> Option<u8> {
+        loop {
+            let word = read_byte(self.base, self.shift)?;
+            if word == CTRL_C {
+                CTRL_C_PENDING.store(true, Ordering::Relaxed);
+            } else {
+                return Some(word)
+            }
+        }
+    }
+}
+
+fn read_byte(base: usize, shift: usize) -> Option<u8> {
+    let pending = unsafe { read_volatile((base + (offsets::LSR << shift)) as *const u8) } & masks::DR;
+    if pending != 0 {
+        Some(unsafe { read_volatile((base + (offsets::RBR << shift)) as *const u8) })
+    } else {
+        None
+    }
+}
+
+/// 接收中断的处理函数：读出串口中的字节放入缓冲区，返回收到的调试请求
+pub fn receive_interrupt() -> Option<Request> {
+    let (base, shift) = (INTERRUPT_BASE.load(Ordering::Relaxed), INTERRUPT_SHIFT.load(Ordering::Relaxed));
+    let mut received = RECEIVED.lock();
+    let mut matched = ATTACH_MATCHED.load(Ordering::Relaxed);
+    let mut request = None;
+    while let Some(word) = read_byte(base, shift) {
+        if word == CTRL_C {
+            CTRL_C_PENDING.store(true, Ordering::Relaxed);
+            continue
+        }
+        if word == GDB_ATTACH[matched] {
+            matched += 1;
+            if matched == GDB_ATTACH.len() {
+                // 包剩下的部分留在串口中，由调试器读取
+                matched = 0;
+                request = Some(Request::GdbAttach);
+                break
+            }
+            continue
+        }
+        // 匹配失败，先前扣下的字节还给内核
+        for &held in &GDB_ATTACH[..matched] {
+            received.push(held);
+        }
+        matched = 0;
+        if word == GDB_ATTACH[0] {
+            matched = 1;
+        } else {
+            received.push(word);
+        }
+    }
+    ATTACH_MATCHED.store(matched, Ordering::Relaxed);
+    if request.is_none() && take_ctrl_c() {
+        request = Some(Request::Interrupt);
+    }
+    request
+}
+
+/// 不等待地读一个交给内核的字节，没有输入时返回None
+pub fn try_read() -> Option<u8> {
+    if let Some(word) = RECEIVED.lock().pop() {
+        return Some(word)
+    }
+    let serial = Ns16550a { base: INTERRUPT_BASE.load(Ordering::Relaxed), shift: INTERRUPT_SHIFT.load(Ordering::Relaxed) };
+    serial.read_hardware()
+}
+
+/// 取出并清除Ctrl+C请求
+pub fn take_ctrl_c() -> bool {
+    CTRL_C_PENDING.swap(false, Ordering::Relaxed)
 }
 
 impl Read<u8> for Ns16550a {
@@ -38,16 +175,11 @@ impl Read<u8> for Ns16550a {
     type Error = Infallible;
 
     fn try_read(&mut self) -> nb::Result<u8, Self::Error> {
-        let pending =
-            unsafe { read_volatile((self.base + (offsets::LSR << self.shift)) as *const u8) }
-                & masks::DR;
-        if pending != 0 {
-            let word =
-                unsafe { read_volatile((self.base + (offsets::RBR << self.shift)) as *const u8) };
-            Ok(word)
-        } else {
-            Err(nb::Error::WouldBlock)
+        // 先交出接收中断已经读出的字节
+        if let Some(word) = RECEIVED.lock().pop() {
+            return Ok(word)
         }
+        self.read_hardware().ok_or(nb::Error::WouldBlock)
     }
 }
 
@@ -91,4 +223,8 @@ mod offsets {
 mod masks {
     pub const THRE: u8 = 1 << 5;
     pub const DR: u8 = 1;
+    // IER：接收数据可用中断
+    pub const ERBFI: u8 = 1;
+    // MCR：中断输出使能
+    pub const OUT2: u8 = 1 << 3;
 }
@@ -0,0 +1,206 @@
+// 硬件观察点
+//
+// 观察点使用Sdtrig的mcontrol或mcontrol6触发器，只在S态和U态访存时触发，
+// 调试器自己在M态访问内存时不会触发。触发时访存指令还没有执行。
+//
+// 触发器是每个硬件线程自己的。调试器只能写入自己所在硬件线程的触发器，
+// 其它硬件线程返回内核前发现观察点变化了，再写入自己的触发器
+use crate::{breakpoint, trigger};
+use crate::ipi::MAX_HARTS;
+use alloc::vec::Vec;
+use riscv::register::mhartid;
+use spin::Mutex;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchKind {
+    // watch：写入时触发
+    Write,
+    // rwatch：读取时触发
+    Read,
+    // awatch：读取或写入时触发
+    Access,
+}
+
+impl WatchKind {
+    fn load(self) -> bool {
+        self != WatchKind::Write
+    }
+
+    fn store(self) -> bool {
+        self != WatchKind::Read
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Watchpoint {
+    pub number: usize,
+    /// 观察的虚拟地址范围[address, address + len)
+    pub address: usize,
+    pub len: usize,
+    pub kind: WatchKind,
+    pub enabled: bool,
+    trigger: usize,
+    ty: usize,
+}
+
+impl Watchpoint {
+    pub fn contains(&self, address: usize) -> bool {
+        address.wrapping_sub(self.address) < self.len
+    }
+
+    /// 访问[address, address + width)是否会触发这个观察点
+    pub fn covers(&self, address: usize, width: usize, store: bool) -> bool {
+        let kind_matches = if store { self.kind.store() } else { self.kind.load() };
+        kind_matches && address < self.address.wrapping_add(self.len) && self.address < address.wrapping_add(width)
+    }
+
+    fn arm(&self) -> bool {
+        let (tdata1, tdata2) = trigger::address_match(self.ty, self.address, self.len, self.kind.load(), self.kind.store());
+        trigger::program(self.trigger, tdata1, tdata2)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchError {
+    // 硬件线程上没有触发器
+    NoTriggers,
+    // 所有支持地址匹配的触发器都已经被占用；参数为触发器个数
+    Busy(usize),
+    // 触发器不支持观察这样的地址范围
+    Unsupported,
+}
+
+static WATCHPOINTS: Mutex<Vec<Watchpoint>> = Mutex::new(Vec::new());
+// 添加、删除、启用或禁用观察点时加一
+static GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone, Copy)]
+struct Armed {
+    // 这个硬件线程的触发器对应的观察点版本
+    generation: usize,
+    // 为观察点写入过的触发器，每个触发器一位
+    triggers: usize,
+}
+
+static ARMED: Mutex<[Armed; MAX_HARTS]> = Mutex::new([Armed { generation: 0, triggers: 0 }; MAX_HARTS]);
+
+/// 添加观察点，返回编号
+pub fn add(address: usize, len: usize, kind: WatchKind) -> Result<usize, WatchError> {
+    let count = trigger::count();
+    if count == 0 {
+        return Err(WatchError::NoTriggers)
+    }
+    let (index, ty) = match trigger::allocate(&[trigger::TYPE_MCONTROL6, trigger::TYPE_MCONTROL]) {
+        Some(ans) => ans,
+        None => return Err(WatchError::Busy(count)),
+    };
+    let mut watchpoint = Watchpoint { number: 0, address, len, kind, enabled: true, trigger: index, ty };
+    if !watchpoint.arm() {
+        trigger::free(index);
+        return Err(WatchError::Unsupported)
+    }
+    watchpoint.number = breakpoint::next_number();
+    let number = watchpoint.number;
+    let mut list = WATCHPOINTS.lock();
+    list.push(watchpoint);
+    changed(&list);
+    Ok(number)
+}
+
+/// 删除观察点，观察点不存在时返回false
+pub fn delete(number: usize) -> bool {
+    let mut list = WATCHPOINTS.lock();
+    match list.iter().position(|wp| wp.number == number) {
+        Some(index) => {
+            let wp = list.remove(index);
+            trigger::free(wp.trigger);
+            changed(&list);
+            true
+        }
+        None => false,
+    }
+}
+
+/// 启用或禁用观察点，观察点不存在时返回false
+pub fn set_enabled(number: usize, enabled: bool) -> bool {
+    let mut list = WATCHPOINTS.lock();
+    match list.iter_mut().find(|wp| wp.number == number) {
+        Some(wp) => {
+            wp.enabled = enabled;
+            if enabled {
+                wp.arm();
+            } else {
+                trigger::disable(wp.trigger);
+            }
+            changed(&list);
+            true
+        }
+        None => false,
+    }
+}
+
+pub fn list() -> Vec<Watchpoint> {
+    WATCHPOINTS.lock().clone()
+}
+
+/// 观察给定地址范围和种类的观察点编号
+pub fn number_of(address: usize, len: usize, kind: WatchKind) -> Option<usize> {
+    WATCHPOINTS.lock().iter()
+        .find(|wp| wp.address == address && wp.len == len && wp.kind == kind)
+        .map(|wp| wp.number)
+}
+
+/// 找到hit位置位的观察点
+pub fn find_hit() -> Option<Watchpoint> {
+    WATCHPOINTS.lock().iter().find(|wp| wp.enabled && trigger::hit(wp.trigger, wp.ty)).cloned()
+}
+
+/// 找到访问[address, address + width)时会触发的观察点
+pub fn find_covering(address: usize, width: usize, store: bool) -> Option<Watchpoint> {
+    WATCHPOINTS.lock().iter().find(|wp| wp.enabled && wp.covers(address, width, store)).cloned()
+}
+
+/// 暂时关闭所有观察点，让触发观察点的指令可以执行
+pub fn disarm_all() {
+    for wp in WATCHPOINTS.lock().iter() {
+        trigger::disable(wp.trigger);
+    }
+}
+
+/// 重新写入所有启用的观察点，同时清除hit位
+pub fn arm_all() {
+    for wp in WATCHPOINTS.lock().iter().filter(|wp| wp.enabled) {
+        wp.arm();
+    }
+}
+
+// 调试器所在的硬件线程已经写入了自己的触发器，其它硬件线程之后再同步
+fn changed(list: &[Watchpoint]) {
+    let generation = GENERATION.fetch_add(1, Ordering::Release) + 1;
+    ARMED.lock()[mhartid::read()] = Armed { generation, triggers: enabled_triggers(list) };
+}
+
+fn enabled_triggers(list: &[Watchpoint]) -> usize {
+    list.iter().filter(|wp| wp.enabled).fold(0, |mask, wp| mask | (1 << wp.trigger))
+}
+
+/// 观察点变化后，把当前硬件线程的触发器改成和调试器所在的硬件线程一样；每次返回内核前调用
+pub fn sync() {
+    let hart_id = mhartid::read();
+    if ARMED.lock()[hart_id].generation == GENERATION.load(Ordering::Acquire) {
+        return
+    }
+    let list = WATCHPOINTS.lock();
+    let generation = GENERATION.load(Ordering::Acquire);
+    let triggers = enabled_triggers(&list);
+    let mut armed = ARMED.lock();
+    // 已经删除或禁用的观察点
+    for index in (0..core::mem::size_of::<usize>() * 8).filter(|i| ((armed[hart_id].triggers & !triggers) >> i) & 1 != 0) {
+        trigger::disable(index);
+    }
+    for wp in list.iter().filter(|wp| wp.enabled) {
+        wp.arm();
+    }
+    armed[hart_id] = Armed { generation, triggers };
+}
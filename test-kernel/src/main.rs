@@ -7,16 +7,20 @@
 mod console;
 mod sbi;
 
-use riscv::register::{sepc, /*stvec::{self, TrapMode},*/ scause::{self, Trap, Exception}};
+use riscv::register::{sepc, stvec::{self, TrapMode}, scause::{self, Trap, Exception}};
+use core::fmt::{self, Write};
 
 pub extern "C" fn rust_main(hartid: usize, dtb_pa: usize) -> ! {
     println!("<< Test-kernel: Hart id = {}, DTB physical address = {:#x}", hartid, dtb_pa);
     unsafe { asm!("ebreak") };
     // test_base_extension();
-    // test_sbi_ins_emulation();
-    // unsafe { stvec::write(start_trap as usize, TrapMode::Direct) };
-    // println!(">> Test-kernel: Trigger illegal exception");
-    // unsafe { asm!("csrw mcycle, x0") }; // mcycle cannot be written, this is always a 4-byte illegal instruction
+    test_device_tree(dtb_pa);
+    test_sbi_ins_emulation();
+    test_pmu_extension();
+    test_tiaoshi_extension();
+    unsafe { stvec::write(start_trap as usize, TrapMode::Direct) };
+    println!(">> Test-kernel: Trigger illegal exception");
+    unsafe { asm!("csrw mcycle, x0") }; // mcycle cannot be written, this is always a 4-byte illegal instruction
     println!("<< Test-kernel: SBI test SUCCESS, shutdown");
     sbi::shutdown()
 }
@@ -39,18 +43,192 @@ pub extern "C" fn rust_main(hartid: usize, dtb_pa: usize) -> ! {
 //     println!("<< Test-kernel: Device mimpid: {:x}", sbi::get_mimpid());
 // }
 
-// fn test_sbi_ins_emulation() {
-//     println!(">> Test-kernel: Testing SBI instruction emulation");
-//     let time = riscv::register::time::read64();
-//     println!("<< Test-kernel: Current time: {:x}", time);
-// }
+// The SBI reserves its own memory and describes the debugger in the device tree,
+// so the kernel does not need to hardcode where the SBI lives
+fn test_device_tree(dtb_pa: usize) {
+    println!(">> Test-kernel: Testing device tree from SBI");
+    let read_u32 = |address: usize| u32::from_be(unsafe { core::ptr::read(address as *const u32) }) as usize;
+    if read_u32(dtb_pa) != 0xd00d_feed {
+        println!("!! Test-kernel: No device tree, skipped");
+        return
+    }
+    let structure = dtb_pa + read_u32(dtb_pa + 8);
+    let strings = dtb_pa + read_u32(dtb_pa + 12);
+    // Names of the nodes from the root to the current node
+    let mut names = [""; 16];
+    let (mut offset, mut depth) = (0, 0);
+    let (mut debugger, mut reserved) = (false, None);
+    loop {
+        match read_u32(structure + offset) {
+            1 => { // FDT_BEGIN_NODE
+                let name = unsafe { c_str(structure + offset + 4) };
+                names[depth] = name;
+                depth += 1;
+                offset = (offset + 4 + name.len() + 1 + 3) & !3;
+            }
+            2 => { // FDT_END_NODE
+                depth -= 1;
+                offset += 4;
+            }
+            3 => { // FDT_PROP
+                let len = read_u32(structure + offset + 4);
+                let name = unsafe { c_str(strings + read_u32(structure + offset + 8)) };
+                let value = structure + offset + 12;
+                if depth == 3 && names[1] == "chosen" && names[2] == "tiaoshi-dashi" && name == "features" {
+                    print!("<< Test-kernel: Debugger features:");
+                    let mut feature = value;
+                    while feature < value + len {
+                        let text = unsafe { c_str(feature) };
+                        print!(" {}", text);
+                        feature += text.len() + 1;
+                    }
+                    println!("");
+                    debugger = true;
+                }
+                // QEMU virt uses two cells for both addresses and sizes
+                if depth == 3 && names[1] == "reserved-memory" && names[2].starts_with("tiaoshi-dashi@") && name == "reg" && len == 16 {
+                    let read_u64 = |address: usize| ((read_u32(address) as u64) << 32) | read_u32(address + 4) as u64;
+                    reserved = Some((read_u64(value), read_u64(value + 8)));
+                }
+                offset = (offset + 12 + len + 3) & !3;
+            }
+            4 => offset += 4, // FDT_NOP
+            _ => break,
+        }
+    }
+    let (base, size) = match reserved {
+        Some(region) => region,
+        None => {
+            println!("!! Test-kernel: SBI memory is not reserved in the device tree");
+            sbi::shutdown_failure()
+        }
+    };
+    println!("<< Test-kernel: SBI reserved memory: {:#x}..{:#x}", base, base + size);
+    let kernel = rust_main as usize as u64;
+    if kernel >= base && kernel < base + size {
+        println!("!! Test-kernel: Kernel overlaps memory reserved by SBI");
+        sbi::shutdown_failure()
+    }
+    if !debugger {
+        println!("!! Test-kernel: /chosen/tiaoshi-dashi not found in the device tree");
+        sbi::shutdown_failure()
+    }
+}
+
+unsafe fn c_str(address: usize) -> &'static str {
+    let mut len = 0;
+    while *((address + len) as *const u8) != 0 {
+        len += 1;
+    }
+    core::str::from_utf8_unchecked(core::slice::from_raw_parts(address as *const u8, len))
+}
+
+fn test_sbi_ins_emulation() {
+    println!(">> Test-kernel: Testing SBI instruction emulation");
+    let time = riscv::register::time::read64();
+    println!("<< Test-kernel: Current time: {:x}", time);
+}
+
+fn test_pmu_extension() {
+    println!(">> Test-kernel: Testing PMU extension");
+    if sbi::probe_extension(sbi::EXTENSION_PMU) == 0 {
+        println!("!! Test-kernel: PMU extension not found, skipped");
+        return
+    }
+    let count = sbi::pmu_num_counters();
+    println!("<< Test-kernel: {} PMU counters", count);
+    let all = (1 << count) - 1;
+    let flags = sbi::PMU_CFG_FLAG_CLEAR_VALUE | sbi::PMU_CFG_FLAG_AUTO_START;
+    let cycles = sbi::pmu_counter_config_matching(0, all, flags, sbi::PMU_HW_CPU_CYCLES, 0);
+    let calls = sbi::pmu_counter_config_matching(0, all, flags, sbi::PMU_FW_PLATFORM, 0);
+    if cycles.error != 0 || calls.error != 0 {
+        println!("!! Test-kernel: counter_config_matching failed: {}, {}", cycles.error as isize, calls.error as isize);
+        sbi::shutdown_failure()
+    }
+    for _ in 0..3 {
+        sbi::get_spec_version();
+    }
+    // Hardware counters are read directly once started
+    let cycle = riscv::register::cycle::read();
+    let ret = sbi::pmu_counter_fw_read(calls.value);
+    println!("<< Test-kernel: SBI calls counted: {}, cycle = {}", ret.value, cycle);
+    if ret.error != 0 || ret.value < 3 {
+        println!("!! Test-kernel: SBI call counter is wrong");
+        sbi::shutdown_failure()
+    }
+    sbi::pmu_counter_stop(cycles.value, 1, sbi::PMU_STOP_FLAG_RESET);
+    sbi::pmu_counter_stop(calls.value, 1, sbi::PMU_STOP_FLAG_RESET);
+}
+
+static BOOT_TASK_NAME: &[u8] = b"boot\0";
+static mut BOOT_TASK: [sbi::TiaoshiTask; 1] = [sbi::TiaoshiTask { id: 0, name: core::ptr::null(), state: 0, pc: 0, sp: 0 }];
+static mut TASK_LIST: sbi::TiaoshiTaskList = sbi::TiaoshiTaskList { count: 1, tasks: core::ptr::null() };
+static mut WATCHED: usize = 0;
+
+fn test_tiaoshi_extension() {
+    println!(">> Test-kernel: Testing tiaoshi extension");
+    if sbi::probe_extension(sbi::EXTENSION_TIAOSHI) == 0 {
+        println!("!! Test-kernel: tiaoshi extension not found, skipped");
+        return
+    }
+    let mut symbols = SymbolBuffer { data: [0; 256], len: 0 };
+    let _ = writeln!(symbols, "{:x} T rust_main", rust_main as usize);
+    let _ = writeln!(symbols, "{:x} T test_tiaoshi_extension", test_tiaoshi_extension as usize);
+    let _ = writeln!(symbols, "{:x} t tiaoshi_breakpoint_target", tiaoshi_breakpoint_target as usize);
+    let ret = sbi::tiaoshi_register_symbols(&symbols.data[..symbols.len]);
+    println!("<< Test-kernel: Registered symbols: error = {}, count = {}", ret.error as isize, ret.value);
+    unsafe {
+        BOOT_TASK[0].name = BOOT_TASK_NAME.as_ptr();
+        BOOT_TASK[0].pc = test_tiaoshi_extension as usize;
+        BOOT_TASK[0].sp = BOOT_STACK.as_ptr() as usize + BOOT_STACK_SIZE;
+        TASK_LIST.tasks = BOOT_TASK.as_ptr();
+        sbi::tiaoshi_set_task_list(&TASK_LIST);
+    }
+    // A write watchpoint needs hardware triggers, clear it before it is hit
+    let ret = sbi::tiaoshi_set_watchpoint(unsafe { &WATCHED as *const usize as usize }, core::mem::size_of::<usize>(), sbi::TIAOSHI_WATCH_WRITE);
+    println!("<< Test-kernel: Set watchpoint: error = {}, number = {}", ret.error as isize, ret.value);
+    if ret.error == 0 {
+        sbi::tiaoshi_clear(ret.value);
+    }
+    // The debugger stops at the breakpoint, continue with `c`
+    let ret = sbi::tiaoshi_set_breakpoint(tiaoshi_breakpoint_target as usize);
+    println!("<< Test-kernel: Set breakpoint: error = {}, number = {}", ret.error as isize, ret.value);
+    tiaoshi_breakpoint_target();
+    if ret.error == 0 {
+        sbi::tiaoshi_clear(ret.value);
+    }
+    sbi::tiaoshi_stop("test-kernel: tiaoshi extension test finished");
+}
+
+#[inline(never)]
+fn tiaoshi_breakpoint_target() {
+    println!("<< Test-kernel: Breakpoint target reached");
+}
+
+// Formats the symbol table without an allocator
+struct SymbolBuffer {
+    data: [u8; 256],
+    len: usize,
+}
+
+impl Write for SymbolBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        if end > self.data.len() {
+            return Err(fmt::Error)
+        }
+        self.data[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
 
 pub extern "C" fn rust_trap_exception() {
     let cause = scause::read().cause();
     println!("<< Test-kernel: Value of scause: {:?}", cause);
     if cause != Trap::Exception(Exception::IllegalInstruction) {
         println!("!! Test-kernel: Wrong cause associated to illegal instruction");
-        sbi::shutdown()
+        sbi::shutdown_failure()
     }
     println!("<< Test-kernel: Illegal exception delegate success");
     sepc::write(sepc::read().wrapping_add(4));
@@ -63,7 +241,7 @@ use core::panic::PanicInfo;
 fn panic(info: &PanicInfo) -> ! {
     println!("!! Test-kernel: {}", info);
     println!("!! Test-kernel: SBI test FAILED due to panic");
-    sbi::shutdown()
+    sbi::shutdown_failure()
 }
 
 const BOOT_STACK_SIZE: usize = 4096 * 4 * 8;
@@ -94,89 +272,89 @@ unsafe extern "C" fn entry() -> ! {
 }
 
 
-// #[cfg(target_pointer_width = "128")]
-// macro_rules! define_store_load {
-//     () => {
-//         ".altmacro
-//         .macro STORE reg, offset
-//             sq  \\reg, \\offset* {REGBYTES} (sp)
-//         .endm
-//         .macro LOAD reg, offset
-//             lq  \\reg, \\offset* {REGBYTES} (sp)
-//         .endm"
-//     };
-// }
+#[cfg(target_pointer_width = "128")]
+macro_rules! define_store_load {
+    () => {
+        ".altmacro
+        .macro STORE reg, offset
+            sq  \\reg, \\offset* {REGBYTES} (sp)
+        .endm
+        .macro LOAD reg, offset
+            lq  \\reg, \\offset* {REGBYTES} (sp)
+        .endm"
+    };
+}
 
-// #[cfg(target_pointer_width = "64")]
-// macro_rules! define_store_load {
-//     () => {
-//         ".altmacro
-//         .macro STORE reg, offset
-//             sd  \\reg, \\offset* {REGBYTES} (sp)
-//         .endm
-//         .macro LOAD reg, offset
-//             ld  \\reg, \\offset* {REGBYTES} (sp)
-//         .endm"
-//     };
-// }
+#[cfg(target_pointer_width = "64")]
+macro_rules! define_store_load {
+    () => {
+        ".altmacro
+        .macro STORE reg, offset
+            sd  \\reg, \\offset* {REGBYTES} (sp)
+        .endm
+        .macro LOAD reg, offset
+            ld  \\reg, \\offset* {REGBYTES} (sp)
+        .endm"
+    };
+}
 
-// #[cfg(target_pointer_width = "32")]
-// macro_rules! define_store_load {
-//     () => {
-//         ".altmacro
-//         .macro STORE reg, offset
-//             sw  \\reg, \\offset* {REGBYTES} (sp)
-//         .endm
-//         .macro LOAD reg, offset
-//             lw  \\reg, \\offset* {REGBYTES} (sp)
-//         .endm"
-//     };
-// }
+#[cfg(target_pointer_width = "32")]
+macro_rules! define_store_load {
+    () => {
+        ".altmacro
+        .macro STORE reg, offset
+            sw  \\reg, \\offset* {REGBYTES} (sp)
+        .endm
+        .macro LOAD reg, offset
+            lw  \\reg, \\offset* {REGBYTES} (sp)
+        .endm"
+    };
+}
 
-// #[naked]
-// #[link_section = ".text"]
-// unsafe extern "C" fn start_trap() {
-//     asm!(define_store_load!(), "
-//     .p2align 2
-//     addi    sp, sp, -16 * {REGBYTES}
-//     STORE   ra, 0
-//     STORE   t0, 1
-//     STORE   t1, 2
-//     STORE   t2, 3
-//     STORE   t3, 4
-//     STORE   t4, 5
-//     STORE   t5, 6
-//     STORE   t6, 7
-//     STORE   a0, 8
-//     STORE   a1, 9
-//     STORE   a2, 10
-//     STORE   a3, 11
-//     STORE   a4, 12
-//     STORE   a5, 13
-//     STORE   a6, 14
-//     STORE   a7, 15
-//     mv      a0, sp
-//     call    {rust_trap_exception}
-//     LOAD    ra, 0
-//     LOAD    t0, 1
-//     LOAD    t1, 2
-//     LOAD    t2, 3
-//     LOAD    t3, 4
-//     LOAD    t4, 5
-//     LOAD    t5, 6
-//     LOAD    t6, 7
-//     LOAD    a0, 8
-//     LOAD    a1, 9
-//     LOAD    a2, 10
-//     LOAD    a3, 11
-//     LOAD    a4, 12
-//     LOAD    a5, 13
-//     LOAD    a6, 14
-//     LOAD    a7, 15
-//     addi    sp, sp, 16 * {REGBYTES}
-//     sret
-//     ",
-//     REGBYTES = const core::mem::size_of::<usize>(),
-//     rust_trap_exception = sym rust_trap_exception,
-//     options(noreturn))
-// }
+#[naked]
+#[link_section = ".text"]
+unsafe extern "C" fn start_trap() {
+    asm!(define_store_load!(), "
+    .p2align 2
+    addi    sp, sp, -16 * {REGBYTES}
+    STORE   ra, 0
+    STORE   t0, 1
+    STORE   t1, 2
+    STORE   t2, 3
+    STORE   t3, 4
+    STORE   t4, 5
+    STORE   t5, 6
+    STORE   t6, 7
+    STORE   a0, 8
+    STORE   a1, 9
+    STORE   a2, 10
+    STORE   a3, 11
+    STORE   a4, 12
+    STORE   a5, 13
+    STORE   a6, 14
+    STORE   a7, 15
+    mv      a0, sp
+    call    {rust_trap_exception}
+    LOAD    ra, 0
+    LOAD    t0, 1
+    LOAD    t1, 2
+    LOAD    t2, 3
+    LOAD    t3, 4
+    LOAD    t4, 5
+    LOAD    t5, 6
+    LOAD    t6, 7
+    LOAD    a0, 8
+    LOAD    a1, 9
+    LOAD    a2, 10
+    LOAD    a3, 11
+    LOAD    a4, 12
+    LOAD    a5, 13
+    LOAD    a6, 14
+    LOAD    a7, 15
+    addi    sp, sp, 16 * {REGBYTES}
+    sret
+    ",
+    REGBYTES = const core::mem::size_of::<usize>(),
+    rust_trap_exception = sym rust_trap_exception,
+    options(noreturn))
+}
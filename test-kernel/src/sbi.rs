@@ -6,6 +6,8 @@ pub const EXTENSION_IPI: usize = 0x735049;
 pub const EXTENSION_RFENCE: usize = 0x52464E43;
 pub const EXTENSION_HSM: usize = 0x48534D;
 pub const EXTENSION_SRST: usize = 0x53525354;
+pub const EXTENSION_TIAOSHI: usize = 0x09545344;
+pub const EXTENSION_PMU: usize = 0x504D55;
 
 const FUNCTION_BASE_GET_SPEC_VERSION: usize = 0x0;
 const FUNCTION_BASE_GET_SBI_IMPL_ID: usize = 0x1;
@@ -15,6 +17,64 @@ const FUNCTION_BASE_GET_MVENDORID: usize = 0x4;
 const FUNCTION_BASE_GET_MARCHID: usize = 0x5;
 const FUNCTION_BASE_GET_MIMPID: usize = 0x6;
 
+const FUNCTION_SYSTEM_RESET: usize = 0x0;
+
+pub const RESET_TYPE_SHUTDOWN: usize = 0x0;
+pub const RESET_TYPE_COLD_REBOOT: usize = 0x1;
+pub const RESET_REASON_NO_REASON: usize = 0x0;
+pub const RESET_REASON_SYSTEM_FAILURE: usize = 0x1;
+
+const FUNCTION_TIAOSHI_REGISTER_SYMBOLS: usize = 0x0;
+const FUNCTION_TIAOSHI_SET_BREAKPOINT: usize = 0x1;
+const FUNCTION_TIAOSHI_SET_WATCHPOINT: usize = 0x2;
+const FUNCTION_TIAOSHI_CLEAR: usize = 0x3;
+const FUNCTION_TIAOSHI_STOP: usize = 0x4;
+const FUNCTION_TIAOSHI_SET_TASK_LIST: usize = 0x5;
+
+const FUNCTION_PMU_NUM_COUNTERS: usize = 0x0;
+const FUNCTION_PMU_COUNTER_GET_INFO: usize = 0x1;
+const FUNCTION_PMU_COUNTER_CONFIG_MATCHING: usize = 0x2;
+const FUNCTION_PMU_COUNTER_START: usize = 0x3;
+const FUNCTION_PMU_COUNTER_STOP: usize = 0x4;
+const FUNCTION_PMU_COUNTER_FW_READ: usize = 0x5;
+
+pub const PMU_CFG_FLAG_SKIP_MATCH: usize = 1 << 0;
+pub const PMU_CFG_FLAG_CLEAR_VALUE: usize = 1 << 1;
+pub const PMU_CFG_FLAG_AUTO_START: usize = 1 << 2;
+pub const PMU_START_FLAG_SET_INIT_VALUE: usize = 1 << 0;
+pub const PMU_STOP_FLAG_RESET: usize = 1 << 0;
+
+/// Hardware general events, type 0
+pub const PMU_HW_CPU_CYCLES: usize = 0x1;
+pub const PMU_HW_INSTRUCTIONS: usize = 0x2;
+/// Firmware events, type 15
+pub const PMU_FW_MISALIGNED_LOAD: usize = 0xf_0000;
+pub const PMU_FW_MISALIGNED_STORE: usize = 0xf_0001;
+pub const PMU_FW_ILLEGAL_INSN: usize = 0xf_0004;
+/// Platform firmware event, counts SBI calls handled by the firmware
+pub const PMU_FW_PLATFORM: usize = 0xf_ffff;
+
+pub const TIAOSHI_WATCH_WRITE: usize = 0x0;
+pub const TIAOSHI_WATCH_READ: usize = 0x1;
+pub const TIAOSHI_WATCH_ACCESS: usize = 0x2;
+
+/// Task list published to the debugger
+#[repr(C)]
+pub struct TiaoshiTaskList {
+    pub count: usize,
+    pub tasks: *const TiaoshiTask,
+}
+
+/// One task in the task list, `name` is a NUL-terminated string or null
+#[repr(C)]
+pub struct TiaoshiTask {
+    pub id: usize,
+    pub name: *const u8,
+    pub state: usize,
+    pub pc: usize,
+    pub sp: usize,
+}
+
 #[repr(C)]
 pub struct SbiRet {
     /// Error number
@@ -43,6 +103,26 @@ fn sbi_call(extension: usize, function: usize, arg0: usize, arg1: usize, arg2: u
     SbiRet { error, value }
 }
 
+#[inline(always)]
+fn sbi_call_5(extension: usize, function: usize, arg0: usize, arg1: usize, arg2: usize, arg3: usize, arg4: usize) -> SbiRet {
+    let (error, value);
+    match () {
+        #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+        () => unsafe { asm!(
+            "ecall", 
+            in("a0") arg0, in("a1") arg1, in("a2") arg2, in("a3") arg3, in("a4") arg4,
+            in("a6") function, in("a7") extension,
+            lateout("a0") error, lateout("a1") value,
+        ) },
+        #[cfg(not(any(target_arch = "riscv32", target_arch = "riscv64")))]
+        () => {
+            drop((extension, function, arg0, arg1, arg2, arg3, arg4));
+            unimplemented!("not RISC-V instruction set architecture")
+        }
+    };
+    SbiRet { error, value }
+}
+
 #[inline]
 pub fn get_spec_version() -> usize {
     sbi_call(EXTENSION_BASE, FUNCTION_BASE_GET_SPEC_VERSION, 0, 0, 0).value
@@ -121,6 +201,87 @@ pub fn shutdown() -> ! {
     unreachable!()
 }
 
+#[inline]
+pub fn system_reset(reset_type: usize, reset_reason: usize) -> SbiRet {
+    sbi_call(EXTENSION_SRST, FUNCTION_SYSTEM_RESET, reset_type, reset_reason, 0)
+}
+
+/// Shutdown with a system failure reason, QEMU exits with a non-zero code
+pub fn shutdown_failure() -> ! {
+    system_reset(RESET_TYPE_SHUTDOWN, RESET_REASON_SYSTEM_FAILURE);
+    unreachable!()
+}
+
 pub fn set_timer(time: usize) {
     sbi_call_legacy(SBI_SET_TIMER, time, 0, 0);
 }
+
+/// Register a symbol table in `nm` output format, returns the number of symbols
+#[inline]
+pub fn tiaoshi_register_symbols(symbols: &[u8]) -> SbiRet {
+    sbi_call(EXTENSION_TIAOSHI, FUNCTION_TIAOSHI_REGISTER_SYMBOLS, symbols.as_ptr() as usize, symbols.len(), 0)
+}
+
+/// Set a breakpoint on a virtual address, returns the breakpoint number
+#[inline]
+pub fn tiaoshi_set_breakpoint(address: usize) -> SbiRet {
+    sbi_call(EXTENSION_TIAOSHI, FUNCTION_TIAOSHI_SET_BREAKPOINT, address, 0, 0)
+}
+
+/// Set a watchpoint on the calling hart, returns the watchpoint number
+#[inline]
+pub fn tiaoshi_set_watchpoint(address: usize, len: usize, kind: usize) -> SbiRet {
+    sbi_call(EXTENSION_TIAOSHI, FUNCTION_TIAOSHI_SET_WATCHPOINT, address, len, kind)
+}
+
+/// Clear a breakpoint or watchpoint by number
+#[inline]
+pub fn tiaoshi_clear(number: usize) -> SbiRet {
+    sbi_call(EXTENSION_TIAOSHI, FUNCTION_TIAOSHI_CLEAR, number, 0, 0)
+}
+
+/// Stop in the debugger, which prints the message
+#[inline]
+pub fn tiaoshi_stop(message: &str) -> SbiRet {
+    sbi_call(EXTENSION_TIAOSHI, FUNCTION_TIAOSHI_STOP, message.as_ptr() as usize, message.len(), 0)
+}
+
+/// Publish the task list, or withdraw it with a null pointer
+#[inline]
+pub fn tiaoshi_set_task_list(list: *const TiaoshiTaskList) -> SbiRet {
+    sbi_call(EXTENSION_TIAOSHI, FUNCTION_TIAOSHI_SET_TASK_LIST, list as usize, 0, 0)
+}
+
+/// Number of PMU counters, hardware counters first
+#[inline]
+pub fn pmu_num_counters() -> usize {
+    sbi_call(EXTENSION_PMU, FUNCTION_PMU_NUM_COUNTERS, 0, 0, 0).value
+}
+
+/// CSR number and width of a hardware counter, or the type bit of a firmware counter
+#[inline]
+pub fn pmu_counter_get_info(counter_idx: usize) -> SbiRet {
+    sbi_call(EXTENSION_PMU, FUNCTION_PMU_COUNTER_GET_INFO, counter_idx, 0, 0)
+}
+
+/// Find and configure a counter for the event, returns the counter index
+#[inline]
+pub fn pmu_counter_config_matching(counter_idx_base: usize, counter_idx_mask: usize, config_flags: usize, event_idx: usize, event_data: usize) -> SbiRet {
+    sbi_call_5(EXTENSION_PMU, FUNCTION_PMU_COUNTER_CONFIG_MATCHING, counter_idx_base, counter_idx_mask, config_flags, event_idx, event_data)
+}
+
+#[inline]
+pub fn pmu_counter_start(counter_idx_base: usize, counter_idx_mask: usize, start_flags: usize, initial_value: usize) -> SbiRet {
+    sbi_call_5(EXTENSION_PMU, FUNCTION_PMU_COUNTER_START, counter_idx_base, counter_idx_mask, start_flags, initial_value, 0)
+}
+
+#[inline]
+pub fn pmu_counter_stop(counter_idx_base: usize, counter_idx_mask: usize, stop_flags: usize) -> SbiRet {
+    sbi_call(EXTENSION_PMU, FUNCTION_PMU_COUNTER_STOP, counter_idx_base, counter_idx_mask, stop_flags)
+}
+
+/// Read a firmware counter
+#[inline]
+pub fn pmu_counter_fw_read(counter_idx: usize) -> SbiRet {
+    sbi_call(EXTENSION_PMU, FUNCTION_PMU_COUNTER_FW_READ, counter_idx, 0, 0)
+}